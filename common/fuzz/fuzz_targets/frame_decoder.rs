@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use common::codec::{FrameDecoder, MAX_FRAME_SIZE};
+use common::controller::UpstreamMessage;
+
+// The first byte picks how the rest is chunked, like reads returning whatever the port had
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk, data)) = data.split_first() else {
+        return;
+    };
+
+    let mut decoder = FrameDecoder::default();
+    for chunk in data.chunks(chunk as usize + 1) {
+        decoder.push(chunk);
+        while let Some(result) = decoder.next::<UpstreamMessage>() {
+            let _ = result;
+        }
+        assert!(decoder.pending() < MAX_FRAME_SIZE);
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use common::controller::{DownstreamMessage, UpstreamMessage};
+
+// The firmware decodes downstream frames and the pc upstream ones, both straight off a noisy line
+fuzz_target!(|data: &[u8]| {
+    let _ = common::read::<DownstreamMessage>(&mut data.to_vec());
+    let _ = common::read::<UpstreamMessage>(&mut data.to_vec());
+});
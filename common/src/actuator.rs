@@ -0,0 +1,64 @@
+use serde::{Serialize, Deserialize};
+
+/// Number of auxiliary actuator channels
+pub const ACTUATOR_COUNT: usize = 4;
+
+/// The auxiliary actuators driven by the controller next to the thrusters
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Actuator {
+    /// Speed of the gripper servo in the range -1 to 1, positive opens the claw
+    Claw,
+    /// Position of the camera tilt servo in the range -1 to 1, positive tilts up
+    CameraTilt,
+    /// Brightness of the lights in the range 0 to 1
+    Lights,
+    /// Spare digital output, on above 0.5
+    Aux,
+}
+
+impl Actuator {
+    /// Every actuator in the order of `ActuatorData`
+    pub const ALL: [Actuator; ACTUATOR_COUNT] = [Actuator::Claw, Actuator::CameraTilt, Actuator::Lights, Actuator::Aux];
+
+    /// Index of the actuator in `ActuatorData`
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// Limit a value to the range the actuator accepts, values that aren't finite are treated as the safe value
+    pub fn clamp(self, value: f32) -> f32 {
+        if !value.is_finite() {
+            return SAFE_ACTUATORS.get(self);
+        }
+
+        match self {
+            Actuator::Claw | Actuator::CameraTilt => value.clamp(-1.0, 1.0),
+            Actuator::Lights | Actuator::Aux => value.clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// The value of every actuator, indexed like `Actuator::ALL`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ActuatorData(pub [f32; ACTUATOR_COUNT]);
+
+/// What the actuators are returned to on an emergency stop or when the pc goes silent
+/// The claw stops where it is, the camera is centered and the lights and aux output are switched off
+pub const SAFE_ACTUATORS: ActuatorData = ActuatorData([0.0; ACTUATOR_COUNT]);
+
+impl Default for ActuatorData {
+    fn default() -> Self {
+        SAFE_ACTUATORS
+    }
+}
+
+impl ActuatorData {
+    pub fn get(&self, actuator: Actuator) -> f32 {
+        self.0[actuator.index()]
+    }
+
+    /// Set an actuator, the value is limited to its range
+    pub fn set(&mut self, actuator: Actuator, value: f32) {
+        self.0[actuator.index()] = actuator.clamp(value);
+    }
+}
@@ -0,0 +1,97 @@
+use postcard::flavors::Slice;
+use serde::{Serialize, Deserialize};
+use crate::CommunicationError;
+use crate::crc::{Crc, CRC};
+
+/// Number of motors driven by the sabertooths
+pub const MOTOR_COUNT: usize = 4;
+/// Space reserved for an encoded `Calibration` record
+pub const RECORD_SIZE: usize = 64;
+/// Version of the record layout, bump whenever `Calibration` changes so old records are ignored
+const RECORD_VERSION: u8 = 1;
+
+/// Corrects for how a motor was wired and mounted
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MotorCalibration {
+    /// Spin the motor the other way
+    pub inverted: bool,
+    /// Added to the output while the motor is driven, in the range -1 to 1
+    pub trim: f32,
+    /// Largest output the motor is driven at, in the range 0 to 1
+    pub max_output: f32,
+}
+
+impl Default for MotorCalibration {
+    fn default() -> Self {
+        MotorCalibration {
+            inverted: false,
+            trim: 0.0,
+            max_output: 1.0
+        }
+    }
+}
+
+impl MotorCalibration {
+    /// Map a thruster output onto the motor, a stopped thruster always stops the motor
+    pub fn apply(&self, output: f32) -> f32 {
+        if output == 0.0 || !output.is_finite() {
+            return 0.0;
+        }
+
+        let max_output = if self.max_output.is_finite() { self.max_output.clamp(0.0, 1.0) } else { 0.0 };
+        let trim = if self.trim.is_finite() { self.trim.clamp(-1.0, 1.0) } else { 0.0 };
+
+        let output = (output * max_output + trim).clamp(-max_output, max_output);
+        if self.inverted { -output } else { output }
+    }
+}
+
+/// Calibration of every motor, indexed like `sabertooth::MOTORS` in the firmware
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub motors: [MotorCalibration; MOTOR_COUNT],
+}
+
+/// Matches how the motors were wired before calibration could be changed at runtime
+pub const DEFAULT_CALIBRATION: Calibration = Calibration {
+    motors: [
+        MotorCalibration { inverted: true, trim: 0.0, max_output: 1.0 }, // left
+        MotorCalibration { inverted: false, trim: 0.0, max_output: 1.0 }, // right
+        MotorCalibration { inverted: false, trim: 0.0, max_output: 1.0 }, // strafing
+        MotorCalibration { inverted: true, trim: 0.0, max_output: 1.0 }, // vertical
+    ]
+};
+
+impl Default for Calibration {
+    fn default() -> Self {
+        DEFAULT_CALIBRATION
+    }
+}
+
+impl Calibration {
+    /// Encode a versioned record followed by its crc
+    pub fn encode<'a>(&self, out: &'a mut [u8]) -> Result<&'a mut [u8], CommunicationError> {
+        postcard::serialize_with_flavor(&(RECORD_VERSION, self), Crc::new(Slice::new(out))).map_err(CommunicationError::from)
+    }
+
+    /// Decode a record written by `encode`, anything left after the record is ignored
+    pub fn decode(record: &[u8]) -> Result<Calibration, CommunicationError> {
+        let ((version, calibration), rest) = postcard::take_from_bytes::<(u8, Calibration)>(record)?;
+        let len = record.len() - rest.len();
+
+        if rest.len() < 2 {
+            return Err(CommunicationError::EOF);
+        }
+
+        let crc = u16::from_le_bytes([rest[0], rest[1]]);
+        let checksum = CRC.checksum(&record[..len]);
+        if checksum != crc {
+            return Err(CommunicationError::BadCheckSum(checksum, crc));
+        }
+        if version != RECORD_VERSION {
+            return Err(CommunicationError::BadData);
+        }
+
+        Ok(calibration)
+    }
+}
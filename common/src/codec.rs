@@ -0,0 +1,274 @@
+//! Splits a byte stream into frames and decodes them, for the host side of a serial link
+
+extern crate std;
+
+use core::fmt;
+use std::vec;
+use std::vec::Vec;
+use serde::{Serialize, Deserialize};
+use crate::{CommunicationError, Envelope};
+
+/// Larger than any message either side sends, including the envelope and framing
+pub const MAX_FRAME_SIZE: usize = 256;
+
+/// Why a frame was dropped
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    /// This many bytes arrived without a delimiter, they were discarded up to the next one
+    Overflow(usize),
+    /// A complete frame failed to decode
+    Decode(CommunicationError),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Overflow(len) => write!(f, "frame overflowed after {} bytes", len),
+            FrameError::Decode(error) => write!(f, "could not decode frame: {:?}", error),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Buffers bytes as they arrive in any size of chunk and decodes every complete frame
+///
+/// Garbage on the line costs at most the frame it lands in, decoding picks up again after the next delimiter.
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    // Bytes before this have been decoded
+    start: usize,
+    max_frame: usize,
+    // Dropping an overflowed frame until its delimiter arrives
+    discarding: bool,
+}
+
+impl FrameDecoder {
+    /// Frames longer than `max_frame`, including the delimiter, are discarded
+    pub fn new(max_frame: usize) -> Self {
+        FrameDecoder {
+            buffer: Vec::with_capacity(max_frame),
+            start: 0,
+            max_frame,
+            discarding: false,
+        }
+    }
+
+    /// Add received bytes
+    pub fn push(&mut self, mut bytes: &[u8]) {
+        self.buffer.drain(..self.start);
+        self.start = 0;
+
+        if self.discarding {
+            match bytes.iter().position(crate::end_of_frame) {
+                Some(end) => {
+                    bytes = &bytes[end + 1..];
+                    self.discarding = false;
+                }
+                None => return,
+            }
+        }
+
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Decode the next complete frame, returns `None` once more bytes are needed
+    pub fn next<'a, D: Deserialize<'a>>(&'a mut self) -> Option<Result<Envelope<D>, FrameError>> {
+        let envelope = self.next_envelope()?;
+        Some(envelope.and_then(|envelope| postcard::from_bytes(envelope).map_err(|error| FrameError::Decode(error.into()))))
+    }
+
+    /// Like `next`, but copies the frame out so it can outlive the decoder, its message is decoded later
+    pub fn next_frame(&mut self) -> Option<Result<ReceivedFrame, FrameError>> {
+        let envelope = self.next_envelope()?;
+        Some(envelope.and_then(|envelope| {
+            // The sequence number leads the envelope, the message follows it
+            let (seq, message) = postcard::take_from_bytes::<u16>(envelope).map_err(|error| FrameError::Decode(error.into()))?;
+            Ok(ReceivedFrame { seq, message: message.to_vec() })
+        }))
+    }
+
+    /// Unframe the next complete frame, returns its serialized envelope
+    fn next_envelope(&mut self) -> Option<Result<&[u8], FrameError>> {
+        loop {
+            let pending = &self.buffer[self.start..];
+            let Some(end) = pending.iter().position(crate::end_of_frame) else {
+                if pending.len() >= self.max_frame {
+                    let len = pending.len();
+                    self.start = self.buffer.len();
+                    self.discarding = true;
+                    return Some(Err(FrameError::Overflow(len)));
+                }
+                return None;
+            };
+
+            let frame = self.start..self.start + end + 1;
+            self.start = frame.end;
+
+            // Lone delimiters are sent to flush the line, they aren't frames
+            if end == 0 {
+                continue;
+            }
+            if frame.len() > self.max_frame {
+                return Some(Err(FrameError::Overflow(frame.len())));
+            }
+
+            return Some(crate::unframe(&mut self.buffer[frame]).map_err(FrameError::Decode));
+        }
+    }
+
+    /// Bytes waiting for the rest of their frame
+    pub fn pending(&self) -> usize {
+        self.buffer.len() - self.start
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::new(MAX_FRAME_SIZE)
+    }
+}
+
+/// A frame that passed its checksum and owns its bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedFrame {
+    seq: u16,
+    message: Vec<u8>,
+}
+
+impl ReceivedFrame {
+    pub fn seq(&self) -> u16 {
+        self.seq
+    }
+
+    /// Decode the message, any text it holds borrows from the frame
+    pub fn message<'a, D: Deserialize<'a>>(&'a self) -> Result<D, CommunicationError> {
+        postcard::from_bytes(&self.message).map_err(CommunicationError::from)
+    }
+}
+
+/// Encodes messages into frames, reusing one buffer
+pub struct FrameEncoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameEncoder {
+    /// Messages whose frame would be longer than `max_frame` fail with `BufferFull`
+    pub fn new(max_frame: usize) -> Self {
+        FrameEncoder {
+            buffer: vec![0; max_frame],
+        }
+    }
+
+    /// Encode a message, the frame is valid until the next call
+    pub fn encode<S: Serialize>(&mut self, seq: u16, message: &S) -> Result<&[u8], CommunicationError> {
+        crate::write(seq, message, &mut self.buffer).map(|frame| &*frame)
+    }
+}
+
+impl Default for FrameEncoder {
+    fn default() -> Self {
+        FrameEncoder::new(MAX_FRAME_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use super::*;
+
+    type Payload = Vec<u8>;
+
+    fn encode(frames: &[(u16, Payload)]) -> Vec<u8> {
+        let mut encoder = FrameEncoder::default();
+        frames.iter()
+            .flat_map(|(seq, payload)| encoder.encode(*seq, payload).unwrap().to_vec())
+            .collect()
+    }
+
+    /// Push the stream in chunks of the given sizes, collecting everything decoded
+    fn decode(stream: &[u8], chunks: &[usize]) -> Vec<Result<(u16, Payload), FrameError>> {
+        let mut decoder = FrameDecoder::default();
+        let mut decoded = Vec::new();
+        let mut rest = stream;
+        for &size in chunks.iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (chunk, remaining) = rest.split_at(size.min(rest.len()));
+            rest = remaining;
+
+            decoder.push(chunk);
+            while let Some(result) = decoder.next::<Payload>() {
+                decoded.push(result.map(|envelope| (envelope.seq, envelope.message)));
+            }
+            assert!(decoder.pending() < MAX_FRAME_SIZE);
+        }
+        decoded
+    }
+
+    fn frames() -> impl Strategy<Value = Vec<(u16, Payload)>> {
+        prop::collection::vec((any::<u16>(), prop::collection::vec(any::<u8>(), 0..200)), 0..10)
+    }
+
+    fn chunks() -> impl Strategy<Value = Vec<usize>> {
+        prop::collection::vec(1..300usize, 1..10)
+    }
+
+    #[test]
+    fn frames_outlive_the_decoder() {
+        let mut decoder = FrameDecoder::default();
+        decoder.push(&encode(&[(7, vec![0, 1, 2])]));
+        let frame = decoder.next_frame().unwrap().unwrap();
+        drop(decoder);
+
+        assert_eq!(frame.seq(), 7);
+        assert_eq!(frame.message::<Payload>().unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn overflow_resynchronises() {
+        let frames = [(1, vec![1, 2, 3])];
+        let mut stream = vec![0xAA; 2 * MAX_FRAME_SIZE];
+        stream.push(0);
+        stream.extend(encode(&frames));
+
+        let decoded = decode(&stream, &[100]);
+        assert!(matches!(decoded[0], Err(FrameError::Overflow(_))));
+        assert_eq!(decoded[1..], [Ok((1, vec![1, 2, 3]))]);
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips_in_any_chunks(frames in frames(), chunks in chunks()) {
+            let decoded = decode(&encode(&frames), &chunks);
+            prop_assert_eq!(decoded, frames.into_iter().map(Ok).collect::<Vec<_>>());
+        }
+
+        #[test]
+        fn resynchronises_after_garbage(frames in frames(), garbage in prop::collection::vec(prop::collection::vec(any::<u8>(), 0..600), 10), chunks in chunks()) {
+            // Garbage ends with a delimiter, otherwise it would run into the frame after it
+            let mut stream = Vec::new();
+            for (frame, garbage) in frames.iter().zip(&garbage) {
+                stream.extend(garbage);
+                stream.push(0);
+                stream.extend(encode(core::slice::from_ref(frame)));
+            }
+
+            // Every frame arrives in order, between whatever the garbage decoded to
+            let decoded = decode(&stream, &chunks);
+            let mut expected = frames.iter().peekable();
+            for result in decoded {
+                if result.as_ref().ok() == expected.peek().copied() {
+                    expected.next();
+                }
+            }
+            prop_assert!(expected.next().is_none());
+        }
+
+        #[test]
+        fn never_panics(stream in prop::collection::vec(any::<u8>(), 0..2000), chunks in chunks()) {
+            decode(&stream, &chunks);
+        }
+    }
+}
@@ -1,19 +1,200 @@
+use core::fmt;
+use core::ops::Add;
 use serde::{Serialize, Deserialize};
 use crate::CommunicationError;
+use crate::actuator::{Actuator, ActuatorData};
+use crate::sensors::{Alarms, SensorData, SensorThresholds};
+use crate::calibration::MotorCalibration;
+use crate::mixing::{AXES, ThrusterData};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Version of the message layout below, bump whenever `DownstreamMessage` or `UpstreamMessage` changes
+pub const PROTOCOL_VERSION: u16 = 14;
+
+/// Firmware ramps the pc setpoint to zero when the pc goes silent
+pub const CAPABILITY_FAILSAFE: u32 = 1 << 0;
+/// Firmware latches emergency stops and releases them on `DownstreamMessage::Arm`
+pub const CAPABILITY_ESTOP_RELEASE: u32 = 1 << 1;
+/// Firmware mixes body frame commands onto its thrusters using a configurable matrix
+pub const CAPABILITY_MIXING: u32 = 1 << 2;
+/// Firmware limits how quickly each axis of the velocity setpoint can change
+pub const CAPABILITY_SLEW_LIMIT: u32 = 1 << 3;
+/// Firmware stores a per motor calibration in eeprom
+pub const CAPABILITY_CALIBRATION: u32 = 1 << 4;
+/// Firmware drives the auxiliary actuators in `actuator`
+pub const CAPABILITY_ACTUATORS: u32 = 1 << 5;
+/// Firmware reports leak and power readings and raises alarms on them
+pub const CAPABILITY_SENSORS: u32 = 1 << 6;
+
+/// Identifies the firmware to the pc
+///
+/// The existing fields of this struct, the `Envelope` around it and its position as the first variant of both message enums
+/// must never change, otherwise mismatched versions would not be able to detect each other
+/// New fields may only be appended, a pc ignores trailing fields it doesn't know about
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Hello<'a> {
+    pub protocol_version: u16,
+    pub build_id: &'a str,
+    pub capabilities: u32,
+    pub reset_cause: ResetCause,
+}
+
+/// Why the firmware last started, holds the flags of the avr's MCUSR register
+/// Empty if the bootloader cleared the register before starting the firmware, except for resets after a panic
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResetCause(pub u8);
+
+impl ResetCause {
+    pub const POWER_ON: u8 = 1 << 0;
+    pub const EXTERNAL: u8 = 1 << 1;
+    pub const BROWN_OUT: u8 = 1 << 2;
+    pub const WATCHDOG: u8 = 1 << 3;
+    pub const JTAG: u8 = 1 << 4;
+
+    const NAMES: [(u8, &'static str); 5] = [
+        (ResetCause::POWER_ON, "Power on"),
+        (ResetCause::EXTERNAL, "External"),
+        (ResetCause::BROWN_OUT, "Brown out"),
+        (ResetCause::WATCHDOG, "Watchdog"),
+        (ResetCause::JTAG, "Jtag"),
+    ];
+
+    pub fn contains(&self, flag: u8) -> bool {
+        self.0 & flag != 0
+    }
+}
+
+impl fmt::Display for ResetCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (flag, name) in ResetCause::NAMES {
+            if self.contains(flag) {
+                if !first {
+                    f.write_str(", ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+
+        if first {
+            f.write_str("Unknown")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Where the firmware panicked before its last reset
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PanicReport<'a> {
+    pub file: &'a str,
+    pub line: u32,
+    pub column: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum DownstreamMessage {
+    /// Asks the firmware to identify itself, carries the pc's protocol version
+    Hello(u16),
+
     VelocityUpdate(VelocityData),
     EmergencyStop,
-    Ping
+    /// Releases a latched emergency stop, ignored while the physical button is pressed
+    Arm,
+    Ping,
+
+    /// Sets how long the controller waits without hearing from the pc before engaging the failsafe
+    SetFailsafeTimeout(u16),
+    /// Sets how much each axis contributes to a thruster, see `mixing::MixingMatrix`
+    SetMixing(u8, [f32; AXES]),
+    /// Sets how quickly each axis can change in full scale per second, zero disables the limit
+    SetSlewRate([f32; AXES]),
+
+    /// Asks the firmware to report the calibration of every motor
+    GetCalibration,
+    /// Sets and stores the calibration of a motor, the firmware reports it back once applied
+    SetCalibration(u8, MotorCalibration),
+
+    /// Sets how often the firmware sends `Telemetry` (ms), it is raised to the fastest rate the link can carry
+    /// Changes to the emergency stop or failsafe are always sent immediately
+    SetTelemetryInterval(u16),
+
+    /// Sets how the pc and joystick setpoints are combined, the joystick priority pin takes precedence
+    SetArbitration(ArbitrationMode),
+
+    /// Sets an auxiliary actuator, it returns to its safe value on an emergency stop or when the failsafe engages
+    SetActuator(Actuator, f32),
+
+    /// Sets when alarms are raised and which of them latch an emergency stop
+    SetSensorThresholds(SensorThresholds)
+}
+
+/// How the setpoints from the pc and the onboard joysticks are combined
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ArbitrationMode {
+    /// Both setpoints are added together
+    #[default]
+    Sum,
+    /// Only the pc is listened to
+    PcOnly,
+    /// Only the joysticks are listened to
+    JoystickOnly,
+    /// The pc is listened to until a joystick is deflected
+    JoystickOverride,
+}
+
+/// Whose setpoint is driving the robot
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ControlSource {
+    #[default]
+    Pc,
+    Joystick,
+    Both,
+}
+
+/// Why an emergency stop was latched
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EStopReason {
+    /// The physical button was pressed
+    Button,
+    /// The pc sent `DownstreamMessage::EmergencyStop`
+    Pc,
+    /// The pc was silent for too long
+    Failsafe,
+    /// The leak probe got wet
+    Leak,
+    /// The battery or tether voltage dropped below its threshold
+    LowVoltage,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EStopState {
+    #[default]
+    Armed,
+    Latched(EStopReason),
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+impl EStopState {
+    pub fn is_latched(&self) -> bool {
+        matches!(self, EStopState::Latched(_))
+    }
+}
+
+/// A body frame velocity command, every axis is in the range -1 to 1
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct VelocityData {
-    pub forwards_left: f32,
-    pub forwards_right: f32,
-    pub strafing: f32,
-    pub vertical: f32,
+    /// Forwards
+    pub surge: f32,
+    /// Right
+    pub sway: f32,
+    /// Up
+    pub heave: f32,
+    /// Clockwise when viewed from above
+    pub yaw: f32,
+    /// Nose up
+    pub pitch: f32,
+    /// Clockwise when viewed from behind
+    pub roll: f32,
 }
 
 impl VelocityData {
@@ -26,27 +207,244 @@ impl VelocityData {
             num.clamp(-1.0, 1.0)
         }
 
-        VelocityData {
-            forwards_left: clamp(self.forwards_left),
-            forwards_right: clamp(self.forwards_right),
-            strafing: clamp(self.strafing),
-            vertical: clamp(self.vertical)
+        VelocityData::from_axes(self.to_axes().map(clamp))
+    }
+
+    pub fn scale(&self, factor: f32) -> VelocityData {
+        VelocityData::from_axes(self.to_axes().map(|axis| axis * factor))
+    }
+
+    /// Is every axis stopped
+    pub fn is_zero(&self) -> bool {
+        self.to_axes().iter().all(|axis| *axis == 0.0)
+    }
+
+    pub fn to_axes(&self) -> [f32; AXES] {
+        [self.surge, self.sway, self.heave, self.yaw, self.pitch, self.roll]
+    }
+
+    pub fn from_axes(axes: [f32; AXES]) -> VelocityData {
+        let [surge, sway, heave, yaw, pitch, roll] = axes;
+        VelocityData { surge, sway, heave, yaw, pitch, roll }
+    }
+}
+
+impl Add for VelocityData {
+    type Output = VelocityData;
+
+    fn add(self, other: VelocityData) -> VelocityData {
+        let mut axes = self.to_axes();
+        for (axis, other) in axes.iter_mut().zip(other.to_axes()) {
+            *axis += other;
         }
+
+        VelocityData::from_axes(axes)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Counts of what the firmware received from the pc since it started
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    /// Packets that were decoded and acknowledged
+    pub packets: u32,
+    /// Packets that failed to decode, each was reported with `BadP`
+    pub bad_packets: u32,
+    /// Packets that overran the receive buffer, each was reported with `BadO`
+    pub overruns: u32,
+    /// Bytes the receive interrupt dropped because the firmware wasn't reading them quickly enough
+    pub rx_overruns: u32,
+    /// Packets to the pc or the sabertooths dropped whole because their transmit buffer was full
+    pub tx_overflows: u32,
+}
+
+/// Duration of the control loop iterations since the previous telemetry (us)
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LoopStats {
+    pub min: u32,
+    pub max: u32,
+    pub avg: u32,
+}
+
+/// Periodic summary of the controller's state
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Telemetry {
+    /// When the telemetry was sent, microseconds since boot, wraps around after ~71 minutes
+    pub timestamp: u32,
+    pub loop_time: LoopStats,
+
+    /// Setpoint from the pc after the failsafe ramp
+    pub pc_setpoint: VelocityData,
+    /// Setpoint from the onboard joysticks
+    pub joystick_setpoint: VelocityData,
+    /// The output of every thruster after slew limiting and mixing
+    pub thrusters: ThrusterData,
+    /// The arbitration mode in effect, which may be forced by the joystick priority pin
+    pub arbitration: ArbitrationMode,
+    pub control_source: ControlSource,
+
+    pub emergency_stop: EStopState,
+    pub failsafe: bool,
+
+    /// The output of every auxiliary actuator, the safe values while stopped
+    pub actuators: ActuatorData,
+
+    pub sensors: SensorData,
+    pub alarms: Alarms,
+
+    pub counters: Counters,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum UpstreamMessage<'a> {
-    Init,
+    Hello(Hello<'a>),
     Log(&'a str),
 
-    Ack,
+    /// Acknowledges the packet with the given sequence number
+    Ack(u16),
     BadO,
     BadP(CommunicationError),
 
-    TotalVelocity(VelocityData),
+    Telemetry(Telemetry),
+
+    /// The calibration of the motor with the given index
+    Calibration(u8, MotorCalibration),
 
-    EStop(bool),
+    /// The firmware panicked before its last reset, sent after every hello
+    Panic(PanicReport<'a>),
 
     Pong
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::boxed::Box;
+    use std::string::String;
+    use std::vec;
+    use proptest::prelude::*;
+    use super::*;
+
+    // NaN never equals itself, so stick to finite values
+    fn float() -> impl Strategy<Value = f32> {
+        -1e6f32..1e6
+    }
+
+    // Messages borrow their text, leaking it keeps the strategies simple
+    fn text() -> impl Strategy<Value = &'static str> {
+        any::<String>().prop_map(|text| &*Box::leak(text.into_boxed_str()))
+    }
+
+    fn velocity() -> impl Strategy<Value = VelocityData> {
+        prop::array::uniform6(float())
+            .prop_map(|[surge, sway, heave, yaw, pitch, roll]| VelocityData { surge, sway, heave, yaw, pitch, roll })
+    }
+
+    fn motor_calibration() -> impl Strategy<Value = MotorCalibration> {
+        (any::<bool>(), float(), float()).prop_map(|(inverted, trim, max_output)| MotorCalibration { inverted, trim, max_output })
+    }
+
+    fn arbitration() -> impl Strategy<Value = ArbitrationMode> {
+        prop_oneof![
+            Just(ArbitrationMode::Sum),
+            Just(ArbitrationMode::PcOnly),
+            Just(ArbitrationMode::JoystickOnly),
+            Just(ArbitrationMode::JoystickOverride),
+        ]
+    }
+
+    fn emergency_stop() -> impl Strategy<Value = EStopState> {
+        prop_oneof![
+            Just(EStopState::Armed),
+            prop::sample::select(&[EStopReason::Button, EStopReason::Pc, EStopReason::Failsafe, EStopReason::Leak, EStopReason::LowVoltage][..])
+                .prop_map(EStopState::Latched),
+        ]
+    }
+
+    fn telemetry() -> impl Strategy<Value = Telemetry> {
+        let setpoints = (velocity(), velocity(), prop::array::uniform4(float()), arbitration(), prop::sample::select(&[ControlSource::Pc, ControlSource::Joystick, ControlSource::Both][..]));
+        let state = (emergency_stop(), any::<bool>(), prop::array::uniform4(float()), (float(), float(), any::<bool>()), any::<u8>());
+        (any::<u32>(), any::<[u32; 3]>(), setpoints, state, any::<[u32; 5]>()).prop_map(
+            |(timestamp, [min, max, avg], (pc_setpoint, joystick_setpoint, thrusters, arbitration, control_source), (emergency_stop, failsafe, actuators, (battery_voltage, tether_voltage, leak), alarms), [packets, bad_packets, overruns, rx_overruns, tx_overflows])| Telemetry {
+                timestamp,
+                loop_time: LoopStats { min, max, avg },
+                pc_setpoint,
+                joystick_setpoint,
+                thrusters: ThrusterData(thrusters),
+                arbitration,
+                control_source,
+                emergency_stop,
+                failsafe,
+                actuators: ActuatorData(actuators),
+                sensors: SensorData { battery_voltage, tether_voltage, leak },
+                alarms: Alarms(alarms),
+                counters: Counters { packets, bad_packets, overruns, rx_overruns, tx_overflows },
+            })
+    }
+
+    fn downstream() -> impl Strategy<Value = DownstreamMessage> {
+        prop_oneof![
+            any::<u16>().prop_map(DownstreamMessage::Hello),
+            velocity().prop_map(DownstreamMessage::VelocityUpdate),
+            Just(DownstreamMessage::EmergencyStop),
+            Just(DownstreamMessage::Arm),
+            Just(DownstreamMessage::Ping),
+            any::<u16>().prop_map(DownstreamMessage::SetFailsafeTimeout),
+            (any::<u8>(), prop::array::uniform6(float())).prop_map(|(thruster, weights)| DownstreamMessage::SetMixing(thruster, weights)),
+            prop::array::uniform6(float()).prop_map(DownstreamMessage::SetSlewRate),
+            Just(DownstreamMessage::GetCalibration),
+            (any::<u8>(), motor_calibration()).prop_map(|(motor, calibration)| DownstreamMessage::SetCalibration(motor, calibration)),
+            any::<u16>().prop_map(DownstreamMessage::SetTelemetryInterval),
+            arbitration().prop_map(DownstreamMessage::SetArbitration),
+            (prop::sample::select(&Actuator::ALL[..]), float()).prop_map(|(actuator, value)| DownstreamMessage::SetActuator(actuator, value)),
+            (float(), float(), any::<u8>()).prop_map(|(min_battery_voltage, min_tether_voltage, estop_alarms)| {
+                DownstreamMessage::SetSensorThresholds(SensorThresholds { min_battery_voltage, min_tether_voltage, estop_alarms })
+            }),
+        ]
+    }
+
+    fn upstream() -> impl Strategy<Value = UpstreamMessage<'static>> {
+        let error = prop_oneof![
+            Just(CommunicationError::BadData),
+            any::<(u16, u16)>().prop_map(|(expected, received)| CommunicationError::BadCheckSum(expected, received)),
+            Just(CommunicationError::EOF),
+            Just(CommunicationError::BufferFull),
+            Just(CommunicationError::InternalError),
+        ];
+
+        prop_oneof![
+            (any::<u16>(), text(), any::<u32>(), any::<u8>()).prop_map(|(protocol_version, build_id, capabilities, reset_cause)| {
+                UpstreamMessage::Hello(Hello { protocol_version, build_id, capabilities, reset_cause: ResetCause(reset_cause) })
+            }),
+            text().prop_map(UpstreamMessage::Log),
+            any::<u16>().prop_map(UpstreamMessage::Ack),
+            Just(UpstreamMessage::BadO),
+            error.prop_map(UpstreamMessage::BadP),
+            telemetry().prop_map(UpstreamMessage::Telemetry),
+            (any::<u8>(), motor_calibration()).prop_map(|(motor, calibration)| UpstreamMessage::Calibration(motor, calibration)),
+            (text(), any::<u32>(), any::<u32>()).prop_map(|(file, line, column)| UpstreamMessage::Panic(PanicReport { file, line, column })),
+            Just(UpstreamMessage::Pong),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn downstream_round_trips(seq in any::<u16>(), message in downstream()) {
+            let mut buffer = [0; 256];
+            let frame = crate::write(seq, &message, &mut buffer).unwrap();
+            let envelope = crate::read::<DownstreamMessage>(frame).unwrap();
+            prop_assert_eq!(envelope.seq, seq);
+            prop_assert_eq!(envelope.message, message);
+        }
+
+        #[test]
+        fn upstream_round_trips(seq in any::<u16>(), message in upstream()) {
+            // Text can be long, the firmware never sends more than fits its buffer
+            let mut buffer = [0; 4096];
+            let frame = crate::write(seq, &message, &mut buffer).unwrap();
+            let envelope = crate::read::<UpstreamMessage>(frame).unwrap();
+            prop_assert_eq!(envelope.seq, seq);
+            prop_assert_eq!(envelope.message, message);
+        }
+    }
+}
@@ -5,8 +5,14 @@ use serde::{Serialize, Deserialize};
 use crate::controller::VelocityData;
 use crate::crc::Crc;
 
+pub mod actuator;
+pub mod calibration;
+#[cfg(any(feature = "std", test))]
+pub mod codec;
 pub mod controller;
 pub mod crc;
+pub mod mixing;
+pub mod sensors;
 
 // other vals can have less error?
 pub const BAUD_RATE_CTRL : u32 = 1000000;//1000000;//921600;//460800;//115200;
@@ -14,7 +20,14 @@ pub const BAUD_RATE_FORWARD: u32 = 1000000;
 pub const BAUD_RATE_SABERTOOTH : u32 = 38400;
 pub const BAUD_RATE_NANO : u32 = 57600;
 
+/// Wraps every message on the wire so the receiver can detect lost packets and match acknowledgements
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope<T> {
+    pub seq: u16,
+    pub message: T,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum CommunicationError {
     BadData,
     BadCheckSum(u16, u16),
@@ -50,32 +63,86 @@ mod test {
     use crate::controller::{DownstreamMessage, VelocityData};
     use crate::{read, write};
     use crate::clamp_map_val;
+    use crate::mixing::DEFAULT_MIXING;
+    use crate::calibration::{Calibration, DEFAULT_CALIBRATION, MotorCalibration, RECORD_SIZE};
+    use crate::actuator::{Actuator, ActuatorData, SAFE_ACTUATORS};
 
     #[test]
     fn test_communication() {
         let mut buffer : [u8; 200] = unsafe { MaybeUninit::uninit().assume_init() };
 
         let command = DownstreamMessage::VelocityUpdate(VelocityData {
-            forwards_left: 4.0,
-            forwards_right: 3.0,
-            strafing: 2.0,
-            vertical: 1.0
+            surge: 6.0,
+            sway: 5.0,
+            heave: 4.0,
+            yaw: 3.0,
+            pitch: 2.0,
+            roll: 1.0
         });
 
-        let buffer2 = write(&command, &mut buffer).unwrap();
+        let buffer2 = write(42, &command, &mut buffer).unwrap();
         let received = read::<DownstreamMessage>(buffer2).unwrap(); //
 
-        match received {
+        assert_eq!(received.seq, 42);
+        match received.message {
             DownstreamMessage::VelocityUpdate(data) => {
-                assert_eq!(data.forwards_left, 4.0);
-                assert_eq!(data.forwards_right, 3.0);
-                assert_eq!(data.strafing, 2.0);
-                assert_eq!(data.vertical, 1.0);
+                assert_eq!(data.to_axes(), [6.0, 5.0, 4.0, 3.0, 2.0, 1.0]);
             }
             _ => { panic!() }
         }
     }
 
+    #[test]
+    fn test_mixing() {
+        let velocity = VelocityData {
+            surge: 1.0,
+            yaw: 0.5,
+            heave: -0.5,
+            ..Default::default()
+        };
+
+        let thrusters = DEFAULT_MIXING.mix(&velocity);
+
+        // Saturation scales every thruster down together
+        assert_eq!(thrusters.0[0], 1.0);
+        assert_eq!(thrusters.0[1], 0.5 / 1.5);
+        assert_eq!(thrusters.0[2], 0.0);
+        assert_eq!(thrusters.0[3], -0.5 / 1.5);
+    }
+
+    #[test]
+    fn test_calibration() {
+        let mut calibration = DEFAULT_CALIBRATION;
+        calibration.motors[2] = MotorCalibration { inverted: true, trim: 0.1, max_output: 0.5 };
+
+        let mut buffer = [0; RECORD_SIZE];
+        let len = calibration.encode(&mut buffer).unwrap().len();
+        assert_eq!(Calibration::decode(&buffer).unwrap(), calibration);
+
+        buffer[len / 2] ^= 1;
+        assert!(Calibration::decode(&buffer).is_err());
+        // Blank eeprom
+        assert!(Calibration::decode(&[0xFF; RECORD_SIZE]).is_err());
+
+        let motor = calibration.motors[2];
+        assert_eq!(motor.apply(0.0), 0.0);
+        assert_eq!(motor.apply(0.5), -0.35);
+        assert_eq!(motor.apply(1.0), -0.5);
+        assert_eq!(motor.apply(-1.0), 0.4);
+    }
+
+    #[test]
+    fn test_actuators() {
+        let mut actuators = ActuatorData::default();
+        assert_eq!(actuators, SAFE_ACTUATORS);
+
+        actuators.set(Actuator::Claw, -2.0);
+        actuators.set(Actuator::CameraTilt, 0.25);
+        actuators.set(Actuator::Lights, -1.0);
+        actuators.set(Actuator::Aux, f32::NAN);
+        assert_eq!(actuators.0, [-1.0, 0.25, 0.0, 0.0]);
+    }
+
     #[test]
     fn test_wrap_val() {
         let cases = [
@@ -99,11 +166,17 @@ mod test {
     }
 }
 
-pub fn write<'a, S: Serialize>(obj: &S, out: &'a mut [u8]) -> Result<&'a mut [u8], CommunicationError> {
-    postcard::serialize_with_flavor(obj, Crc::new(Cobs::try_new(Slice::new(out)).map_err(CommunicationError::from)?)).map_err(CommunicationError::from)
+pub fn write<'a, S: Serialize>(seq: u16, obj: &S, out: &'a mut [u8]) -> Result<&'a mut [u8], CommunicationError> {
+    let envelope = Envelope { seq, message: obj };
+    postcard::serialize_with_flavor(&envelope, Crc::new(Cobs::try_new(Slice::new(out)).map_err(CommunicationError::from)?)).map_err(CommunicationError::from)
 }
 
-pub fn read<'a, D: Deserialize<'a>>(buffer: &'a mut [u8]) -> Result<D, CommunicationError> {
+pub fn read<'a, D: Deserialize<'a>>(buffer: &'a mut [u8]) -> Result<Envelope<D>, CommunicationError> {
+    postcard::from_bytes(unframe(buffer)?).map_err(CommunicationError::from)
+}
+
+/// Decodes a frame in place and checks its checksum, returns the serialized envelope
+pub fn unframe(buffer: &mut [u8]) -> Result<&[u8], CommunicationError> {
     let read = postcard_cobs::decode_in_place(buffer).map_err(|_| CommunicationError::BadData)?;
     if read > 3 {
         let data = &buffer[..read - 3];
@@ -111,7 +184,7 @@ pub fn read<'a, D: Deserialize<'a>>(buffer: &'a mut [u8]) -> Result<D, Communica
 
         let checksum = crate::crc::CRC.checksum(data);
         if checksum == crc {
-            postcard::from_bytes(data).map_err(CommunicationError::from)
+            Ok(data)
         } else {
             Err(CommunicationError::BadCheckSum(checksum, crc))
         }
@@ -125,16 +198,12 @@ pub fn end_of_frame(byte: &u8) -> bool {
 }
 
 pub fn joystick_math(lx: f32, ly: f32, rx: f32, ry: f32) -> VelocityData {
-    let forwards_left = ly + lx;
-    let forwards_right = ly - lx;
-    let strafing = rx;
-    let vertical = ry;
-
     VelocityData {
-        forwards_left,
-        forwards_right,
-        strafing,
-        vertical
+        surge: ly,
+        sway: rx,
+        heave: ry,
+        yaw: lx,
+        ..Default::default()
     }.clamp()
 }
 
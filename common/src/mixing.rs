@@ -0,0 +1,87 @@
+//! Maps body frame commands onto thrusters
+//!
+//! There is one thruster per motor, so the number of thrusters is fixed at build time by `calibration::MOTOR_COUNT`.
+//! The firmware drives them in the order of its `sabertooth::MOTORS`, which follows from that count.
+
+use serde::{Serialize, Deserialize};
+use crate::calibration::MOTOR_COUNT;
+use crate::controller::VelocityData;
+
+/// Number of body frame axes, in the order surge, sway, heave, yaw, pitch, roll
+pub const AXES: usize = 6;
+/// Number of thrusters a mixing matrix drives, one per motor
+pub const THRUSTERS: usize = MOTOR_COUNT;
+
+/// Output of every thruster, each in the range -1 to 1
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct ThrusterData(pub [f32; THRUSTERS]);
+
+/// Maps a body frame command onto thruster outputs
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct MixingMatrix {
+    /// How much each axis contributes to each thruster
+    pub weights: [[f32; AXES]; THRUSTERS],
+}
+
+/// Two forwards thrusters used for surge and yaw, one strafing thruster and one vertical thruster
+pub const DEFAULT_MIXING: MixingMatrix = MixingMatrix {
+    weights: [
+        // surge, sway, heave, yaw, pitch, roll
+        [1.0, 0.0, 0.0,  1.0, 0.0, 0.0], // forwards left
+        [1.0, 0.0, 0.0, -1.0, 0.0, 0.0], // forwards right
+        [0.0, 1.0, 0.0,  0.0, 0.0, 0.0], // strafing
+        [0.0, 0.0, 1.0,  0.0, 0.0, 0.0], // vertical
+    ]
+};
+
+impl Default for MixingMatrix {
+    fn default() -> Self {
+        DEFAULT_MIXING
+    }
+}
+
+impl MixingMatrix {
+    /// Compute the thruster outputs for a body frame command
+    ///
+    /// If any thruster would saturate, all outputs are scaled down together so the direction of travel is preserved
+    pub fn mix(&self, velocity: &VelocityData) -> ThrusterData {
+        let axes = velocity.clamp().to_axes();
+
+        let mut outputs = [0.0; THRUSTERS];
+        let mut max = 0.0f32;
+        for (output, weights) in outputs.iter_mut().zip(self.weights.iter()) {
+            let sum: f32 = weights.iter().zip(axes.iter()).map(|(weight, axis)| weight * axis).sum();
+            // Huge weights can overflow, stop that thruster rather than scale every output to NaN
+            *output = if sum.is_finite() { sum } else { 0.0 };
+            max = max.max(crate::abs(*output));
+        }
+
+        if max > 1.0 {
+            for output in outputs.iter_mut() {
+                *output /= max;
+            }
+        }
+
+        ThrusterData(outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use super::*;
+
+    fn weight() -> impl Strategy<Value = f32> {
+        prop_oneof![-2.0f32..2.0, Just(f32::MAX), Just(f32::MIN), any::<f32>()]
+    }
+
+    proptest! {
+        #[test]
+        fn outputs_stay_in_range(weights in prop::array::uniform::<_, THRUSTERS>(prop::array::uniform6(weight())), axes in prop::array::uniform6(any::<f32>())) {
+            let thrusters = MixingMatrix { weights }.mix(&VelocityData::from_axes(axes));
+            for output in thrusters.0 {
+                prop_assert!((-1.0..=1.0).contains(&output), "{} out of range", output);
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use core::fmt;
+use serde::{Serialize, Deserialize};
+
+/// Readings of the leak and power monitoring inputs
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct SensorData {
+    /// Voltage of the battery (V)
+    pub battery_voltage: f32,
+    /// Voltage where the tether enters the robot (V)
+    pub tether_voltage: f32,
+    /// Is the leak probe wet
+    pub leak: bool,
+}
+
+/// When the firmware raises alarms and which of them latch an emergency stop
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct SensorThresholds {
+    /// Alarm below this battery voltage (V), 0 disables the alarm
+    pub min_battery_voltage: f32,
+    /// Alarm below this tether voltage (V), 0 disables the alarm
+    pub min_tether_voltage: f32,
+    /// The `Alarms` flags that latch an emergency stop when they are raised
+    pub estop_alarms: u8,
+}
+
+/// Sized for the 12V system the sabertooths are configured for
+pub const DEFAULT_THRESHOLDS: SensorThresholds = SensorThresholds {
+    min_battery_voltage: 10.5,
+    min_tether_voltage: 10.0,
+    estop_alarms: Alarms::LEAK | Alarms::LOW_BATTERY | Alarms::LOW_TETHER,
+};
+
+impl Default for SensorThresholds {
+    fn default() -> Self {
+        DEFAULT_THRESHOLDS
+    }
+}
+
+/// The alarms the firmware has raised, each is a flag
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Alarms(pub u8);
+
+impl Alarms {
+    pub const LEAK: u8 = 1 << 0;
+    pub const LOW_BATTERY: u8 = 1 << 1;
+    pub const LOW_TETHER: u8 = 1 << 2;
+
+    const NAMES: [(u8, &'static str); 3] = [
+        (Alarms::LEAK, "Leak"),
+        (Alarms::LOW_BATTERY, "Low battery"),
+        (Alarms::LOW_TETHER, "Low tether voltage"),
+    ];
+
+    pub fn contains(&self, flag: u8) -> bool {
+        self.0 & flag != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl fmt::Display for Alarms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (flag, name) in Alarms::NAMES {
+            if self.contains(flag) {
+                if !first {
+                    f.write_str(", ")?;
+                }
+                f.write_str(name)?;
+                first = false;
+            }
+        }
+
+        if first {
+            f.write_str("None")?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! Converts actuator values into what drives the hardware
+
+use common::actuator::Actuator;
+
+/// Pulse width of a servo at -1 (us)
+pub const SERVO_MIN_PULSE: u16 = 1000;
+/// Pulse width of a servo at 1 (us)
+pub const SERVO_MAX_PULSE: u16 = 2000;
+
+/// How an actuator channel is driven
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// A servo pulse, the width is in microseconds
+    Servo(u16),
+    /// A pwm duty cycle, 255 is always on
+    Pwm(u8),
+    Digital(bool),
+}
+
+/// The output driving an actuator at `value`, which must already be limited by `Actuator::clamp`
+pub fn output(actuator: Actuator, value: f32) -> Output {
+    match actuator {
+        Actuator::Claw | Actuator::CameraTilt => {
+            let center = (SERVO_MIN_PULSE + SERVO_MAX_PULSE) as f32 / 2.0;
+            let range = (SERVO_MAX_PULSE - SERVO_MIN_PULSE) as f32 / 2.0;
+            Output::Servo((center + value * range) as u16)
+        }
+        Actuator::Lights => Output::Pwm((value * 255.0) as u8),
+        Actuator::Aux => Output::Digital(value > 0.5),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn servo_pulse() {
+        assert_eq!(output(Actuator::Claw, -1.0), Output::Servo(SERVO_MIN_PULSE));
+        assert_eq!(output(Actuator::Claw, 0.0), Output::Servo(1500));
+        assert_eq!(output(Actuator::CameraTilt, 1.0), Output::Servo(SERVO_MAX_PULSE));
+    }
+
+    #[test]
+    fn pwm_and_digital() {
+        assert_eq!(output(Actuator::Lights, 0.0), Output::Pwm(0));
+        assert_eq!(output(Actuator::Lights, 1.0), Output::Pwm(255));
+        assert_eq!(output(Actuator::Aux, 0.4), Output::Digital(false));
+        assert_eq!(output(Actuator::Aux, 1.0), Output::Digital(true));
+    }
+}
@@ -0,0 +1,937 @@
+use core::mem;
+use heapless::Vec;
+use common::actuator::Actuator;
+use common::calibration::MOTOR_COUNT;
+use common::controller::{Counters, DownstreamMessage, Hello, Telemetry, UpstreamMessage, VelocityData};
+use common::{CommunicationError, Envelope};
+use crate::hal::{Actuators, Adc, Clock, InputPin, OutputPin, SerialRx, SerialTx, Storage};
+use crate::actuator;
+use crate::joystick;
+use crate::panic::PanicRecord;
+use crate::sabertooth;
+use crate::sensors;
+use crate::state::State;
+use crate::storage;
+use crate::storage::RecordWriter;
+use crate::telemetry::TelemetryScheduler;
+
+/// The hardware the control loop runs on
+pub struct Peripherals<Pc, Motors, Analog, Time, Button, Enable, Priority, EStop, Store, Aux> {
+    /// Serial connection to the pc
+    pub pc: Pc,
+    /// Serial connection to the sabertooth motor controllers
+    pub motors: Motors,
+    /// Analog inputs of the onboard joysticks and sensors, see `joystick` and `sensors` for the channels
+    pub analog: Analog,
+    pub clock: Time,
+    /// Emergency stop button, high while pressed
+    pub estop_button: Button,
+    /// Joystick enable switch, low while enabled
+    pub joystick_enable: Enable,
+    /// Joystick priority switch, low to listen to the joysticks only
+    pub joystick_priority: Priority,
+    /// Emergency stop line of the sabertooths, active low
+    pub estop_out: EStop,
+    /// Holds the calibration, see `storage`
+    pub storage: Store,
+    /// Servos, lights and other auxiliary outputs
+    pub actuators: Aux,
+}
+
+/// The control loop, owns everything except the hardware
+pub struct Controller {
+    state: State,
+    hello: Hello<'static>,
+
+    // This buffer will hold partially received packets
+    packet: Vec<u8, { mem::size_of::<Envelope<DownstreamMessage>>() + 5 }>,
+
+    out_buffer: [u8; 200],
+    out_sequence: u16,
+
+    // Speeds last written to each motor, `None` if it has to be written again
+    speeds: [Option<i8>; MOTOR_COUNT],
+    // Time speeds were last written, `None` before the first time
+    speeds_sent: Option<u32>,
+
+    calibration_writer: RecordWriter,
+    telemetry: TelemetryScheduler,
+    counters: Counters,
+
+    // Where the firmware panicked before this boot
+    panic: Option<PanicRecord>,
+}
+
+impl Controller {
+    /// `hello` is sent to identify the firmware whenever the pc asks for it
+    pub fn new(hello: Hello<'static>) -> Self {
+        Controller {
+            state: State::default(),
+            hello,
+            packet: Vec::new(),
+            out_buffer: [0; 200],
+            out_sequence: 0,
+            speeds: [None; MOTOR_COUNT],
+            speeds_sent: None,
+            calibration_writer: RecordWriter::default(),
+            telemetry: TelemetryScheduler::default(),
+            counters: Counters::default(),
+            panic: None
+        }
+    }
+
+    /// Report a panic from before the last reset after every hello
+    pub fn set_panic(&mut self, record: PanicRecord) {
+        if record.report().is_some() {
+            self.panic = Some(record);
+        }
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// Load the calibration, configure the motor controllers and notify the pc that we are ready to receive data
+    /// The sabertooths must be powered on before this is called
+    pub fn init<Pc, Motors, Analog, Time, Button, Enable, Priority, EStop, Store, Aux>(&mut self, p: &mut Peripherals<Pc, Motors, Analog, Time, Button, Enable, Priority, EStop, Store, Aux>)
+        where
+            Pc: SerialTx,
+            Motors: SerialTx,
+            Store: Storage,
+    {
+        self.state.set_calibration(storage::load_calibration(&mut p.storage));
+
+        self.write_callback(sabertooth::write_init, &mut p.motors);
+        for address in sabertooth::ADDRESSES {
+            for setting in sabertooth::DEFAULT_PROFILE.settings() {
+                self.write_callback(|buffer| sabertooth::write_setting(buffer, address, setting), &mut p.motors);
+            }
+        }
+        self.write_hello(&mut p.pc);
+    }
+
+    /// Run one iteration of the control loop
+    pub fn step<Pc, Motors, Analog, Time, Button, Enable, Priority, EStop, Store, Aux>(&mut self, p: &mut Peripherals<Pc, Motors, Analog, Time, Button, Enable, Priority, EStop, Store, Aux>)
+        where
+            Pc: SerialRx + SerialTx,
+            Motors: SerialTx,
+            Analog: Adc,
+            Time: Clock,
+            Button: InputPin,
+            Enable: InputPin,
+            Priority: InputPin,
+            EStop: OutputPin,
+            Store: Storage,
+            Aux: Actuators,
+    {
+        let now = p.clock.millis();
+        self.telemetry.tick(p.clock.micros());
+        self.counters.rx_overruns = p.pc.overruns();
+
+        // process data from computer
+        while let Some(byte) = p.pc.read() {
+            self.receive(byte, now, &mut p.pc);
+        }
+
+        // Update joystick state
+        {
+            // Treat a pin we can't read as disabled
+            if p.joystick_enable.is_low().unwrap_or(false) {
+                let joystick_velocity = joystick::read(&mut p.analog);
+                self.state.update_joystick(joystick_velocity);
+            } else {
+                self.state.update_joystick(VelocityData::default());
+            }
+
+            // Treat a pin we can't read as released
+            self.state.update_joystick_priority(p.joystick_priority.is_low().unwrap_or(false));
+        }
+
+        // Read leak and power sensors
+        {
+            let sensors = sensors::read(&mut p.analog);
+            self.state.update_sensors(sensors, now);
+        }
+
+        // Read emergency stop button
+        {
+            // Treat a pin we can't read as pressed
+            let emergency_stop = p.estop_button.is_high().unwrap_or(true);
+            self.state.update_emergency_stop(emergency_stop);
+            self.state.update_failsafe(now);
+        }
+
+        // Tell the motor controllers to go into an emergency stop if necessary
+        {
+            // The sabertooth's emergency stop pin is active low
+            let _ = if self.state.emergency_stop() {
+                p.estop_out.set_low()
+            } else {
+                p.estop_out.set_high()
+            };
+        }
+
+        // Respond to hellos
+        {
+            if self.state.do_hello() {
+                self.write_hello(&mut p.pc);
+                self.state.clear_hello();
+            }
+        }
+
+        // Respond to pings
+        {
+            if self.state.do_ping() {
+                self.write_message(&UpstreamMessage::Pong, &mut p.pc);
+                self.state.clear_ping();
+            }
+        }
+
+        // Report calibration changes
+        {
+            let report = self.state.report_calibration();
+            let calibration = *self.state.calibration();
+            for (motor, calibration) in calibration.motors.into_iter().enumerate() {
+                if report & (1 << motor) != 0 {
+                    self.write_message(&UpstreamMessage::Calibration(motor as u8, calibration), &mut p.pc);
+                }
+            }
+            self.state.clear_report_calibration();
+        }
+
+        // Store calibration changes
+        {
+            if self.state.save_calibration() {
+                self.calibration_writer.start(self.state.calibration());
+                self.state.clear_save_calibration();
+            }
+
+            self.calibration_writer.poll(&mut p.storage);
+        }
+
+        // Send updated motor speeds
+        {
+            let total_velocity = self.state.compute_velocity(now);
+            let total_velocity = self.state.limit_velocity(&total_velocity, now);
+            let thrusters = self.state.mixing().mix(&total_velocity);
+            self.write_speeds(&thrusters.0, now, &mut p.motors);
+
+            // Drive the auxiliary actuators
+            let actuators = self.state.actuators(now);
+            for actuator in Actuator::ALL {
+                p.actuators.set(actuator, actuator::output(actuator, actuators.get(actuator)));
+            }
+
+            // Notify the connected pc
+            let emergency_stop = self.state.emergency_stop_state();
+            let failsafe = self.state.failsafe(now);
+            if self.telemetry.due(now, self.state.telemetry_interval(), emergency_stop, failsafe) {
+                let telemetry = Telemetry {
+                    timestamp: p.clock.micros(),
+                    loop_time: self.telemetry.sent(now, emergency_stop, failsafe),
+                    pc_setpoint: self.state.pc_setpoint(now),
+                    joystick_setpoint: self.state.joystick_setpoint(),
+                    thrusters,
+                    arbitration: self.state.arbitration(),
+                    control_source: self.state.control_source(),
+                    emergency_stop,
+                    failsafe,
+                    actuators,
+                    sensors: self.state.sensors(),
+                    alarms: self.state.alarms(),
+                    counters: self.counters
+                };
+                self.write_message(&UpstreamMessage::Telemetry(telemetry), &mut p.pc);
+            }
+        }
+    }
+
+    /// Process a byte received from the pc
+    fn receive(&mut self, byte: u8, now: u32, pc: &mut impl SerialTx) {
+        // Add that byte to the buffer
+        if let Ok(()) = self.packet.push(byte) {
+            // If that byte signals the end of a packet we needed to parse the packet
+            if common::end_of_frame(&byte) {
+                match common::read::<DownstreamMessage>(&mut self.packet) {
+                    Ok(envelope) => {
+                        // Update the robot's state and send acknowledgement
+                        self.state.update_pc(envelope.message, now);
+                        self.write_message(&UpstreamMessage::Ack(envelope.seq), pc);
+                        self.counters.packets = self.counters.packets.wrapping_add(1);
+                    }
+                    Err(e) => {
+                        // data was corrupted during transmission
+                        self.write_message(&UpstreamMessage::BadP(e), pc);
+                        self.counters.bad_packets = self.counters.bad_packets.wrapping_add(1);
+                    }
+                }
+
+                // Clear the packet buffer so we can receive the next packet
+                self.packet.clear();
+            }
+        } else {
+            // data buffer was over run, a seperator byte was mis-received
+            self.write_message(&UpstreamMessage::BadO, pc);
+            self.counters.overruns = self.counters.overruns.wrapping_add(1);
+            self.packet.clear();
+        }
+    }
+
+    /// Write the speeds that changed, paced so the sabertooth link can keep up
+    fn write_speeds(&mut self, thrusters: &[f32; MOTOR_COUNT], now: u32, motors: &mut impl SerialTx) {
+        let since_sent = match self.speeds_sent {
+            Some(sent) => now.wrapping_sub(sent),
+            None => u32::MAX
+        };
+        if since_sent < sabertooth::SPEED_INTERVAL {
+            return;
+        }
+
+        // Everything is written again now and then in case a packet was corrupted on the way
+        let refresh = since_sent >= sabertooth::SPEED_REFRESH;
+        let calibration = self.state.calibration().motors;
+        let mut sent = false;
+        for motor in 0..MOTOR_COUNT {
+            let speed = (calibration[motor].apply(thrusters[motor]) * 127.0) as i8;
+            if refresh || self.speeds[motor] != Some(speed) {
+                let written = self.write_callback(|buffer| sabertooth::write_speed(buffer, sabertooth::MOTORS[motor], speed), motors);
+                self.speeds[motor] = if written { Some(speed) } else { None };
+                sent = true;
+            }
+        }
+
+        if sent {
+            self.speeds_sent = Some(now);
+        }
+    }
+
+    /// Identify ourselves to the pc
+    fn write_hello(&mut self, pc: &mut impl SerialTx) {
+        self.write_message(&UpstreamMessage::Hello(self.hello.clone()), pc);
+
+        if let Some(record) = self.panic {
+            if let Some(report) = record.report() {
+                self.write_message(&UpstreamMessage::Panic(report), pc);
+            }
+        }
+    }
+
+    fn write_message(&mut self, message: &UpstreamMessage, serial: &mut impl SerialTx) {
+        // Encode the packet with the next sequence number into the temporary buffer
+        self.out_sequence = self.out_sequence.wrapping_add(1);
+        if let Ok(buffer) = common::write(self.out_sequence, message, &mut self.out_buffer) {
+            // Write the buffer
+            if !write_buffer(buffer, serial) {
+                self.counters.tx_overflows = self.counters.tx_overflows.wrapping_add(1);
+            }
+        }
+    }
+
+    /// Returns false if the packet was dropped
+    fn write_callback<F: Fn(&mut [u8]) -> Result<&mut [u8], CommunicationError>>(&mut self, message_producer: F, serial: &mut impl SerialTx) -> bool {
+        // Encode the packet into the temporary buffer
+        if let Ok(buffer) = (message_producer)(&mut self.out_buffer) {
+            // Write the buffer
+            if !write_buffer(buffer, serial) {
+                self.counters.tx_overflows = self.counters.tx_overflows.wrapping_add(1);
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Queue a packet without blocking, returns false if it was dropped because it didn't fit
+fn write_buffer(buffer: &[u8], serial: &mut impl SerialTx) -> bool {
+    // Drop the whole packet, part of one would corrupt the next
+    if serial.space() < buffer.len() {
+        return false;
+    }
+
+    for &byte in buffer {
+        serial.write(byte);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use common::calibration::{DEFAULT_CALIBRATION, MotorCalibration};
+    use common::sensors::{Alarms, DEFAULT_THRESHOLDS, SensorThresholds};
+    use common::controller::{ArbitrationMode, ControlSource, EStopReason, EStopState, LoopStats, ResetCause};
+    use common::mixing::{AXES, DEFAULT_MIXING};
+    use crate::mock::*;
+    use crate::telemetry::MIN_TELEMETRY_INTERVAL;
+    use super::*;
+
+    const HELLO: Hello = Hello {
+        protocol_version: common::controller::PROTOCOL_VERSION,
+        build_id: "test",
+        capabilities: 0,
+        reset_cause: ResetCause(ResetCause::WATCHDOG),
+    };
+
+    const DEFAULT_PROFILE_PACKETS: usize = 5;
+
+    fn setup() -> (Controller, MockPeripherals) {
+        let mut controller = Controller::new(HELLO);
+        let mut p = MockPeripherals::default();
+        controller.init(&mut p);
+
+        // Make setpoints apply immediately and report as often as possible
+        p.pc.send(&DownstreamMessage::SetSlewRate([0.0; AXES]));
+        p.pc.send(&DownstreamMessage::SetTelemetryInterval(0));
+        controller.step(&mut p);
+        p.pc.take_messages();
+
+        (controller, p)
+    }
+
+    /// Step once the next telemetry is due
+    fn step(controller: &mut Controller, p: &mut MockPeripherals) {
+        p.clock.0 += MIN_TELEMETRY_INTERVAL;
+        controller.step(p);
+    }
+
+    fn velocity(surge: f32) -> DownstreamMessage {
+        DownstreamMessage::VelocityUpdate(VelocityData { surge, ..Default::default() })
+    }
+
+    fn last_telemetry(messages: &[Upstream]) -> Telemetry {
+        messages.iter().rev()
+            .find_map(|message| match message {
+                Upstream::Telemetry(telemetry) => Some(telemetry.clone()),
+                _ => None
+            })
+            .expect("no telemetry was sent")
+    }
+
+    fn last_thrusters(messages: &[Upstream]) -> [f32; 4] {
+        last_telemetry(messages).thrusters.0
+    }
+
+    #[test]
+    fn hello_on_init() {
+        let mut controller = Controller::new(HELLO);
+        let mut p = MockPeripherals::default();
+        controller.init(&mut p);
+
+        // Auto bauding then the profile for each sabertooth
+        assert_eq!(p.motors.tx[0], 0xAA);
+        assert_eq!(p.motors.tx.len(), 1 + sabertooth::ADDRESSES.len() * DEFAULT_PROFILE_PACKETS * 4);
+        assert_eq!(p.pc.take_messages(), [Upstream::Hello(common::controller::PROTOCOL_VERSION)]);
+    }
+
+    #[test]
+    fn valid_packet_is_acked() {
+        let (mut controller, mut p) = setup();
+
+        let seq = p.pc.send(&DownstreamMessage::Ping);
+        controller.step(&mut p);
+
+        let messages = p.pc.take_messages();
+        assert!(messages.contains(&Upstream::Ack(seq)));
+        assert!(messages.contains(&Upstream::Pong));
+    }
+
+    #[test]
+    fn corrupted_packet_is_rejected() {
+        let (mut controller, mut p) = setup();
+
+        let mut frame = MockSerial::encode(0, &velocity(1.0));
+        frame[2] ^= 0x55;
+        p.pc.rx.extend(frame);
+        step(&mut controller, &mut p);
+
+        let messages = p.pc.take_messages();
+        assert!(messages.iter().any(|message| matches!(message, Upstream::BadP)));
+        assert!(!messages.iter().any(|message| matches!(message, Upstream::Ack(_))));
+        assert_eq!(last_thrusters(&messages), [0.0; 4]);
+
+        // The next packet is still received
+        p.pc.send(&velocity(1.0));
+        step(&mut controller, &mut p);
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [1.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn overrun_is_reported() {
+        let (mut controller, mut p) = setup();
+
+        // A frame whose separator was lost
+        p.pc.rx.extend([0xFF; 128]);
+        step(&mut controller, &mut p);
+        assert!(p.pc.take_messages().contains(&Upstream::BadO));
+
+        // Resynchronises on the next separator
+        p.pc.rx.push_back(0);
+        p.pc.send(&velocity(0.5));
+        step(&mut controller, &mut p);
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [0.5, 0.5, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn estop_button_latches_until_armed() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.send(&velocity(1.0));
+        step(&mut controller, &mut p);
+        assert!(p.estop_out.0);
+
+        p.estop_button.0 = true;
+        step(&mut controller, &mut p);
+        assert!(!p.estop_out.0);
+        assert_eq!(controller.state().emergency_stop_state(), EStopState::Latched(EStopReason::Button));
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [0.0; 4]);
+
+        // Arming is refused while the button is held
+        p.pc.send(&DownstreamMessage::Arm);
+        step(&mut controller, &mut p);
+        assert!(!p.estop_out.0);
+
+        // Releasing the button doesn't rearm on its own
+        p.estop_button.0 = false;
+        step(&mut controller, &mut p);
+        assert!(!p.estop_out.0);
+
+        p.pc.send(&DownstreamMessage::Arm);
+        step(&mut controller, &mut p);
+        assert!(p.estop_out.0);
+        assert_eq!(last_telemetry(&p.pc.take_messages()).emergency_stop, EStopState::Armed);
+
+        // The setpoint from before the emergency stop was discarded
+        step(&mut controller, &mut p);
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [0.0; 4]);
+    }
+
+    #[test]
+    fn estop_from_pc() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.send(&velocity(1.0));
+        p.pc.send(&DownstreamMessage::EmergencyStop);
+        controller.step(&mut p);
+
+        assert!(!p.estop_out.0);
+        assert_eq!(controller.state().emergency_stop_state(), EStopState::Latched(EStopReason::Pc));
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [0.0; 4]);
+    }
+
+    #[test]
+    fn estop_resets_slew_limit() {
+        let (mut controller, mut p) = setup();
+        p.pc.send(&DownstreamMessage::SetSlewRate([1.0; AXES]));
+        controller.step(&mut p);
+
+        p.clock.0 += 250;
+        p.pc.send(&velocity(1.0));
+        controller.step(&mut p);
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [0.25, 0.25, 0.0, 0.0]);
+
+        // The stop is immediate, and the robot ramps up from zero once armed again
+        p.pc.send(&DownstreamMessage::EmergencyStop);
+        controller.step(&mut p);
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [0.0; 4]);
+
+        p.pc.send(&DownstreamMessage::Arm);
+        p.pc.send(&velocity(1.0));
+        controller.step(&mut p);
+        p.clock.0 += 250;
+        p.pc.send(&velocity(1.0));
+        controller.step(&mut p);
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [0.25, 0.25, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn pc_and_joystick_setpoints_add() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.send(&velocity(0.25));
+        // Full forwards on the left stick
+        p.analog.0[joystick::LEFT_Y as usize] = 0;
+        step(&mut controller, &mut p);
+
+        // The joystick is ignored until enabled
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [0.25, 0.25, 0.0, 0.0]);
+
+        p.joystick_enable.0 = false;
+        step(&mut controller, &mut p);
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [1.0, 1.0, 0.0, 0.0]);
+
+        // Opposing setpoints cancel out
+        p.pc.send(&velocity(-1.0));
+        step(&mut controller, &mut p);
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [0.0; 4]);
+    }
+
+    #[test]
+    fn unusable_mixing_is_rejected() {
+        let (mut controller, mut p) = setup();
+
+        // Only as many thrusters as there are motors, and every weight has to be finite
+        p.pc.send(&DownstreamMessage::SetMixing(sabertooth::MOTORS.len() as u8, [1.0; AXES]));
+        p.pc.send(&DownstreamMessage::SetMixing(1, [f32::INFINITY, 0.0, 0.0, 0.0, 0.0, 0.0]));
+        p.pc.send(&DownstreamMessage::SetMixing(2, [f32::NAN; AXES]));
+        p.pc.send(&DownstreamMessage::SetMixing(0, [0.5, 0.0, 0.0, 0.0, 0.0, 0.0]));
+        p.pc.send(&velocity(1.0));
+        step(&mut controller, &mut p);
+
+        let mut expected = DEFAULT_MIXING;
+        expected.weights[0] = [0.5, 0.0, 0.0, 0.0, 0.0, 0.0];
+        assert_eq!(*controller.state().mixing(), expected);
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [0.5, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn failsafe_drops_pc_but_not_joystick() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.send(&velocity(1.0));
+        p.analog.0[joystick::RIGHT_Y as usize] = 0;
+        p.joystick_enable.0 = false;
+        step(&mut controller, &mut p);
+        assert_eq!(last_thrusters(&p.pc.take_messages()), [1.0, 1.0, 0.0, 1.0]);
+
+        p.clock.0 += crate::state::DEFAULT_FAILSAFE_TIMEOUT + crate::state::FAILSAFE_RAMP + 1;
+        step(&mut controller, &mut p);
+
+        let messages = p.pc.take_messages();
+        assert!(last_telemetry(&messages).failsafe);
+        assert_eq!(last_thrusters(&messages), [0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn calibration_is_stored() {
+        let (mut controller, mut p) = setup();
+        assert_eq!(*controller.state().calibration(), DEFAULT_CALIBRATION);
+
+        let calibration = MotorCalibration { inverted: false, trim: 0.0, max_output: 0.5 };
+        p.pc.send(&DownstreamMessage::SetCalibration(0, calibration));
+        p.pc.send(&velocity(1.0));
+        p.motors.tx.clear();
+        step(&mut controller, &mut p);
+        assert!(p.pc.take_messages().contains(&Upstream::Calibration(0, calibration)));
+
+        // Left is driven at half speed and no longer inverted
+        assert_eq!(p.motors.tx[..4], [128, 4, 63, 67]);
+
+        // One byte is written per step
+        for _ in 0..common::calibration::RECORD_SIZE {
+            step(&mut controller, &mut p);
+        }
+        assert!(p.storage.writes > 0);
+
+        // The calibration survives a reboot
+        let mut rebooted = Controller::new(HELLO);
+        rebooted.init(&mut p);
+        assert_eq!(rebooted.state().calibration().motors[0], calibration);
+        assert_eq!(rebooted.state().calibration().motors[1..], DEFAULT_CALIBRATION.motors[1..]);
+    }
+
+    #[test]
+    fn calibration_is_reported() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.send(&DownstreamMessage::GetCalibration);
+        controller.step(&mut p);
+
+        let messages = p.pc.take_messages();
+        for (motor, calibration) in DEFAULT_CALIBRATION.motors.iter().enumerate() {
+            assert!(messages.contains(&Upstream::Calibration(motor as u8, *calibration)));
+        }
+    }
+
+    #[test]
+    fn telemetry_is_rate_limited() {
+        let (mut controller, mut p) = setup();
+        p.pc.send(&DownstreamMessage::SetTelemetryInterval(100));
+        controller.step(&mut p);
+        p.pc.take_messages();
+
+        let telemetry_count = |p: &mut MockPeripherals| p.pc.take_messages().iter()
+            .filter(|message| matches!(message, Upstream::Telemetry(_)))
+            .count();
+
+        for _ in 0..10 {
+            p.clock.0 += 10;
+            controller.step(&mut p);
+        }
+        assert_eq!(telemetry_count(&mut p), 1);
+
+        // Emergency stops are reported immediately
+        p.clock.0 += 1;
+        p.estop_button.0 = true;
+        controller.step(&mut p);
+        let messages = p.pc.take_messages();
+        assert_eq!(last_telemetry(&messages).emergency_stop, EStopState::Latched(EStopReason::Button));
+
+        p.clock.0 += 1;
+        controller.step(&mut p);
+        assert_eq!(telemetry_count(&mut p), 0);
+    }
+
+    #[test]
+    fn telemetry_interval_is_clamped() {
+        let (mut controller, mut p) = setup();
+
+        // The setup asked for telemetry every loop
+        for _ in 0..MIN_TELEMETRY_INTERVAL {
+            p.clock.0 += 1;
+            controller.step(&mut p);
+        }
+        let telemetry_count = p.pc.take_messages().iter()
+            .filter(|message| matches!(message, Upstream::Telemetry(_)))
+            .count();
+        assert_eq!(telemetry_count, 1);
+    }
+
+    #[test]
+    fn telemetry_contents() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.send(&velocity(0.5));
+        p.pc.rx.extend([0xFF, 0x00]);
+        p.analog.0[joystick::LEFT_Y as usize] = 0;
+        p.joystick_enable.0 = false;
+        step(&mut controller, &mut p);
+
+        let telemetry = last_telemetry(&p.pc.take_messages());
+        assert_eq!(telemetry.pc_setpoint.surge, 0.5);
+        assert_eq!(telemetry.joystick_setpoint.surge, 1.0);
+        assert_eq!(telemetry.counters.packets, 3);
+        assert_eq!(telemetry.counters.bad_packets, 1);
+    }
+
+    #[test]
+    fn panic_is_reported_after_hello() {
+        let mut controller = Controller::new(HELLO);
+        controller.set_panic(PanicRecord::new("src/state.rs", 10, 20));
+        let mut p = MockPeripherals::default();
+
+        controller.init(&mut p);
+        let panic = Upstream::Panic("src/state.rs".into(), 10, 20);
+        assert_eq!(p.pc.take_messages(), [Upstream::Hello(common::controller::PROTOCOL_VERSION), panic.clone()]);
+
+        // And again whenever the pc asks who we are
+        p.pc.send(&DownstreamMessage::Hello(common::controller::PROTOCOL_VERSION));
+        controller.step(&mut p);
+        assert!(p.pc.take_messages().contains(&panic));
+    }
+
+    #[test]
+    fn arbitration_modes() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.send(&velocity(0.25));
+        p.analog.0[joystick::LEFT_Y as usize] = 0;
+        p.joystick_enable.0 = false;
+
+        let cases = [
+            (ArbitrationMode::Sum, ControlSource::Both, 1.0),
+            (ArbitrationMode::PcOnly, ControlSource::Pc, 0.25),
+            (ArbitrationMode::JoystickOnly, ControlSource::Joystick, 1.0),
+            (ArbitrationMode::JoystickOverride, ControlSource::Joystick, 1.0),
+        ];
+        for (mode, source, surge) in cases {
+            p.pc.send(&DownstreamMessage::SetArbitration(mode));
+            step(&mut controller, &mut p);
+
+            let telemetry = last_telemetry(&p.pc.take_messages());
+            assert_eq!(telemetry.arbitration, mode);
+            assert_eq!(telemetry.control_source, source);
+            assert_eq!(telemetry.thrusters.0[0], surge);
+        }
+
+        // The pc takes back control once the stick is released
+        p.analog.0[joystick::LEFT_Y as usize] = 512;
+        step(&mut controller, &mut p);
+        let telemetry = last_telemetry(&p.pc.take_messages());
+        assert_eq!(telemetry.control_source, ControlSource::Pc);
+        assert_eq!(telemetry.thrusters.0[0], 0.25);
+    }
+
+    #[test]
+    fn joystick_priority_pin() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.send(&DownstreamMessage::SetArbitration(ArbitrationMode::PcOnly));
+        p.pc.send(&velocity(0.25));
+        p.joystick_priority.0 = false;
+        step(&mut controller, &mut p);
+
+        // The joysticks are disabled so nothing moves
+        let telemetry = last_telemetry(&p.pc.take_messages());
+        assert_eq!(telemetry.arbitration, ArbitrationMode::JoystickOnly);
+        assert_eq!(telemetry.thrusters.0[0], 0.0);
+
+        p.joystick_priority.0 = true;
+        step(&mut controller, &mut p);
+        let telemetry = last_telemetry(&p.pc.take_messages());
+        assert_eq!(telemetry.arbitration, ArbitrationMode::PcOnly);
+        assert_eq!(telemetry.thrusters.0[0], 0.25);
+    }
+
+    #[test]
+    fn actuators_follow_pc() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.send(&DownstreamMessage::SetActuator(Actuator::CameraTilt, 1.0));
+        p.pc.send(&DownstreamMessage::SetActuator(Actuator::Lights, 2.0));
+        p.pc.send(&DownstreamMessage::SetActuator(Actuator::Aux, 1.0));
+        step(&mut controller, &mut p);
+
+        assert_eq!(p.actuators.0, [
+            Some(actuator::Output::Servo(1500)),
+            Some(actuator::Output::Servo(actuator::SERVO_MAX_PULSE)),
+            Some(actuator::Output::Pwm(255)),
+            Some(actuator::Output::Digital(true)),
+        ]);
+        assert_eq!(last_telemetry(&p.pc.take_messages()).actuators.0, [0.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn actuators_are_safe_on_estop() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.send(&DownstreamMessage::SetActuator(Actuator::Claw, 1.0));
+        controller.step(&mut p);
+        assert_eq!(p.actuators.0[0], Some(actuator::Output::Servo(actuator::SERVO_MAX_PULSE)));
+
+        p.pc.send(&DownstreamMessage::EmergencyStop);
+        controller.step(&mut p);
+        assert_eq!(p.actuators.0[0], Some(actuator::Output::Servo(1500)));
+
+        // Arming doesn't bring back the old values
+        p.pc.send(&DownstreamMessage::Arm);
+        controller.step(&mut p);
+        assert_eq!(p.actuators.0[0], Some(actuator::Output::Servo(1500)));
+    }
+
+    #[test]
+    fn actuators_are_safe_on_comms_loss() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.send(&DownstreamMessage::SetActuator(Actuator::Lights, 1.0));
+        controller.step(&mut p);
+        assert_eq!(p.actuators.0[2], Some(actuator::Output::Pwm(255)));
+
+        p.clock.0 += crate::state::DEFAULT_FAILSAFE_TIMEOUT + 1;
+        controller.step(&mut p);
+        assert_eq!(p.actuators.0[2], Some(actuator::Output::Pwm(0)));
+
+        // The lights stay off once the pc is back until it sets them again
+        p.pc.send(&DownstreamMessage::Ping);
+        controller.step(&mut p);
+        assert_eq!(p.actuators.0[2], Some(actuator::Output::Pwm(0)));
+    }
+
+    #[test]
+    fn leak_latches_estop() {
+        let (mut controller, mut p) = setup();
+
+        p.analog.0[sensors::LEAK as usize] = 1023;
+        step(&mut controller, &mut p);
+        let telemetry = last_telemetry(&p.pc.take_messages());
+        assert!(telemetry.sensors.leak);
+        assert!(telemetry.alarms.is_empty());
+        assert!(p.estop_out.0);
+
+        p.clock.0 += sensors::ALARM_DEBOUNCE;
+        p.pc.send(&DownstreamMessage::Ping);
+        step(&mut controller, &mut p);
+        let telemetry = last_telemetry(&p.pc.take_messages());
+        assert_eq!(telemetry.alarms, Alarms(Alarms::LEAK));
+        assert_eq!(telemetry.emergency_stop, EStopState::Latched(EStopReason::Leak));
+        assert!(!p.estop_out.0);
+
+        // The robot can be armed again to bring it to the surface
+        p.pc.send(&DownstreamMessage::Arm);
+        step(&mut controller, &mut p);
+        assert!(p.estop_out.0);
+        assert_eq!(last_telemetry(&p.pc.take_messages()).alarms, Alarms(Alarms::LEAK));
+    }
+
+    #[test]
+    fn low_voltage_thresholds() {
+        let (mut controller, mut p) = setup();
+
+        // Only raise an alarm
+        p.pc.send(&DownstreamMessage::SetSensorThresholds(SensorThresholds { estop_alarms: 0, ..DEFAULT_THRESHOLDS }));
+        p.analog.0[sensors::TETHER_VOLTAGE as usize] = 100;
+        controller.step(&mut p);
+        p.clock.0 += sensors::ALARM_DEBOUNCE;
+        p.pc.send(&DownstreamMessage::Ping);
+        controller.step(&mut p);
+
+        let telemetry = last_telemetry(&p.pc.take_messages());
+        assert!(telemetry.sensors.tether_voltage < DEFAULT_THRESHOLDS.min_tether_voltage);
+        assert_eq!(telemetry.alarms, Alarms(Alarms::LOW_TETHER));
+        assert_eq!(telemetry.emergency_stop, EStopState::Armed);
+    }
+
+    #[test]
+    fn loop_timing_and_overruns() {
+        let (mut controller, mut p) = setup();
+
+        p.pc.overruns = 3;
+        for _ in 0..4 {
+            p.clock.0 += 5;
+            controller.step(&mut p);
+        }
+
+        let telemetry = last_telemetry(&p.pc.take_messages());
+        assert_eq!(telemetry.timestamp, p.clock.0 * 1000);
+        assert_eq!(telemetry.loop_time, LoopStats { min: 5000, max: 5000, avg: 5000 });
+        assert_eq!(telemetry.counters.rx_overruns, 3);
+    }
+
+    #[test]
+    fn full_transmit_buffers_drop_whole_packets() {
+        let (mut controller, mut p) = setup();
+
+        // No room for the acks, pong and telemetry, room for only one of the two motors that change
+        p.pc.tx_capacity = 0;
+        p.motors.tx_capacity = p.motors.tx.len() + 4;
+        p.pc.send(&velocity(1.0));
+        p.pc.send(&DownstreamMessage::Ping);
+        let motors_sent = p.motors.tx.len();
+        step(&mut controller, &mut p);
+        assert!(p.pc.tx.is_empty());
+        assert_eq!(p.motors.tx.len(), motors_sent + 4);
+
+        // Nothing blocked, the drops are reported and the lost speed is written once there is room again
+        p.pc.tx_capacity = usize::MAX;
+        p.motors.tx_capacity = usize::MAX;
+        step(&mut controller, &mut p);
+        assert_eq!(last_telemetry(&p.pc.take_messages()).counters.tx_overflows, 5);
+        assert_eq!(p.motors.tx.len(), motors_sent + 8);
+    }
+
+    #[test]
+    fn speeds_are_paced() {
+        let (mut controller, mut p) = setup();
+        p.motors.tx.clear();
+
+        // Unchanged speeds are only refreshed now and then
+        for _ in 1..sabertooth::SPEED_REFRESH {
+            p.clock.0 += 1;
+            controller.step(&mut p);
+        }
+        assert!(p.motors.tx.is_empty());
+        p.clock.0 += 1;
+        controller.step(&mut p);
+        assert_eq!(p.motors.tx.len(), MOTOR_COUNT * 4);
+
+        // Changes wait for the link to catch up
+        p.motors.tx.clear();
+        p.pc.send(&velocity(1.0));
+        controller.step(&mut p);
+        assert!(p.motors.tx.is_empty());
+
+        // Then only the motors that changed are written
+        p.clock.0 += sabertooth::SPEED_INTERVAL;
+        controller.step(&mut p);
+        assert_eq!(p.motors.tx.len(), 2 * 4);
+        assert_eq!(last_telemetry(&p.pc.take_messages()).counters.tx_overflows, 0);
+    }
+}
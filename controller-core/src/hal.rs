@@ -0,0 +1,53 @@
+//! Traits the control loop uses to talk to the hardware
+
+pub use embedded_hal::digital::v2::{InputPin, OutputPin};
+use common::actuator::Actuator;
+use crate::actuator::Output;
+
+/// A serial port that buffers the bytes it receives
+pub trait SerialRx {
+    /// Take the next received byte, if there is one
+    fn read(&mut self) -> Option<u8>;
+
+    /// Bytes dropped since boot because the buffer was full
+    fn overruns(&mut self) -> u32 {
+        0
+    }
+}
+
+/// A serial port that queues bytes and sends them in the background
+pub trait SerialTx {
+    /// How many bytes can be queued without blocking
+    fn space(&mut self) -> usize;
+    /// Queue a byte, only called while there is `space`
+    fn write(&mut self, byte: u8);
+}
+
+/// An analog to digital converter
+pub trait Adc {
+    /// Sample a channel, returns a value between 0 and 1023
+    fn read(&mut self, channel: u8) -> u16;
+}
+
+/// A millisecond and microsecond clock
+pub trait Clock {
+    /// Milliseconds since boot, wraps around after ~50 days
+    fn millis(&mut self) -> u32;
+    /// Microseconds since boot, wraps around after ~71 minutes
+    fn micros(&mut self) -> u32;
+}
+
+/// Non volatile storage that is written one byte at a time
+pub trait Storage {
+    fn read(&mut self, address: u16) -> u8;
+    /// Can another byte be written without blocking
+    fn ready(&mut self) -> bool;
+    /// Start writing a byte, only called while `ready`
+    fn write(&mut self, address: u16, byte: u8);
+}
+
+/// The outputs driving the auxiliary actuators
+pub trait Actuators {
+    /// Drive an actuator, the output always has the kind `actuator::output` uses for it
+    fn set(&mut self, actuator: Actuator, output: Output);
+}
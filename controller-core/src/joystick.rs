@@ -0,0 +1,28 @@
+use common::controller::VelocityData;
+use crate::hal::Adc;
+
+// Adc channel of each joystick axis
+pub const LEFT_X: u8 = 0;
+pub const LEFT_Y: u8 = 1;
+pub const RIGHT_X: u8 = 2;
+pub const RIGHT_Y: u8 = 3;
+
+/// Read the velocity requested by the onboard joysticks
+pub fn read(adc: &mut impl Adc) -> VelocityData {
+    const ADC_SCALE: f32 = 1023.0;
+
+    let max = 0.93;
+    let min = 0.07;
+
+    let lx = adc.read(LEFT_X) as f32 / ADC_SCALE * 2.0 - 1.0;
+    let ly = adc.read(LEFT_Y) as f32 / ADC_SCALE * 2.0 - 1.0;
+    let rx = adc.read(RIGHT_X) as f32 / ADC_SCALE * 2.0 - 1.0;
+    let ry = adc.read(RIGHT_Y) as f32 / ADC_SCALE * 2.0 - 1.0;
+
+    let lx = -common::clamp_map_val(lx, min, max);
+    let ly = -common::clamp_map_val(ly, min, max);
+    let rx = common::clamp_map_val(rx, min, max);
+    let ry = -common::clamp_map_val(ry, min, max);
+
+    common::joystick_math(lx, ly, rx, ry)
+}
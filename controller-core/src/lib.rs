@@ -0,0 +1,25 @@
+#![no_std]
+#![cfg_attr(target_arch = "avr", feature(asm_experimental_arch))]
+
+//! Hardware independent part of the controller firmware
+//!
+//! Everything that touches the hardware goes through the traits in `hal`,
+//! so the same control loop runs on the arduino and against mock hardware in `cargo test`
+
+pub mod actuator;
+pub mod controller;
+pub mod hal;
+pub mod joystick;
+pub mod panic;
+pub mod sabertooth;
+pub mod sensors;
+pub mod slew;
+// Vendored from heapless, kept as close to upstream as possible
+#[allow(clippy::all)]
+pub mod spsc;
+pub mod state;
+pub mod telemetry;
+pub mod storage;
+
+#[cfg(test)]
+mod mock;
@@ -0,0 +1,223 @@
+//! Mock hardware for running the control loop on the host
+
+extern crate std;
+
+use core::convert::Infallible;
+use std::collections::VecDeque;
+use std::string::String;
+use std::vec::Vec;
+use common::actuator::{ACTUATOR_COUNT, Actuator};
+use common::calibration::MotorCalibration;
+use common::controller::{DownstreamMessage, Telemetry, UpstreamMessage};
+use crate::actuator::Output;
+use crate::controller::Peripherals;
+use crate::hal::{Actuators, Adc, Clock, InputPin, OutputPin, SerialRx, SerialTx, Storage};
+
+pub type MockPeripherals = Peripherals<MockSerial, MockSerial, MockAdc, MockClock, MockPin, MockPin, MockPin, MockPin, MockStorage, MockActuators>;
+
+impl Default for MockPeripherals {
+    fn default() -> Self {
+        Peripherals {
+            pc: MockSerial::default(),
+            motors: MockSerial::default(),
+            // Sticks centered, 12V supplies and a dry leak probe
+            analog: MockAdc([512, 512, 512, 512, 223, 223, 0]),
+            clock: MockClock(0),
+            // Button released
+            estop_button: MockPin(false),
+            // Joystick disabled
+            joystick_enable: MockPin(true),
+            // Joystick priority released
+            joystick_priority: MockPin(true),
+            estop_out: MockPin(false),
+            storage: MockStorage::default(),
+            actuators: MockActuators::default()
+        }
+    }
+}
+
+/// A serial port, `rx` holds bytes waiting to be read and `tx` holds everything written
+/// `tx` never drains by itself, it holds at most `tx_capacity` bytes
+pub struct MockSerial {
+    pub rx: VecDeque<u8>,
+    pub tx: Vec<u8>,
+    pub tx_capacity: usize,
+    pub overruns: u32,
+    seq: u16,
+}
+
+impl Default for MockSerial {
+    fn default() -> Self {
+        MockSerial {
+            rx: VecDeque::new(),
+            tx: Vec::new(),
+            tx_capacity: usize::MAX,
+            overruns: 0,
+            seq: 0
+        }
+    }
+}
+
+impl MockSerial {
+    pub fn encode(seq: u16, message: &DownstreamMessage) -> Vec<u8> {
+        let mut buffer = [0; 256];
+        common::write(seq, message, &mut buffer).unwrap().to_vec()
+    }
+
+    /// Queue a message to be read, returns its sequence number
+    pub fn send(&mut self, message: &DownstreamMessage) -> u16 {
+        self.seq = self.seq.wrapping_add(1);
+        self.rx.extend(Self::encode(self.seq, message));
+        self.seq
+    }
+
+    /// Decode and clear everything written so far
+    pub fn take_messages(&mut self) -> Vec<Upstream> {
+        let mut tx = core::mem::take(&mut self.tx);
+        tx.split_inclusive_mut(common::end_of_frame)
+            .map(|frame| Upstream::from(common::read::<UpstreamMessage>(frame).unwrap().message))
+            .collect()
+    }
+}
+
+impl SerialRx for MockSerial {
+    fn read(&mut self) -> Option<u8> {
+        self.rx.pop_front()
+    }
+
+    fn overruns(&mut self) -> u32 {
+        self.overruns
+    }
+}
+
+impl SerialTx for MockSerial {
+    fn space(&mut self) -> usize {
+        self.tx_capacity.saturating_sub(self.tx.len())
+    }
+
+    fn write(&mut self, byte: u8) {
+        assert!(self.tx.len() < self.tx_capacity, "wrote without space");
+        self.tx.push(byte);
+    }
+}
+
+/// An owned copy of `UpstreamMessage` that is easy to compare
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upstream {
+    Hello(u16),
+    Log,
+    Ack(u16),
+    BadO,
+    BadP,
+    Telemetry(Telemetry),
+    Calibration(u8, MotorCalibration),
+    Panic(String, u32, u32),
+    Pong,
+}
+
+impl From<UpstreamMessage<'_>> for Upstream {
+    fn from(message: UpstreamMessage) -> Self {
+        match message {
+            UpstreamMessage::Hello(hello) => Upstream::Hello(hello.protocol_version),
+            UpstreamMessage::Log(_) => Upstream::Log,
+            UpstreamMessage::Ack(seq) => Upstream::Ack(seq),
+            UpstreamMessage::BadO => Upstream::BadO,
+            UpstreamMessage::BadP(_) => Upstream::BadP,
+            UpstreamMessage::Telemetry(telemetry) => Upstream::Telemetry(telemetry),
+            UpstreamMessage::Calibration(motor, calibration) => Upstream::Calibration(motor, calibration),
+            UpstreamMessage::Panic(report) => Upstream::Panic(report.file.into(), report.line, report.column),
+            UpstreamMessage::Pong => Upstream::Pong,
+        }
+    }
+}
+
+/// Returns a fixed value for every channel
+pub struct MockAdc(pub [u16; 7]);
+
+impl Adc for MockAdc {
+    fn read(&mut self, channel: u8) -> u16 {
+        self.0[channel as usize]
+    }
+}
+
+/// Holds the time in milliseconds
+pub struct MockClock(pub u32);
+
+impl Clock for MockClock {
+    fn millis(&mut self) -> u32 {
+        self.0
+    }
+
+    fn micros(&mut self) -> u32 {
+        self.0.wrapping_mul(1000)
+    }
+}
+
+/// A pin that is high while the inner value is true
+pub struct MockPin(pub bool);
+
+impl InputPin for MockPin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.0)
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(!self.0)
+    }
+}
+
+impl OutputPin for MockPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0 = false;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0 = true;
+        Ok(())
+    }
+}
+
+/// Erased eeprom that completes writes immediately
+pub struct MockStorage {
+    pub bytes: Vec<u8>,
+    pub writes: usize,
+}
+
+impl Default for MockStorage {
+    fn default() -> Self {
+        MockStorage {
+            bytes: std::vec![0xFF; 4096],
+            writes: 0
+        }
+    }
+}
+
+impl Storage for MockStorage {
+    fn read(&mut self, address: u16) -> u8 {
+        self.bytes[address as usize]
+    }
+
+    fn ready(&mut self) -> bool {
+        true
+    }
+
+    fn write(&mut self, address: u16, byte: u8) {
+        self.bytes[address as usize] = byte;
+        self.writes += 1;
+    }
+}
+
+/// The last output of every actuator, `None` until it is first driven
+#[derive(Default)]
+pub struct MockActuators(pub [Option<Output>; ACTUATOR_COUNT]);
+
+impl Actuators for MockActuators {
+    fn set(&mut self, actuator: Actuator, output: Output) {
+        self.0[actuator.index()] = Some(output);
+    }
+}
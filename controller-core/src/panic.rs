@@ -0,0 +1,106 @@
+use common::controller::{PanicReport, ResetCause};
+
+/// Marks a record that was written by the panic handler rather than left over in uninitialized ram
+const MAGIC: u32 = 0x5041_4E43;
+/// How much of the file path is kept
+pub const FILE_LEN: usize = 48;
+
+/// Where the firmware panicked, kept in ram that survives a reset so it can be reported afterwards
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct PanicRecord {
+    magic: u32,
+    line: u32,
+    column: u32,
+    file_len: u8,
+    file: [u8; FILE_LEN],
+}
+
+impl PanicRecord {
+    /// A record that doesn't hold a panic
+    pub const fn empty() -> Self {
+        PanicRecord {
+            magic: 0,
+            line: 0,
+            column: 0,
+            file_len: 0,
+            file: [0; FILE_LEN]
+        }
+    }
+
+    /// Record a panic location, long paths keep their end as it is the most specific part
+    pub fn new(file: &str, line: u32, column: u32) -> Self {
+        let mut start = file.len().saturating_sub(FILE_LEN);
+        while !file.is_char_boundary(start) {
+            start += 1;
+        }
+        let file = &file.as_bytes()[start..];
+
+        let mut record = PanicRecord {
+            magic: MAGIC,
+            line,
+            column,
+            file_len: file.len() as u8,
+            ..PanicRecord::empty()
+        };
+        record.file[..file.len()].copy_from_slice(file);
+
+        record
+    }
+
+    /// The recorded panic, `None` if the record is empty or corrupt
+    pub fn report(&self) -> Option<PanicReport<'_>> {
+        if self.magic != MAGIC || self.file_len as usize > FILE_LEN {
+            return None;
+        }
+
+        let file = core::str::from_utf8(&self.file[..self.file_len as usize]).ok()?;
+        Some(PanicReport {
+            file,
+            line: self.line,
+            column: self.column
+        })
+    }
+
+    /// Why the firmware restarted, given the MCUSR flags read on boot
+    ///
+    /// The bootloader usually clears MCUSR before starting the firmware,
+    /// but the panic handler always resets through the watchdog so a valid record implies one.
+    pub fn reset_cause(&self, mcusr: u8) -> ResetCause {
+        if self.report().is_some() {
+            ResetCause(mcusr | ResetCause::WATCHDOG)
+        } else {
+            ResetCause(mcusr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let record = PanicRecord::new("src/main.rs", 12, 5);
+        assert_eq!(record.report(), Some(PanicReport { file: "src/main.rs", line: 12, column: 5 }));
+        assert_eq!(PanicRecord::empty().report(), None);
+    }
+
+    #[test]
+    fn long_path() {
+        let path = "/home/user/.cargo/git/checkouts/avr-hal/src/some/deeply/nested/module/é.rs";
+        let record = PanicRecord::new(path, 1, 1);
+        let report = record.report().unwrap();
+
+        assert!(report.file.len() <= FILE_LEN);
+        assert!(path.ends_with(report.file));
+    }
+
+    #[test]
+    fn panic_implies_watchdog_reset() {
+        let record = PanicRecord::new("src/main.rs", 12, 5);
+        assert_eq!(record.reset_cause(0), ResetCause(ResetCause::WATCHDOG));
+        assert_eq!(PanicRecord::empty().reset_cause(0), ResetCause(0));
+        assert_eq!(PanicRecord::empty().reset_cause(ResetCause::EXTERNAL), ResetCause(ResetCause::EXTERNAL));
+    }
+}
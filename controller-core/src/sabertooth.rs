@@ -0,0 +1,332 @@
+use common::CommunicationError;
+use common::calibration::MOTOR_COUNT;
+
+// Address, Command
+// Direction is part of the calibration, see `common::calibration`
+#[derive(Clone, Copy)]
+pub struct Motor(u8, u8);
+
+/// Address of the first sabertooth, the others count up from it
+const FIRST_ADDRESS: u8 = 128;
+
+// Each sabertooth drives two motors, and only eight addresses can be set with its dip switches
+const _: () = assert!(MOTOR_COUNT % 2 == 0 && MOTOR_COUNT / 2 <= 8);
+
+/// Addresses of every sabertooth on the bus
+pub const ADDRESSES: [u8; MOTOR_COUNT / 2] = {
+    let mut addresses = [0; MOTOR_COUNT / 2];
+    let mut i = 0;
+    while i < addresses.len() {
+        addresses[i] = FIRST_ADDRESS + i as u8;
+        i += 1;
+    }
+    addresses
+};
+
+/// The motor driven by each thruster output of the mixing matrix, also the order of `common::calibration::Calibration`
+///
+/// Motor 2 then motor 1 of each sabertooth, on our robot that is left, right, strafing and vertical.
+pub const MOTORS: [Motor; MOTOR_COUNT] = {
+    let mut motors = [Motor(0, 0); MOTOR_COUNT];
+    let mut i = 0;
+    while i < motors.len() {
+        motors[i] = Motor(ADDRESSES[i / 2], if i % 2 == 0 { 4 } else { 0 });
+        i += 1;
+    }
+    motors
+};
+
+/// Shortest time between speed updates (ms), a full update takes about 4ms at 38400 baud
+pub const SPEED_INTERVAL: u32 = 10;
+/// Longest time between speed updates (ms), well within the serial timeout of `DEFAULT_PROFILE`
+pub const SPEED_REFRESH: u32 = 100;
+
+// Packetized serial command numbers
+const CMD_MIN_VOLTAGE: u8 = 2;
+const CMD_MAX_VOLTAGE: u8 = 3;
+const CMD_DRIVE_FORWARDS: u8 = 8;
+const CMD_DRIVE_BACKWARDS: u8 = 9;
+const CMD_TURN_RIGHT: u8 = 10;
+const CMD_TURN_LEFT: u8 = 11;
+const CMD_SERIAL_TIMEOUT: u8 = 14;
+const CMD_BAUD_RATE: u8 = 15;
+const CMD_RAMPING: u8 = 16;
+const CMD_DEADBAND: u8 = 17;
+
+/// Largest value a data byte can hold
+const MAX_DATA: u8 = 127;
+/// Slowest ramp the sabertooth supports
+const MAX_RAMPING: u8 = 80;
+/// Lowest voltage cutoff the sabertooth supports
+const MIN_VOLTAGE: f32 = 6.0;
+
+/// Baud rates the sabertooth can be switched to, the setting is stored by the sabertooth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaudRate {
+    B2400 = 1,
+    B9600 = 2,
+    B19200 = 3,
+    B38400 = 4,
+    /// Only supported by the 2x32
+    B115200 = 5,
+}
+
+/// A setting that is applied to a whole sabertooth
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Setting {
+    /// Voltage below which the sabertooth stops driving, at least 6 volts
+    MinVoltage(f32),
+    /// Voltage the sabertooth holds the battery below while regen breaking
+    MaxVoltage(f32),
+    /// Stop the motors when no packet arrives for this long (ms), rounded up to 100ms, 0 disables the timeout
+    SerialTimeout(u16),
+    /// 0 disables ramping, 1-10 are fast ramps and 11-80 are slow ramps
+    Ramping(u8),
+    /// Commands this close to stop are treated as stop, 0 restores the default
+    Deadband(u8),
+}
+
+/// The settings applied to every sabertooth on startup
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Profile {
+    pub min_voltage: f32,
+    pub max_voltage: f32,
+    pub serial_timeout: u16,
+    pub ramping: u8,
+    pub deadband: u8,
+}
+
+/// Stops the thrusters if the control loop hangs, the firmware slew limits on its own so ramping is disabled
+pub const DEFAULT_PROFILE: Profile = Profile {
+    min_voltage: 6.0,
+    max_voltage: 16.0,
+    serial_timeout: 500,
+    ramping: 0,
+    deadband: 0,
+};
+
+impl Profile {
+    /// The settings that make up this profile, in the order they should be sent
+    pub fn settings(&self) -> [Setting; 5] {
+        [
+            Setting::MinVoltage(self.min_voltage),
+            Setting::MaxVoltage(self.max_voltage),
+            Setting::SerialTimeout(self.serial_timeout),
+            Setting::Ramping(self.ramping),
+            Setting::Deadband(self.deadband),
+        ]
+    }
+}
+
+/// Writes the auto bauding char
+pub fn write_init(buffer: &mut [u8]) -> Result<&mut [u8], CommunicationError> {
+    let mut buffer = Buffer::new(buffer);
+    buffer.write_byte(0xAA)?;
+    Ok(buffer.into_buffer())
+}
+
+/// Updates the speed of a motor
+pub fn write_speed(buffer: &mut [u8], motor: Motor, speed: i8) -> Result<&mut [u8], CommunicationError> {
+    write_signed(buffer, motor.0, motor.1, motor.1 + 1, speed)
+}
+
+/// Drives both motors of a sabertooth in mixed mode, positive is forwards
+/// Mixed mode only starts once both a drive and a turn command have been received
+pub fn write_drive(buffer: &mut [u8], address: u8, speed: i8) -> Result<&mut [u8], CommunicationError> {
+    write_signed(buffer, address, CMD_DRIVE_FORWARDS, CMD_DRIVE_BACKWARDS, speed)
+}
+
+/// Turns using both motors of a sabertooth in mixed mode, positive is right
+pub fn write_turn(buffer: &mut [u8], address: u8, speed: i8) -> Result<&mut [u8], CommunicationError> {
+    write_signed(buffer, address, CMD_TURN_RIGHT, CMD_TURN_LEFT, speed)
+}
+
+/// Applies a setting to a sabertooth
+pub fn write_setting(buffer: &mut [u8], address: u8, setting: Setting) -> Result<&mut [u8], CommunicationError> {
+    match setting {
+        Setting::MinVoltage(voltage) => write_min_voltage(buffer, address, voltage),
+        Setting::MaxVoltage(voltage) => write_max_voltage(buffer, address, voltage),
+        Setting::SerialTimeout(timeout) => write_serial_timeout(buffer, address, timeout),
+        Setting::Ramping(ramping) => write_ramping(buffer, address, ramping),
+        Setting::Deadband(deadband) => write_deadband(buffer, address, deadband),
+    }
+}
+
+/// Sets the voltage at which the sabertooth will power off, anything below 6 volts is raised to it
+pub fn write_min_voltage(buffer: &mut [u8], address: u8, voltage: f32) -> Result<&mut [u8], CommunicationError> {
+    let voltage = voltage.max(MIN_VOLTAGE);
+    write_command(buffer, address, CMD_MIN_VOLTAGE, (((voltage - MIN_VOLTAGE) * 5.0) as u8).min(MAX_DATA))
+}
+
+/// Sets the max voltage the sabertooth will produce during regen breaking
+pub fn write_max_voltage(buffer: &mut [u8], address: u8, voltage: f32) -> Result<&mut [u8], CommunicationError> {
+    write_command(buffer, address, CMD_MAX_VOLTAGE, (voltage * 5.12) as u8)
+}
+
+/// Sets how long the sabertooth waits for a packet before stopping the motors (ms)
+pub fn write_serial_timeout(buffer: &mut [u8], address: u8, timeout: u16) -> Result<&mut [u8], CommunicationError> {
+    // The sabertooth counts in 100ms steps, round up so the timeout is never shorter than requested
+    let steps = timeout.div_ceil(100);
+    write_command(buffer, address, CMD_SERIAL_TIMEOUT, steps.min(MAX_DATA as u16) as u8)
+}
+
+/// Changes the baud rate of the sabertooth, it will stop responding at the old baud rate immediately
+pub fn write_baud_rate(buffer: &mut [u8], address: u8, baud_rate: BaudRate) -> Result<&mut [u8], CommunicationError> {
+    write_command(buffer, address, CMD_BAUD_RATE, baud_rate as u8)
+}
+
+/// Sets how quickly the sabertooth ramps to a new speed, anything above 80 is the slowest ramp
+pub fn write_ramping(buffer: &mut [u8], address: u8, ramping: u8) -> Result<&mut [u8], CommunicationError> {
+    write_command(buffer, address, CMD_RAMPING, ramping.min(MAX_RAMPING))
+}
+
+/// Sets how close to stop a command must be to be treated as stop
+pub fn write_deadband(buffer: &mut [u8], address: u8, deadband: u8) -> Result<&mut [u8], CommunicationError> {
+    write_command(buffer, address, CMD_DEADBAND, deadband.min(MAX_DATA))
+}
+
+/// Writes a command that has separate command numbers for each direction
+fn write_signed(buffer: &mut [u8], address: u8, positive: u8, negative: u8, value: i8) -> Result<&mut [u8], CommunicationError> {
+    if value >= 0 {
+        write_command(buffer, address, positive, value as u8)
+    } else {
+        write_command(buffer, address, negative, value.saturating_neg() as u8)
+    }
+}
+
+/// Writes a complete packet
+fn write_command(buffer: &mut [u8], address: u8, command: u8, data: u8) -> Result<&mut [u8], CommunicationError> {
+    let mut buffer = Buffer::new(buffer);
+    buffer.write_byte(address)?;
+    buffer.write_byte(command)?;
+    buffer.write_byte(data.min(MAX_DATA))?;
+
+    buffer.write_checksum()
+}
+
+
+/// Simple buffer system to communicate with the sabertooth
+struct Buffer<'a> {
+    buffer: &'a mut [u8],
+    index: usize,
+    sum: u8
+}
+
+impl<'a> Buffer<'a> {
+    fn new(buffer: &'a mut [u8]) -> Self {
+        Buffer {
+            buffer,
+            index: 0,
+            sum: 0
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), CommunicationError> {
+        if self.index >= self.buffer.len() {
+            return Err(CommunicationError::BufferFull);
+        }
+
+        self.buffer[self.index] = byte;
+        self.index += 1;
+
+        self.sum = self.sum.wrapping_add(byte);
+
+        Ok(())
+    }
+
+    fn write_checksum(mut self) -> Result<&'a mut [u8], CommunicationError> {
+        self.write_byte(self.sum & 0b01111111)?;
+        Ok(self.into_buffer())
+    }
+
+    fn into_buffer(self) -> &'a mut [u8] {
+        &mut self.buffer[..self.index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(producer: impl Fn(&mut [u8]) -> Result<&mut [u8], CommunicationError>) -> [u8; 4] {
+        let mut buffer = [0; 16];
+        producer(&mut buffer).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn checksum() {
+        // Example packet from the sabertooth manual
+        assert_eq!(encode(|buffer| write_command(buffer, 128, 0, 127)), [128, 0, 127, 127]);
+        // The checksum only keeps the lower 7 bits of the sum
+        assert_eq!(encode(|buffer| write_command(buffer, 129, 17, 100)), [129, 17, 100, (129 + 17 + 100) & 0x7F]);
+    }
+
+    #[test]
+    fn init() {
+        let mut buffer = [0; 16];
+        assert_eq!(write_init(&mut buffer).unwrap(), [0xAA]);
+    }
+
+    #[test]
+    fn speed() {
+        assert_eq!(encode(|buffer| write_speed(buffer, MOTORS[1], 64)), [128, 0, 64, 64]);
+        assert_eq!(encode(|buffer| write_speed(buffer, MOTORS[1], -64)), [128, 1, 64, 65]);
+        assert_eq!(encode(|buffer| write_speed(buffer, MOTORS[0], 64)), [128, 4, 64, 68]);
+        assert_eq!(encode(|buffer| write_speed(buffer, MOTORS[0], -64)), [128, 5, 64, 69]);
+        assert_eq!(encode(|buffer| write_speed(buffer, MOTORS[1], i8::MIN)), [128, 1, 127, 0]);
+    }
+
+    #[test]
+    fn motor_layout() {
+        assert_eq!(ADDRESSES, [128, 129]);
+        let motors = MOTORS.map(|Motor(address, command)| (address, command));
+        assert_eq!(motors, [(128, 4), (128, 0), (129, 4), (129, 0)]);
+    }
+
+    #[test]
+    fn mixed() {
+        assert_eq!(encode(|buffer| write_drive(buffer, 128, 10)), [128, 8, 10, 18]);
+        assert_eq!(encode(|buffer| write_drive(buffer, 128, -10)), [128, 9, 10, 19]);
+        assert_eq!(encode(|buffer| write_turn(buffer, 129, 10)), [129, 10, 10, 21]);
+        assert_eq!(encode(|buffer| write_turn(buffer, 129, -10)), [129, 11, 10, 22]);
+    }
+
+    #[test]
+    fn voltages() {
+        assert_eq!(encode(|buffer| write_min_voltage(buffer, 128, 12.0)), [128, 2, 30, 32]);
+        assert_eq!(encode(|buffer| write_max_voltage(buffer, 128, 16.0)), [128, 3, 81, 84]);
+        // Out of range values are clamped to fit in a data byte
+        assert_eq!(encode(|buffer| write_min_voltage(buffer, 128, 3.0)), [128, 2, 0, 2]);
+        assert_eq!(encode(|buffer| write_min_voltage(buffer, 128, 100.0)), [128, 2, 127, 1]);
+        assert_eq!(encode(|buffer| write_max_voltage(buffer, 128, 30.0)), [128, 3, 127, 2]);
+    }
+
+    #[test]
+    fn serial_timeout() {
+        assert_eq!(encode(|buffer| write_serial_timeout(buffer, 128, 0)), [128, 14, 0, 14]);
+        assert_eq!(encode(|buffer| write_serial_timeout(buffer, 128, 500)), [128, 14, 5, 19]);
+        assert_eq!(encode(|buffer| write_serial_timeout(buffer, 128, 501)), [128, 14, 6, 20]);
+        assert_eq!(encode(|buffer| write_serial_timeout(buffer, 128, u16::MAX)), [128, 14, 127, 13]);
+    }
+
+    #[test]
+    fn configuration() {
+        assert_eq!(encode(|buffer| write_baud_rate(buffer, 128, BaudRate::B38400)), [128, 15, 4, 19]);
+        assert_eq!(encode(|buffer| write_ramping(buffer, 128, 20)), [128, 16, 20, 36]);
+        assert_eq!(encode(|buffer| write_ramping(buffer, 128, 200)), [128, 16, 80, 96]);
+        assert_eq!(encode(|buffer| write_deadband(buffer, 128, 3)), [128, 17, 3, 20]);
+        assert_eq!(encode(|buffer| write_deadband(buffer, 128, 200)), [128, 17, 127, 16]);
+    }
+
+    #[test]
+    fn profile() {
+        let settings = DEFAULT_PROFILE.settings();
+        assert_eq!(encode(|buffer| write_setting(buffer, ADDRESSES[1], settings[2])), [129, 14, 5, 20]);
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buffer = [0; 3];
+        assert!(matches!(write_drive(&mut buffer, 128, 1), Err(CommunicationError::BufferFull)));
+    }
+}
@@ -0,0 +1,109 @@
+//! Leak and power monitoring
+
+use common::sensors::{Alarms, SensorData, SensorThresholds};
+use crate::hal::Adc;
+
+// Adc channel of each sensor
+pub const BATTERY_VOLTAGE: u8 = 4;
+pub const TETHER_VOLTAGE: u8 = 5;
+pub const LEAK: u8 = 6;
+
+/// Voltage the adc reads as 1023 (V)
+const ADC_REFERENCE: f32 = 5.0;
+/// Ratio of the 100k/10k dividers in front of the voltage inputs
+const VOLTAGE_DIVIDER: f32 = 11.0;
+/// The leak probe pulls its input above this while wet
+const LEAK_THRESHOLD: u16 = 512;
+
+/// How long a condition must hold before its alarm is raised (ms), rides out dips while the thrusters spin up
+pub const ALARM_DEBOUNCE: u32 = 500;
+
+/// Read the leak probe and the supply voltages
+pub fn read(adc: &mut impl Adc) -> SensorData {
+    let volts = |counts: u16| counts as f32 / 1023.0 * ADC_REFERENCE * VOLTAGE_DIVIDER;
+
+    SensorData {
+        battery_voltage: volts(adc.read(BATTERY_VOLTAGE)),
+        tether_voltage: volts(adc.read(TETHER_VOLTAGE)),
+        leak: adc.read(LEAK) > LEAK_THRESHOLD,
+    }
+}
+
+/// Raises alarms once a reading has crossed its threshold for `ALARM_DEBOUNCE`
+#[derive(Default)]
+pub struct AlarmMonitor {
+    // Time each condition started holding, in the order of `conditions`
+    since: [Option<u32>; 3],
+    alarms: Alarms,
+}
+
+impl AlarmMonitor {
+    /// Check new readings, returns the alarms that were raised by them
+    pub fn update(&mut self, data: &SensorData, thresholds: &SensorThresholds, now: u32) -> Alarms {
+        let below = |voltage: f32, min: f32| min > 0.0 && voltage < min;
+        let conditions = [
+            (Alarms::LEAK, data.leak),
+            (Alarms::LOW_BATTERY, below(data.battery_voltage, thresholds.min_battery_voltage)),
+            (Alarms::LOW_TETHER, below(data.tether_voltage, thresholds.min_tether_voltage)),
+        ];
+
+        let mut alarms = 0;
+        for ((flag, holds), since) in conditions.into_iter().zip(self.since.iter_mut()) {
+            if holds {
+                let start = *since.get_or_insert(now);
+                if now.wrapping_sub(start) >= ALARM_DEBOUNCE {
+                    alarms |= flag;
+                }
+            } else {
+                *since = None;
+            }
+        }
+
+        let raised = alarms & !self.alarms.0;
+        self.alarms = Alarms(alarms);
+        Alarms(raised)
+    }
+
+    /// The alarms currently raised
+    pub fn alarms(&self) -> Alarms {
+        self.alarms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::sensors::DEFAULT_THRESHOLDS;
+    use super::*;
+
+    const HEALTHY: SensorData = SensorData { battery_voltage: 12.0, tether_voltage: 12.0, leak: false };
+
+    #[test]
+    fn debounced() {
+        let mut monitor = AlarmMonitor::default();
+        let low = SensorData { battery_voltage: 9.0, ..HEALTHY };
+
+        assert!(monitor.update(&low, &DEFAULT_THRESHOLDS, 0).is_empty());
+        // A short dip is ignored
+        assert!(monitor.update(&HEALTHY, &DEFAULT_THRESHOLDS, ALARM_DEBOUNCE - 1).is_empty());
+        assert!(monitor.update(&low, &DEFAULT_THRESHOLDS, ALARM_DEBOUNCE).is_empty());
+
+        // Raised once
+        assert_eq!(monitor.update(&low, &DEFAULT_THRESHOLDS, 2 * ALARM_DEBOUNCE), Alarms(Alarms::LOW_BATTERY));
+        assert!(monitor.update(&low, &DEFAULT_THRESHOLDS, 3 * ALARM_DEBOUNCE).is_empty());
+        assert_eq!(monitor.alarms(), Alarms(Alarms::LOW_BATTERY));
+
+        // Cleared as soon as the voltage recovers
+        monitor.update(&HEALTHY, &DEFAULT_THRESHOLDS, 3 * ALARM_DEBOUNCE);
+        assert!(monitor.alarms().is_empty());
+    }
+
+    #[test]
+    fn disabled_thresholds() {
+        let mut monitor = AlarmMonitor::default();
+        let thresholds = SensorThresholds { min_battery_voltage: 0.0, min_tether_voltage: 0.0, ..DEFAULT_THRESHOLDS };
+        let data = SensorData { battery_voltage: 0.0, tether_voltage: 0.0, leak: true };
+
+        monitor.update(&data, &thresholds, 0);
+        assert_eq!(monitor.update(&data, &thresholds, ALARM_DEBOUNCE), Alarms(Alarms::LEAK));
+    }
+}
@@ -0,0 +1,145 @@
+use common::controller::VelocityData;
+use common::mixing::AXES;
+
+/// How quickly each axis can change by default, in full scale per second
+pub const DEFAULT_SLEW_RATE: f32 = 2.0;
+
+/// Limits how quickly each axis of the velocity setpoint can change
+/// Starts at zero so the motors always spin up gradually
+pub struct SlewLimiter {
+    // Full scale per second, zero disables limiting for that axis
+    rates: [f32; AXES],
+    current: [f32; AXES],
+    last_update: Option<u32>,
+}
+
+impl Default for SlewLimiter {
+    fn default() -> Self {
+        SlewLimiter {
+            rates: [DEFAULT_SLEW_RATE; AXES],
+            current: [0.0; AXES],
+            last_update: None
+        }
+    }
+}
+
+impl SlewLimiter {
+    /// Set the maximum rate of change of every axis
+    ///
+    /// Rates that aren't positive or are infinite disable limiting for their axis, NaN leaves the axis unchanged.
+    pub fn set_rates(&mut self, rates: [f32; AXES]) {
+        for (rate, new) in self.rates.iter_mut().zip(rates) {
+            if new.is_nan() {
+                continue;
+            }
+            *rate = if new.is_finite() && new > 0.0 { new } else { 0.0 };
+        }
+    }
+
+    /// Step towards the target as far as the rate limits allow
+    pub fn limit(&mut self, target: &VelocityData, now: u32) -> VelocityData {
+        let elapsed = match self.last_update {
+            Some(last_update) => now.wrapping_sub(last_update) as f32 / 1000.0,
+            None => 0.0
+        };
+        self.last_update = Some(now);
+
+        for ((current, target), rate) in self.current.iter_mut().zip(target.to_axes()).zip(self.rates) {
+            let max_step = rate * elapsed;
+            // A huge rate can still overflow, clamping to infinite bounds would be pointless
+            if rate > 0.0 && max_step.is_finite() {
+                *current += (target - *current).clamp(-max_step, max_step);
+            } else {
+                *current = target;
+            }
+        }
+
+        VelocityData::from_axes(self.current)
+    }
+
+    /// Drop the output to zero immediately, it will ramp back up from there
+    pub fn reset(&mut self) {
+        self.current = [0.0; AXES];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn velocity(surge: f32, heave: f32) -> VelocityData {
+        VelocityData { surge, heave, ..Default::default() }
+    }
+
+    fn assert_close(actual: VelocityData, expected: VelocityData) {
+        for (actual, expected) in actual.to_axes().into_iter().zip(expected.to_axes()) {
+            assert!((actual - expected).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    fn limiter(rates: [f32; AXES]) -> SlewLimiter {
+        let mut limiter = SlewLimiter::default();
+        limiter.set_rates(rates);
+        limiter
+    }
+
+    #[test]
+    fn ramps_each_axis_at_its_rate() {
+        let mut limiter = limiter([1.0, 2.0, 4.0, 2.0, 2.0, 2.0]);
+        let target = velocity(1.0, -1.0);
+
+        assert_close(limiter.limit(&target, 1000), velocity(0.0, 0.0));
+        assert_close(limiter.limit(&target, 1100), velocity(0.1, -0.4));
+        assert_close(limiter.limit(&target, 1200), velocity(0.2, -0.8));
+        assert_close(limiter.limit(&target, 1300), velocity(0.3, -1.0));
+
+        // Ramping back down is limited too
+        assert_close(limiter.limit(&velocity(0.0, 0.0), 1400), velocity(0.2, -0.6));
+    }
+
+    #[test]
+    fn survives_the_timer_wrapping() {
+        let mut limiter = limiter([1.0; AXES]);
+        let target = velocity(1.0, 0.0);
+
+        limiter.limit(&target, u32::MAX - 99);
+        assert_close(limiter.limit(&target, 100), velocity(0.2, 0.0));
+    }
+
+    #[test]
+    fn reset_drops_to_zero() {
+        let mut limiter = limiter([1.0; AXES]);
+        let target = velocity(1.0, 0.0);
+        limiter.limit(&target, 0);
+        limiter.limit(&target, 500);
+
+        limiter.reset();
+        assert_close(limiter.limit(&velocity(0.0, 0.0), 500), velocity(0.0, 0.0));
+        assert_close(limiter.limit(&target, 600), velocity(0.1, 0.0));
+    }
+
+    #[test]
+    fn unlimited_rates() {
+        let target = velocity(1.0, -1.0);
+
+        for rate in [0.0, -1.0, f32::INFINITY, f32::NEG_INFINITY] {
+            let mut limiter = limiter([rate; AXES]);
+            // The first step and steps in the same millisecond have no elapsed time
+            assert_close(limiter.limit(&target, 0), target.clone());
+            assert_close(limiter.limit(&target, 0), target.clone());
+        }
+
+        // A finite rate that overflows over a long step
+        let mut limiter = limiter([f32::MAX; AXES]);
+        limiter.limit(&velocity(0.0, 0.0), 0);
+        assert_close(limiter.limit(&target, 10_000), target.clone());
+    }
+
+    #[test]
+    fn nan_rates_are_ignored() {
+        let mut limiter = limiter([1.0, f32::NAN, 0.0, 1.0, 1.0, 1.0]);
+        limiter.set_rates([f32::NAN; AXES]);
+
+        assert_eq!(limiter.rates, [1.0, DEFAULT_SLEW_RATE, 0.0, 1.0, 1.0, 1.0]);
+    }
+}
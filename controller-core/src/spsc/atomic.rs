@@ -8,6 +8,9 @@ pub struct AtomicUsize {
     inner: core::cell::UnsafeCell<usize>,
 }
 
+// Every access to the inner value through a shared reference happens inside a critical section
+unsafe impl Sync for AtomicUsize {}
+
 impl From<usize> for AtomicUsize {
     #[inline]
     fn from(v: usize) -> Self {
@@ -170,7 +173,110 @@ fn store_ordering(order: Ordering) -> Ordering {
     }
 }
 
+/// Run `f` with interrupts disabled, restoring the interrupt flag afterwards
+#[cfg(target_arch = "avr")]
+#[allow(unused)]
+fn critical_section<R>(f: impl FnOnce() -> R) -> R {
+    let sreg: u8;
+    // This is safe because the interrupt flag is restored before returning
+    unsafe { core::arch::asm!("in {sreg}, 0x3F", "cli", sreg = out(reg) sreg) };
+    let result = f();
+    if sreg & 0x80 != 0 {
+        unsafe { core::arch::asm!("sei") };
+    }
+    result
+}
+
+/// Run `f` while holding a global lock, stands in for disabling interrupts when testing on the host
+#[cfg(all(not(target_arch = "avr"), not(loom)))]
+#[allow(unused)]
+fn critical_section<R>(f: impl FnOnce() -> R) -> R {
+    use core::sync::atomic::AtomicBool;
+
+    static LOCK: AtomicBool = AtomicBool::new(false);
+
+    while LOCK.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        core::hint::spin_loop();
+    }
+    let result = f();
+    LOCK.store(false, Ordering::Release);
+    result
+}
+
+/// Run `f` while holding a global lock that loom can schedule around
+#[cfg(all(not(target_arch = "avr"), loom))]
 #[allow(unused)]
 fn critical_section<R>(f: impl FnOnce() -> R) -> R {
-    avr_device::interrupt::free(move |_| f())
-}
\ No newline at end of file
+    extern crate std;
+    use loom::sync::atomic::AtomicBool;
+
+    loom::lazy_static! {
+        static ref LOCK: AtomicBool = AtomicBool::new(false);
+    }
+
+    while LOCK.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        loom::thread::yield_now();
+    }
+    let result = f();
+    LOCK.store(false, Ordering::Release);
+    result
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    const THREADS: usize = 4;
+    const ITERATIONS: usize = 10_000;
+
+    #[test]
+    fn fetch_add_is_atomic() {
+        let counter = AtomicUsize::new(0);
+
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..ITERATIONS {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+
+        assert_eq!(counter.load(Ordering::SeqCst), THREADS * ITERATIONS);
+    }
+
+    #[test]
+    fn compare_exchange_is_atomic() {
+        let counter = AtomicUsize::new(0);
+
+        std::thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..ITERATIONS {
+                        let mut current = counter.load(Ordering::SeqCst);
+                        while let Err(actual) = counter.compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                            current = actual;
+                        }
+                    }
+                });
+            }
+        });
+
+        assert_eq!(counter.into_inner(), THREADS * ITERATIONS);
+    }
+
+    #[test]
+    fn fetch_update() {
+        let value = AtomicUsize::new(5);
+
+        assert_eq!(value.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old| old.checked_sub(10)), Err(5));
+        assert_eq!(value.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old| Some(old * 2)), Ok(5));
+        assert_eq!(value.swap(1, Ordering::SeqCst), 10);
+        assert_eq!(value.fetch_max(3, Ordering::SeqCst), 1);
+        assert_eq!(value.fetch_min(2, Ordering::SeqCst), 3);
+        assert_eq!(value.load(Ordering::SeqCst), 2);
+    }
+}
@@ -13,7 +13,7 @@
 //! - `Queue` can be used as a plain queue
 //!
 //! ```
-//! use heapless::spsc::Queue;
+//! use controller_core::spsc::Queue;
 //!
 //! let mut rb: Queue<u8, 4> = Queue::new();
 //!
@@ -28,7 +28,7 @@
 //! - `Queue` can be `split` and then be used in Single Producer Single Consumer mode
 //!
 //! ```
-//! use heapless::spsc::Queue;
+//! use controller_core::spsc::Queue;
 //!
 //! // Notice, type signature needs to be explicit for now.
 //! // (min_const_eval, does not allow for default type assignments)
@@ -193,7 +193,7 @@ impl<T, const N: usize> Queue<T, N> {
     ///
     /// # Examples
     /// ```
-    /// use heapless::spsc::Queue;
+    /// use controller_core::spsc::Queue;
     ///
     /// let mut queue: Queue<u8, 235> = Queue::new();
     /// let (mut producer, mut consumer) = queue.split();
@@ -540,7 +540,7 @@ impl<'a, T, const N: usize> Consumer<'a, T, N> {
     ///
     /// # Examples
     /// ```
-    /// use heapless::spsc::Queue;
+    /// use controller_core::spsc::Queue;
     ///
     /// let mut queue: Queue<u8, 235> = Queue::new();
     /// let (mut producer, mut consumer) = queue.split();
@@ -591,8 +591,13 @@ impl<'a, T, const N: usize> Producer<'a, T, N> {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod tests {
+    extern crate std;
+
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+    use proptest::prelude::*;
     use crate::spsc::Queue;
     use hash32::Hasher;
 
@@ -637,7 +642,6 @@ mod tests {
 
         for _ in 0..1_000_000 {
             let v = rb.dequeue().unwrap();
-            println!("{}", v);
             rb.enqueue(v).unwrap();
             assert_eq!(rb.len(), 2);
         }
@@ -907,4 +911,167 @@ mod tests {
         };
         assert_eq!(hash1, hash2);
     }
-}
\ No newline at end of file
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Enqueue(u8),
+        Dequeue,
+        Peek,
+    }
+
+    fn ops() -> impl Strategy<Value = Vec<Op>> {
+        let op = prop_oneof![
+            any::<u8>().prop_map(Op::Enqueue),
+            Just(Op::Dequeue),
+            Just(Op::Peek),
+        ];
+        proptest::collection::vec(op, 0..200)
+    }
+
+    /// Run `ops` against a queue and a `VecDeque` and check they always agree
+    fn check_against_model<const N: usize>(ops: &[Op]) {
+        let mut rb: Queue<u8, N> = Queue::new();
+        let mut model = VecDeque::new();
+
+        for op in ops {
+            match *op {
+                Op::Enqueue(value) => {
+                    if model.len() < N - 1 {
+                        assert_eq!(rb.enqueue(value), Ok(()));
+                        model.push_back(value);
+                    } else {
+                        assert_eq!(rb.enqueue(value), Err(value));
+                    }
+                }
+                Op::Dequeue => assert_eq!(rb.dequeue(), model.pop_front()),
+                Op::Peek => assert_eq!(rb.peek(), model.front()),
+            }
+
+            assert_eq!(rb.len(), model.len());
+            assert_eq!(rb.is_empty(), model.is_empty());
+            assert_eq!(rb.is_full(), model.len() == N - 1);
+            assert!(rb.iter().eq(model.iter()));
+        }
+    }
+
+    /// Same as `check_against_model` through the split endpoints
+    fn check_split_against_model<const N: usize>(ops: &[Op]) {
+        let mut rb: Queue<u8, N> = Queue::new();
+        let (mut p, mut c) = rb.split();
+        let mut model = VecDeque::new();
+
+        for op in ops {
+            match *op {
+                Op::Enqueue(value) => {
+                    assert_eq!(p.ready(), model.len() < N - 1);
+                    if p.ready() {
+                        assert_eq!(p.enqueue(value), Ok(()));
+                        model.push_back(value);
+                    } else {
+                        assert_eq!(p.enqueue(value), Err(value));
+                    }
+                }
+                Op::Dequeue => {
+                    assert_eq!(c.ready(), !model.is_empty());
+                    assert_eq!(c.dequeue(), model.pop_front());
+                }
+                Op::Peek => assert_eq!(c.peek(), model.front()),
+            }
+
+            assert_eq!(p.len(), model.len());
+            assert_eq!(c.len(), model.len());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn matches_model(ops in ops()) {
+            // The smallest queue, a power of two and one that isn't
+            check_against_model::<2>(&ops);
+            check_against_model::<4>(&ops);
+            check_against_model::<7>(&ops);
+        }
+
+        #[test]
+        fn split_matches_model(ops in ops()) {
+            check_split_against_model::<2>(&ops);
+            check_split_against_model::<4>(&ops);
+            check_split_against_model::<7>(&ops);
+        }
+    }
+
+    #[test]
+    fn producer_and_consumer_threads() {
+        const COUNT: u32 = 100_000;
+
+        let mut rb: Queue<u32, 8> = Queue::new();
+        let (mut p, mut c) = rb.split();
+
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..COUNT {
+                    while p.enqueue(i).is_err() {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+
+            // Everything arrives once and in order
+            let mut next = 0;
+            while next < COUNT {
+                match c.dequeue() {
+                    Some(value) => {
+                        assert_eq!(value, next);
+                        next += 1;
+                    }
+                    None => std::thread::yield_now(),
+                }
+            }
+            assert_eq!(c.dequeue(), None);
+        });
+    }
+}
+
+// Run with `RUSTFLAGS="--cfg loom" cargo test --release --lib spsc`
+#[cfg(all(test, loom))]
+mod loom_tests {
+    extern crate std;
+
+    use std::boxed::Box;
+    use crate::spsc::Queue;
+
+    /// Interleavings of a producer filling the queue past its capacity and a consumer draining it
+    #[test]
+    fn producer_and_consumer() {
+        // Both sides spin while waiting on each other, bound the preemptions to keep the search finite
+        let mut builder = loom::model::Builder::new();
+        builder.preemption_bound = Some(3);
+
+        builder.check(|| {
+            let rb: &'static mut Queue<u8, 3> = Box::leak(Box::new(Queue::new()));
+            let (mut p, mut c) = rb.split();
+
+            let producer = loom::thread::spawn(move || {
+                for i in 0..3 {
+                    while p.enqueue(i).is_err() {
+                        loom::thread::yield_now();
+                    }
+                }
+            });
+
+            let mut next = 0;
+            while next < 3 {
+                match c.dequeue() {
+                    Some(value) => {
+                        assert_eq!(value, next);
+                        next += 1;
+                    }
+                    None => loom::thread::yield_now(),
+                }
+            }
+
+            producer.join().unwrap();
+            assert_eq!(c.dequeue(), None);
+        });
+    }
+}
@@ -0,0 +1,362 @@
+use common::actuator::{ActuatorData, SAFE_ACTUATORS};
+use common::sensors::{Alarms, SensorData, SensorThresholds};
+use common::calibration::{Calibration, MOTOR_COUNT};
+use common::controller::{ArbitrationMode, ControlSource, DownstreamMessage, EStopReason, EStopState, VelocityData};
+use common::mixing::{MixingMatrix, THRUSTERS};
+use crate::sensors::AlarmMonitor;
+use crate::slew::SlewLimiter;
+use crate::telemetry::{DEFAULT_TELEMETRY_INTERVAL, MIN_TELEMETRY_INTERVAL};
+
+/// How long the pc can stay silent before the failsafe starts ramping its setpoint down (ms)
+pub const DEFAULT_FAILSAFE_TIMEOUT: u32 = 500;
+/// How long the failsafe takes to ramp the pc setpoint from full to zero (ms)
+pub const FAILSAFE_RAMP: u32 = 500;
+/// How long a pc that was connected can stay silent before an emergency stop is latched (ms)
+pub const FAILSAFE_LATCH_TIMEOUT: u32 = 5000;
+
+/// A struct that keeps track of the robots current state
+pub struct State {
+    // Body frame setpoints
+    motor_sp_pc: VelocityData,
+    motor_sp_joystick: VelocityData,
+    arbitration: ArbitrationMode,
+    joystick_priority: bool,
+
+    mixing: MixingMatrix,
+    slew: SlewLimiter,
+    calibration: Calibration,
+
+    actuators: ActuatorData,
+
+    sensors: SensorData,
+    sensor_thresholds: SensorThresholds,
+    alarms: AlarmMonitor,
+
+    emergency_stop: EStopState,
+    button_pressed: bool,
+
+    do_ping: bool,
+    do_hello: bool,
+    // Bit n is set if the calibration of motor n needs to be reported
+    report_calibration: u8,
+    save_calibration: bool,
+
+    // Time of the last valid message from the pc
+    last_pc_message: u32,
+    pc_connected: bool,
+    failsafe_timeout: u32,
+
+    telemetry_interval: u32,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            motor_sp_pc: VelocityData::default(),
+            motor_sp_joystick: VelocityData::default(),
+            arbitration: ArbitrationMode::default(),
+            joystick_priority: false,
+            mixing: MixingMatrix::default(),
+            slew: SlewLimiter::default(),
+            calibration: Calibration::default(),
+            actuators: SAFE_ACTUATORS,
+            sensors: SensorData::default(),
+            sensor_thresholds: SensorThresholds::default(),
+            alarms: AlarmMonitor::default(),
+            emergency_stop: EStopState::Armed,
+            button_pressed: false,
+            do_ping: false,
+            do_hello: false,
+            report_calibration: 0,
+            save_calibration: false,
+            last_pc_message: 0,
+            pc_connected: false,
+            failsafe_timeout: DEFAULT_FAILSAFE_TIMEOUT,
+            telemetry_interval: DEFAULT_TELEMETRY_INTERVAL
+        }
+    }
+}
+
+impl State {
+    /// Update the state with info from the connected pc
+    pub fn update_pc(&mut self, message: DownstreamMessage, now: u32) {
+        // Don't resume with a setpoint from before the connection was lost
+        if self.failsafe(now) {
+            self.motor_sp_pc = VelocityData::default();
+            self.actuators = SAFE_ACTUATORS;
+        }
+        self.last_pc_message = now;
+        self.pc_connected = true;
+
+        match message {
+            DownstreamMessage::Hello(_) => {
+                self.do_hello = true;
+            }
+            DownstreamMessage::VelocityUpdate(velocity) => {
+                self.motor_sp_pc = velocity;
+            }
+            DownstreamMessage::EmergencyStop => {
+                self.latch_emergency_stop(EStopReason::Pc);
+            }
+            DownstreamMessage::Arm => {
+                // The button must be released before the robot can move again
+                if !self.button_pressed {
+                    self.emergency_stop = EStopState::Armed;
+                    self.motor_sp_pc = VelocityData::default();
+                    self.actuators = SAFE_ACTUATORS;
+                }
+            }
+            DownstreamMessage::Ping => {
+                self.do_ping = true;
+            }
+            DownstreamMessage::SetFailsafeTimeout(timeout) => {
+                self.failsafe_timeout = timeout as u32;
+            }
+            DownstreamMessage::SetMixing(thruster, weights) => {
+                // A non-finite weight would scale every output to zero or NaN
+                if (thruster as usize) < THRUSTERS && weights.iter().all(|weight| weight.is_finite()) {
+                    self.mixing.weights[thruster as usize] = weights;
+                }
+            }
+            DownstreamMessage::SetSlewRate(rates) => {
+                self.slew.set_rates(rates);
+            }
+            DownstreamMessage::GetCalibration => {
+                self.report_calibration = (1 << MOTOR_COUNT) - 1;
+            }
+            DownstreamMessage::SetCalibration(motor, calibration) => {
+                if (motor as usize) < MOTOR_COUNT {
+                    self.calibration.motors[motor as usize] = calibration;
+                    self.save_calibration = true;
+                    self.report_calibration |= 1 << motor;
+                }
+            }
+            DownstreamMessage::SetTelemetryInterval(interval) => {
+                self.telemetry_interval = (interval as u32).max(MIN_TELEMETRY_INTERVAL);
+            }
+            DownstreamMessage::SetArbitration(mode) => {
+                self.arbitration = mode;
+            }
+            DownstreamMessage::SetActuator(actuator, value) => {
+                self.actuators.set(actuator, value);
+            }
+            DownstreamMessage::SetSensorThresholds(thresholds) => {
+                self.sensor_thresholds = thresholds;
+            }
+        }
+    }
+
+    /// Update the velocity with info from the physical joysticks
+    pub fn update_joystick(&mut self, velocity: VelocityData) {
+        self.motor_sp_joystick = velocity;
+    }
+
+    /// Update the arbitration with info from the joystick priority pin
+    pub fn update_joystick_priority(&mut self, priority: bool) {
+        self.joystick_priority = priority;
+    }
+
+    /// Update the emergency stop state with info from the physical button
+    pub fn update_emergency_stop(&mut self, button_pressed: bool) {
+        self.button_pressed = button_pressed;
+
+        if button_pressed {
+            self.latch_emergency_stop(EStopReason::Button);
+        }
+    }
+
+    /// Update the alarms with new readings from the leak and power sensors
+    /// An alarm only latches an emergency stop when it is raised, so the robot can be armed again to bring it back
+    pub fn update_sensors(&mut self, sensors: SensorData, now: u32) {
+        self.sensors = sensors;
+
+        let raised = self.alarms.update(&self.sensors, &self.sensor_thresholds, now);
+        let estop = raised.0 & self.sensor_thresholds.estop_alarms;
+        if estop & Alarms::LEAK != 0 {
+            self.latch_emergency_stop(EStopReason::Leak);
+        } else if estop != 0 {
+            self.latch_emergency_stop(EStopReason::LowVoltage);
+        }
+    }
+
+    /// The latest readings of the leak and power sensors
+    pub fn sensors(&self) -> SensorData {
+        self.sensors
+    }
+
+    /// The alarms currently raised
+    pub fn alarms(&self) -> Alarms {
+        self.alarms.alarms()
+    }
+
+    /// Latch an emergency stop if the pc has been silent for too long
+    pub fn update_failsafe(&mut self, now: u32) {
+        // Only latch if we lost a pc, the onboard joysticks must keep working without one
+        if self.pc_connected && now.wrapping_sub(self.last_pc_message) > FAILSAFE_LATCH_TIMEOUT {
+            self.latch_emergency_stop(EStopReason::Failsafe);
+        }
+    }
+
+    /// Latch an emergency stop, the first reason is kept until the robot is armed again
+    fn latch_emergency_stop(&mut self, reason: EStopReason) {
+        if !self.emergency_stop.is_latched() {
+            self.emergency_stop = EStopState::Latched(reason);
+        }
+    }
+
+    /// Is an emergency stop active
+    pub fn emergency_stop(&self) -> bool {
+        self.emergency_stop.is_latched()
+    }
+
+    /// The emergency stop state and why it was latched
+    pub fn emergency_stop_state(&self) -> EStopState {
+        self.emergency_stop
+    }
+
+    /// Has the pc been silent for long enough that its setpoint is being ignored
+    pub fn failsafe(&self, now: u32) -> bool {
+        now.wrapping_sub(self.last_pc_message) > self.failsafe_timeout
+    }
+
+    /// Do we need to respond to a ping
+    pub fn do_ping(&self) -> bool {
+        self.do_ping
+    }
+
+    /// Clear ping status
+    pub fn clear_ping(&mut self) {
+        self.do_ping = false;
+    }
+
+    /// Do we need to identify ourselves to the pc
+    pub fn do_hello(&self) -> bool {
+        self.do_hello
+    }
+
+    /// Clear hello status
+    pub fn clear_hello(&mut self) {
+        self.do_hello = false;
+    }
+
+    /// Replace the calibration, used when it is loaded from storage
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    /// The calibration of every motor
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    /// Bit n is set if we need to report the calibration of motor n
+    pub fn report_calibration(&self) -> u8 {
+        self.report_calibration
+    }
+
+    /// Clear calibration report status
+    pub fn clear_report_calibration(&mut self) {
+        self.report_calibration = 0;
+    }
+
+    /// Has the calibration changed since it was last stored
+    pub fn save_calibration(&self) -> bool {
+        self.save_calibration
+    }
+
+    /// Clear calibration save status
+    pub fn clear_save_calibration(&mut self) {
+        self.save_calibration = false;
+    }
+
+    /// How much of the pc setpoint should still be applied, ramps from 1 to 0 once the failsafe engages
+    fn failsafe_scale(&self, now: u32) -> f32 {
+        let silent = now.wrapping_sub(self.last_pc_message);
+        if silent <= self.failsafe_timeout {
+            return 1.0;
+        }
+
+        let ramped = silent - self.failsafe_timeout;
+        if ramped >= FAILSAFE_RAMP {
+            0.0
+        } else {
+            1.0 - ramped as f32 / FAILSAFE_RAMP as f32
+        }
+    }
+
+    /// How often telemetry should be sent (ms)
+    pub fn telemetry_interval(&self) -> u32 {
+        self.telemetry_interval
+    }
+
+    /// The matrix used to map body frame setpoints onto thrusters
+    pub fn mixing(&self) -> &MixingMatrix {
+        &self.mixing
+    }
+
+    /// Compute the body frame velocity setpoint
+    pub fn compute_velocity(&self, now: u32) -> VelocityData {
+        if self.emergency_stop() {
+            return VelocityData::default();
+        }
+
+        match self.control_source() {
+            ControlSource::Pc => self.pc_setpoint(now),
+            ControlSource::Joystick => self.joystick_setpoint(),
+            ControlSource::Both => (self.pc_setpoint(now) + self.joystick_setpoint()).clamp(),
+        }
+    }
+
+    /// The arbitration mode in effect, the joystick priority pin overrides the pc's choice
+    pub fn arbitration(&self) -> ArbitrationMode {
+        if self.joystick_priority {
+            ArbitrationMode::JoystickOnly
+        } else {
+            self.arbitration
+        }
+    }
+
+    /// Whose setpoint is being used
+    pub fn control_source(&self) -> ControlSource {
+        match self.arbitration() {
+            ArbitrationMode::Sum => ControlSource::Both,
+            ArbitrationMode::PcOnly => ControlSource::Pc,
+            ArbitrationMode::JoystickOnly => ControlSource::Joystick,
+            ArbitrationMode::JoystickOverride => {
+                if self.joystick_setpoint().is_zero() {
+                    ControlSource::Pc
+                } else {
+                    ControlSource::Joystick
+                }
+            }
+        }
+    }
+
+    /// The setpoint from the pc after the failsafe ramp
+    pub fn pc_setpoint(&self, now: u32) -> VelocityData {
+        self.motor_sp_pc.clamp().scale(self.failsafe_scale(now))
+    }
+
+    /// The setpoint from the onboard joysticks
+    pub fn joystick_setpoint(&self) -> VelocityData {
+        self.motor_sp_joystick.clamp()
+    }
+
+    /// The value of every actuator, the safe values during an emergency stop or while the failsafe is engaged
+    pub fn actuators(&self, now: u32) -> ActuatorData {
+        if self.emergency_stop() || self.failsafe(now) {
+            SAFE_ACTUATORS
+        } else {
+            self.actuators
+        }
+    }
+
+    /// Limit how quickly the velocity setpoint can change, emergency stops bypass the limit
+    pub fn limit_velocity(&mut self, velocity: &VelocityData, now: u32) -> VelocityData {
+        if self.emergency_stop() {
+            self.slew.reset();
+        }
+
+        self.slew.limit(velocity, now)
+    }
+}
@@ -0,0 +1,67 @@
+use common::calibration::{Calibration, RECORD_SIZE};
+use crate::hal::Storage;
+
+/// Where the calibration record starts in storage
+pub const CALIBRATION_ADDRESS: u16 = 0;
+
+/// Load the stored calibration, falls back to the default if nothing valid was stored
+pub fn load_calibration(storage: &mut impl Storage) -> Calibration {
+    let mut record = [0; RECORD_SIZE];
+    for (address, byte) in (CALIBRATION_ADDRESS..).zip(record.iter_mut()) {
+        *byte = storage.read(address);
+    }
+
+    Calibration::decode(&record).unwrap_or_default()
+}
+
+/// Writes a record in the background, one byte per call to `poll`
+/// Eeprom writes take milliseconds each, so writing a whole record at once would trip the watchdog
+pub struct RecordWriter {
+    record: [u8; RECORD_SIZE],
+    len: usize,
+    // Index of the next byte to write, `None` when idle
+    next: Option<usize>,
+}
+
+impl Default for RecordWriter {
+    fn default() -> Self {
+        RecordWriter {
+            record: [0; RECORD_SIZE],
+            len: 0,
+            next: None
+        }
+    }
+}
+
+impl RecordWriter {
+    /// Start writing a calibration record, replaces any record that is still being written
+    pub fn start(&mut self, calibration: &Calibration) {
+        if let Ok(record) = calibration.encode(&mut self.record) {
+            self.len = record.len();
+            self.next = Some(0);
+        }
+    }
+
+    pub fn busy(&self) -> bool {
+        self.next.is_some()
+    }
+
+    /// Write the next byte if the storage is ready, bytes that are already stored are skipped
+    pub fn poll(&mut self, storage: &mut impl Storage) {
+        let index = match self.next {
+            Some(index) => index,
+            None => return
+        };
+
+        if !storage.ready() {
+            return;
+        }
+
+        let address = CALIBRATION_ADDRESS + index as u16;
+        if storage.read(address) != self.record[index] {
+            storage.write(address, self.record[index]);
+        }
+
+        self.next = if index + 1 < self.len { Some(index + 1) } else { None };
+    }
+}
@@ -0,0 +1,131 @@
+use common::controller::{EStopState, LoopStats};
+
+/// How often telemetry is sent by default (ms)
+pub const DEFAULT_TELEMETRY_INTERVAL: u32 = 50;
+/// Shortest interval the pc can ask for (ms), faster telemetry would crowd out acks on the usb link
+pub const MIN_TELEMETRY_INTERVAL: u32 = 10;
+
+/// Decides when to send telemetry and measures the loop time in between
+#[derive(Default)]
+pub struct TelemetryScheduler {
+    // Time the last telemetry was sent, `None` before the first one
+    last_sent: Option<u32>,
+
+    // Time the last loop iteration started (us), `None` before the first one
+    last_tick: Option<u32>,
+    // Loop iterations since the last telemetry and how long they took (us)
+    loops: u32,
+    total: u32,
+    min: u32,
+    max: u32,
+
+    // What the pc was last told
+    last_emergency_stop: EStopState,
+    last_failsafe: bool,
+}
+
+impl TelemetryScheduler {
+    /// Record the start of an iteration of the control loop, `now` is in microseconds
+    pub fn tick(&mut self, now: u32) {
+        if let Some(last_tick) = self.last_tick {
+            let duration = now.wrapping_sub(last_tick);
+            self.min = if self.loops == 0 { duration } else { self.min.min(duration) };
+            self.max = self.max.max(duration);
+            self.total = self.total.saturating_add(duration);
+            self.loops = self.loops.saturating_add(1);
+        }
+
+        self.last_tick = Some(now);
+    }
+
+    /// Should telemetry be sent now
+    pub fn due(&self, now: u32, interval: u32, emergency_stop: EStopState, failsafe: bool) -> bool {
+        match self.last_sent {
+            Some(last_sent) => {
+                now.wrapping_sub(last_sent) >= interval
+                    || emergency_stop != self.last_emergency_stop
+                    || failsafe != self.last_failsafe
+            }
+            None => true
+        }
+    }
+
+    /// Record that telemetry is being sent, returns the loop time since the previous telemetry
+    pub fn sent(&mut self, now: u32, emergency_stop: EStopState, failsafe: bool) -> LoopStats {
+        let loop_time = match self.total.checked_div(self.loops) {
+            Some(avg) => LoopStats {
+                min: self.min,
+                max: self.max,
+                avg
+            },
+            None => LoopStats::default()
+        };
+
+        self.last_sent = Some(now);
+        self.loops = 0;
+        self.total = 0;
+        self.min = 0;
+        self.max = 0;
+        self.last_emergency_stop = emergency_stop;
+        self.last_failsafe = failsafe;
+
+        loop_time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::controller::EStopReason;
+    use super::*;
+
+    #[test]
+    fn periodic() {
+        let mut scheduler = TelemetryScheduler::default();
+        let armed = EStopState::Armed;
+
+        assert!(scheduler.due(0, 50, armed, false));
+        assert_eq!(scheduler.sent(0, armed, false), LoopStats::default());
+
+        for now in 1..50 {
+            scheduler.tick(now * 1000);
+            assert!(!scheduler.due(now, 50, armed, false));
+        }
+        scheduler.tick(50 * 1000);
+        assert!(scheduler.due(50, 50, armed, false));
+        assert_eq!(scheduler.sent(50, armed, false), LoopStats { min: 1000, max: 1000, avg: 1000 });
+    }
+
+    #[test]
+    fn loop_stats() {
+        let mut scheduler = TelemetryScheduler::default();
+        let armed = EStopState::Armed;
+
+        for now in [0, 900, 2000, 2800] {
+            scheduler.tick(now);
+        }
+        assert_eq!(scheduler.sent(3, armed, false), LoopStats { min: 800, max: 1100, avg: 933 });
+
+        // Only the iterations since the previous telemetry count
+        scheduler.tick(3300);
+        assert_eq!(scheduler.sent(4, armed, false), LoopStats { min: 500, max: 500, avg: 500 });
+
+        // The microsecond timer wraps around
+        let mut scheduler = TelemetryScheduler::default();
+        scheduler.tick(u32::MAX - 99);
+        scheduler.tick(400);
+        assert_eq!(scheduler.sent(0, armed, false), LoopStats { min: 500, max: 500, avg: 500 });
+    }
+
+    #[test]
+    fn on_change() {
+        let mut scheduler = TelemetryScheduler::default();
+        scheduler.sent(0, EStopState::Armed, false);
+
+        let latched = EStopState::Latched(EStopReason::Button);
+        assert!(scheduler.due(1, 50, latched, false));
+        assert!(scheduler.due(1, 50, EStopState::Armed, true));
+
+        scheduler.sent(1, latched, false);
+        assert!(!scheduler.due(2, 50, latched, false));
+    }
+}
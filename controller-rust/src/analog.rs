@@ -0,0 +1,29 @@
+use arduino_hal::Adc;
+use arduino_hal::adc::Channel;
+use controller_core::hal;
+
+/// Every analog input, channels are defined in `controller_core::joystick` and `controller_core::sensors`
+pub struct AnalogInputs {
+    adc: Adc,
+    // Indexed by channel
+    channels: [Channel; 7],
+}
+
+impl AnalogInputs {
+    /// `channels` must be in the order of the channel numbers
+    pub fn new(adc: Adc, channels: [Channel; 7]) -> Self {
+        AnalogInputs {
+            adc,
+            channels
+        }
+    }
+}
+
+impl hal::Adc for AnalogInputs {
+    fn read(&mut self, channel: u8) -> u16 {
+        match self.channels.get(channel as usize) {
+            Some(pin) => self.adc.read_blocking(pin),
+            None => 0
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use core::convert::Infallible;
+use core::ops::DerefMut;
+use avr_device::atmega2560::{EEPROM, TC3, USART0, USART1};
+use avr_device::interrupt;
+use embedded_hal::digital::v2::OutputPin;
+use common::actuator::{Actuator, SAFE_ACTUATORS};
+use controller_core::actuator;
+use controller_core::actuator::Output;
+use controller_core::hal;
+use controller_core::spsc::Producer;
+use crate::{time, SABERTOOTH_WRITE_SIZE, USB_READ_CONSUMER, USB_READ_OVERRUNS, USB_WRITE_SIZE};
+
+/// The usb serial port, received bytes are buffered by the `USART0_RX` interrupt and sent ones by `USART0_UDRE`
+pub struct Usb<W> {
+    /// Owns the transmit pin, only the interrupt writes to the usart
+    pub writer: W,
+    pub write_producer: Producer<'static, u8, USB_WRITE_SIZE>,
+}
+
+impl<W> hal::SerialRx for Usb<W> {
+    fn read(&mut self) -> Option<u8> {
+        // Get the next byte from the queue
+        interrupt::free(|cs| {
+            if let Some(ref mut usb_consumer) = USB_READ_CONSUMER.borrow(cs).borrow_mut().deref_mut() {
+                usb_consumer.dequeue()
+            } else {
+                None
+            }
+        })
+    }
+
+    fn overruns(&mut self) -> u32 {
+        interrupt::free(|cs| USB_READ_OVERRUNS.borrow(cs).get())
+    }
+}
+
+impl<W> hal::SerialTx for Usb<W> {
+    fn space(&mut self) -> usize {
+        self.write_producer.capacity() - self.write_producer.len()
+    }
+
+    fn write(&mut self, byte: u8) {
+        let _ = self.write_producer.enqueue(byte);
+
+        // Start the interrupt, it stops itself once the queue is empty
+        interrupt::free(|_| {
+            // This is safe because the interrupt only touches this register inside a critical section
+            let usart = unsafe { &*USART0::ptr() };
+            usart.ucsr0b.modify(|_, w| w.udrie0().set_bit());
+        });
+    }
+}
+
+/// The serial port to the sabertooths, sent bytes are buffered by the `USART1_UDRE` interrupt
+pub struct Sabertooth<U> {
+    /// Owns the usart and its pins, only the interrupt writes to it
+    pub usart: U,
+    pub write_producer: Producer<'static, u8, SABERTOOTH_WRITE_SIZE>,
+}
+
+impl<U> hal::SerialTx for Sabertooth<U> {
+    fn space(&mut self) -> usize {
+        self.write_producer.capacity() - self.write_producer.len()
+    }
+
+    fn write(&mut self, byte: u8) {
+        let _ = self.write_producer.enqueue(byte);
+
+        // Start the interrupt, it stops itself once the queue is empty
+        interrupt::free(|_| {
+            // This is safe because the interrupt only touches this register inside a critical section
+            let usart = unsafe { &*USART1::ptr() };
+            usart.ucsr1b.modify(|_, w| w.udrie1().set_bit());
+        });
+    }
+}
+
+/// The timer 0 clock
+pub struct Millis;
+
+impl hal::Clock for Millis {
+    fn millis(&mut self) -> u32 {
+        time::millis()
+    }
+
+    fn micros(&mut self) -> u32 {
+        time::micros()
+    }
+}
+
+/// The emergency stop pins of both sabertooths, driven together
+pub struct EStopPins<A, B>(pub A, pub B);
+
+impl<A: OutputPin<Error = Infallible>, B: OutputPin<Error = Infallible>> OutputPin for EStopPins<A, B> {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.set_low()?;
+        self.1.set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.set_high()?;
+        self.1.set_high()
+    }
+}
+
+/// The internal eeprom
+pub struct Eeprom(pub EEPROM);
+
+impl hal::Storage for Eeprom {
+    fn read(&mut self, address: u16) -> u8 {
+        // Wait for any write to finish before changing the address
+        while self.0.eecr.read().eepe().bit_is_set() {}
+
+        self.0.eear.write(|w| unsafe { w.bits(address) });
+        self.0.eecr.write(|w| w.eere().set_bit());
+        self.0.eedr.read().bits()
+    }
+
+    fn ready(&mut self) -> bool {
+        self.0.eecr.read().eepe().bit_is_clear()
+    }
+
+    fn write(&mut self, address: u16, byte: u8) {
+        self.0.eear.write(|w| unsafe { w.bits(address) });
+        self.0.eedr.write(|w| unsafe { w.bits(byte) });
+
+        // The write must be started within four cycles of enabling it
+        interrupt::free(|_| {
+            self.0.eecr.write(|w| w.eempe().set_bit());
+            self.0.eecr.write(|w| w.eempe().set_bit().eepe().set_bit());
+        });
+    }
+}
+
+/// Timer 3 counts every 0.5us
+const TICKS_PER_US: u16 = 2;
+/// Timer 3 wraps every 20ms, the period servos expect
+const SERVO_PERIOD: u16 = 20000 * TICKS_PER_US;
+
+/// The auxiliary actuators
+/// The servos and lights are on the pwm outputs of timer 3, the aux output is a plain pin
+pub struct Actuators<Pins, Aux> {
+    tc3: TC3,
+    // Only held so the pwm pins stay outputs
+    _pins: Pins,
+    aux: Aux,
+}
+
+impl<Pins, Aux: OutputPin<Error = Infallible>> Actuators<Pins, Aux> {
+    /// `pins` must be the oc3a (claw), oc3b (camera tilt) and oc3c (lights) pins configured as outputs
+    pub fn new(tc3: TC3, pins: Pins, aux: Aux) -> Self {
+        // Fast pwm with icr3 as top, the outputs are cleared on compare match
+        tc3.icr3.write(|w| unsafe { w.bits(SERVO_PERIOD - 1) });
+        tc3.tccr3a.write(|w| w.wgm3().bits(0b10).com3a().match_clear().com3b().match_clear().com3c().match_clear());
+        tc3.tccr3b.write(|w| w.wgm3().bits(0b11).cs3().prescale_8());
+
+        let mut actuators = Actuators { tc3, _pins: pins, aux };
+        for actuator in Actuator::ALL {
+            hal::Actuators::set(&mut actuators, actuator, actuator::output(actuator, SAFE_ACTUATORS.get(actuator)));
+        }
+        actuators
+    }
+}
+
+impl<Pins, Aux: OutputPin<Error = Infallible>> hal::Actuators for Actuators<Pins, Aux> {
+    fn set(&mut self, actuator: Actuator, output: Output) {
+        let ticks = match output {
+            Output::Servo(pulse) => pulse * TICKS_PER_US,
+            Output::Pwm(duty) => (duty as u32 * SERVO_PERIOD as u32 / 255) as u16,
+            Output::Digital(on) => {
+                let _ = if on { self.aux.set_high() } else { self.aux.set_low() };
+                return;
+            }
+        };
+
+        match actuator {
+            Actuator::Claw => self.tc3.ocr3a.write(|w| unsafe { w.bits(ticks) }),
+            Actuator::CameraTilt => self.tc3.ocr3b.write(|w| unsafe { w.bits(ticks) }),
+            Actuator::Lights => self.tc3.ocr3c.write(|w| unsafe { w.bits(ticks) }),
+            Actuator::Aux => {}
+        }
+    }
+}
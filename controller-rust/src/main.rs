@@ -3,21 +3,18 @@
 #![feature(abi_avr_interrupt)]
 
 mod time;
-mod state;
-mod sabertooth;
-mod spsc;
-mod joystick;
+mod analog;
+mod board;
 
-use core::cell::RefCell;
-use core::mem;
+use core::cell::{Cell, RefCell};
+use core::mem::MaybeUninit;
+use core::ptr;
 use core::ops::DerefMut;
 use arduino_hal::prelude::*;
 use embedded_hal::prelude::*;
-use embedded_hal::serial::Write;
-use heapless::Vec;
-use nb::block;
-use common::controller::{DownstreamMessage, UpstreamMessage, VelocityData};
-use crate::state::State;
+use common::controller::{Hello, ResetCause};
+use controller_core::controller::{self, Controller};
+use controller_core::panic::PanicRecord;
 
 use core::panic::PanicInfo;
 use core::sync::atomic;
@@ -29,27 +26,34 @@ use arduino_hal::hal::wdt;
 use arduino_hal::port::mode::{Input, Output};
 use arduino_hal::port::Pin;
 use arduino_hal::usart::UsartReader;
-use avr_device::atmega2560::USART0;
+use avr_device::atmega2560::{USART0, USART1};
 use avr_device::interrupt;
 use avr_device::interrupt::Mutex;
-use spsc::{Consumer, Producer, Queue};
-use ufmt::uwriteln;
-use common::CommunicationError;
-use crate::joystick::Joystick;
+use controller_core::spsc::{Consumer, Producer, Queue};
+use crate::board::{Actuators, Eeprom, EStopPins, Millis, Sabertooth, Usb};
+use crate::analog::AnalogInputs;
+
+/// Where the firmware last panicked, survives the watchdog reset the panic handler triggers
+#[link_section = ".noinit"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
 
 #[inline(never)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     interrupt::disable();
 
+    if let Some(location) = info.location() {
+        // This is safe because no other code can be running at the same time
+        unsafe { ptr::write_volatile(PANIC_RECORD.as_mut_ptr(), PanicRecord::new(location.file(), location.line(), location.column())) };
+    }
+
+    // Reset through the watchdog, the panic is reported once we are back up
     // This is safe because no other code can be running at the same time
     let dp = unsafe { Peripherals::steal() };
-    let pins = pins!(dp);
-    let mut usb = default_serial!(dp, pins, common::BAUD_RATE_CTRL);
+    let mut watchdog = wdt::Wdt::new(dp.WDT, &dp.CPU.mcusr);
+    let _ = watchdog.start(wdt::Timeout::Ms16);
 
-    let location = info.location().unwrap();
     loop {
-        let _ = uwriteln!(usb, "Panicked at {}:{} in {}", location.line(), location.column(), location.file());
         atomic::compiler_fence(Ordering::SeqCst);
     }
 }
@@ -59,41 +63,94 @@ static USB_READER_SERIAL: Mutex<RefCell<Option<UsartReader<USART0, Pin<Input, PE
 static mut USB_READ_QUEUE: Queue<u8, 256> = Queue::new();
 static USB_READ_PRODUCER: Mutex<RefCell<Option<Producer<u8, 256>>>> = Mutex::new(RefCell::new(None));
 static USB_READ_CONSUMER: Mutex<RefCell<Option<Consumer<u8, 256>>>> = Mutex::new(RefCell::new(None));
+// Bytes the receive interrupt dropped because the queue was full
+static USB_READ_OVERRUNS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+// Bytes waiting to be sent, drained by the data register empty interrupts
+// Sized to hold a few messages each, packets that don't fit are dropped by the control loop
+pub const USB_WRITE_SIZE: usize = 256;
+pub const SABERTOOTH_WRITE_SIZE: usize = 128;
+static mut USB_WRITE_QUEUE: Queue<u8, USB_WRITE_SIZE> = Queue::new();
+static USB_WRITE_CONSUMER: Mutex<RefCell<Option<Consumer<u8, USB_WRITE_SIZE>>>> = Mutex::new(RefCell::new(None));
+static mut SABERTOOTH_WRITE_QUEUE: Queue<u8, SABERTOOTH_WRITE_SIZE> = Queue::new();
+static SABERTOOTH_WRITE_CONSUMER: Mutex<RefCell<Option<Consumer<u8, SABERTOOTH_WRITE_SIZE>>>> = Mutex::new(RefCell::new(None));
+
+/// Identifies this firmware to the pc, the reset cause is filled in on boot
+const HELLO: Hello = Hello {
+    protocol_version: common::controller::PROTOCOL_VERSION,
+    build_id: env!("CARGO_PKG_VERSION"),
+    capabilities: common::controller::CAPABILITY_FAILSAFE | common::controller::CAPABILITY_ESTOP_RELEASE | common::controller::CAPABILITY_MIXING | common::controller::CAPABILITY_SLEW_LIMIT | common::controller::CAPABILITY_CALIBRATION | common::controller::CAPABILITY_ACTUATORS | common::controller::CAPABILITY_SENSORS,
+    reset_cause: ResetCause(0),
+};
 
 // Pins
 // Joystick: a1, a0, a3, a2
+// Battery voltage: a4
+// Tether voltage: a5
+// Leak probe: a6
 // Sabertooth serial: none (rx), d18 (tx)
+// Claw servo: d5
+// Camera tilt servo: d2
+// Lights: d3
+// Aux: d24
 
 // Active low
 // Sabertooth e-stop 1: d8
 // Sabertooth e-stop 2: d9
 // E-stop button: d22
 // Joystick-enable: d30
+// Joystick-priority: d32
 
 #[arduino_hal::entry]
 fn main() -> ! {
-    // This buffer will hold partially received packets
-    let mut usb_buffer = Vec::<u8, { mem::size_of::<DownstreamMessage>() + 5 }>::new();
-
-
     // Setup up peripherals
     let dp = Peripherals::take().unwrap();
     let pins = pins!(dp);
 
+    // Find out why we restarted, the flags must be cleared so the next reset reports only its own cause
+    let mcusr = dp.CPU.mcusr.read().bits();
+    dp.CPU.mcusr.write(|w| unsafe { w.bits(0) });
+
+    // A watchdog reset leaves the watchdog running with its shortest timeout, stop it before it resets us again
+    // It is started again once the control loop runs
+    let mut watchdog = wdt::Wdt::new(dp.WDT, &dp.CPU.mcusr);
+    watchdog.stop();
+
+    // Take the panic record left by the last boot, if there is one
+    // This is safe because interrupts are not enabled yet, any bit pattern is a valid record
+    let panic_record = unsafe {
+        let record = ptr::read_volatile(PANIC_RECORD.as_ptr());
+        ptr::write_volatile(PANIC_RECORD.as_mut_ptr(), PanicRecord::empty());
+        record
+    };
+    let reset_cause = panic_record.reset_cause(mcusr);
+
     // Emergency stop
-    let mut estop_out_a = pins.d8.into_output_high();
-    let mut estop_out_b = pins.d9.into_output_high();
+    let estop_out = EStopPins(pins.d8.into_output_high(), pins.d9.into_output_high());
     let estop_in = pins.d22.into_pull_up_input();
 
-    // Joysticks
+    // Joysticks and sensors
     let mut adc = Adc::new(dp.ADC, Default::default());
-    let joystick = Joystick::new(pins.a1, pins.a0, pins.a3, pins.a2, &mut adc);
+    let channels = [
+        pins.a1.into_analog_input(&mut adc).into_channel(), // left x
+        pins.a0.into_analog_input(&mut adc).into_channel(), // left y
+        pins.a3.into_analog_input(&mut adc).into_channel(), // right x
+        pins.a2.into_analog_input(&mut adc).into_channel(), // right y
+        pins.a4.into_analog_input(&mut adc).into_channel(), // battery voltage
+        pins.a5.into_analog_input(&mut adc).into_channel(), // tether voltage
+        pins.a6.into_analog_input(&mut adc).into_channel(), // leak probe
+    ];
+    let analog = AnalogInputs::new(adc, channels);
     let joystick_enable = pins.d30.into_pull_up_input();
+    let joystick_priority = pins.d32.into_pull_up_input();
+
+    // Auxiliary actuators, start at their safe values
+    let actuators = Actuators::new(dp.TC3, (pins.d5.into_output(), pins.d2.into_output(), pins.d3.into_output()), pins.d24.into_output());
 
     // Setup Serial
     let mut usb = default_serial!(dp, pins, common::BAUD_RATE_CTRL);
-    let mut sabertooth = Usart::new(dp.USART1, pins.d19, pins.d18.into_output(), common::BAUD_RATE_SABERTOOTH.into_baudrate());
-    let mut usb_writer = {
+    let sabertooth = Usart::new(dp.USART1, pins.d19, pins.d18.into_output(), common::BAUD_RATE_SABERTOOTH.into_baudrate());
+    let (usb, sabertooth) = {
         // To improve reliability, we need to handle serial data as soon as it is received
         usb.listen(Event::RxComplete);
 
@@ -111,120 +168,49 @@ fn main() -> ! {
             atomic::compiler_fence(Ordering::SeqCst);
         });
 
-        usb_writer
+        // Writes are queued and sent by the data register empty interrupts, so the control loop never waits on a usart
+        let (usb_write_producer, usb_write_consumer) = unsafe { USB_WRITE_QUEUE.split() };
+        let (sabertooth_write_producer, sabertooth_write_consumer) = unsafe { SABERTOOTH_WRITE_QUEUE.split() };
+        interrupt::free(|cs| {
+            USB_WRITE_CONSUMER.borrow(cs).replace(Some(usb_write_consumer));
+            SABERTOOTH_WRITE_CONSUMER.borrow(cs).replace(Some(sabertooth_write_consumer));
+        });
+
+        (
+            Usb { writer: usb_writer, write_producer: usb_write_producer },
+            Sabertooth { usart: sabertooth, write_producer: sabertooth_write_producer }
+        )
     };
 
+    let mut peripherals = controller::Peripherals {
+        pc: usb,
+        motors: sabertooth,
+        analog,
+        clock: Millis,
+        estop_button: estop_in,
+        joystick_enable,
+        joystick_priority,
+        estop_out,
+        storage: Eeprom(dp.EEPROM),
+        actuators
+    };
+    let mut controller = Controller::new(Hello { reset_cause, ..HELLO });
+    controller.set_panic(panic_record);
 
     // Start clock
     time::millis_init(dp.TC0);
 
-    // Wait for sabertooth motor controllers to power on and then initialize it
-    delay_ms(2000);
-    write_callback(sabertooth::write_init, &mut sabertooth);
-
     // Enable interrupts globally
     unsafe { interrupt::enable() };
 
-    // Notify the connected pc that we are ready to receive data
-    write_message(&UpstreamMessage::Init, &mut usb_writer);
+    // Wait for sabertooth motor controllers to power on and then initialize them
+    delay_ms(2000);
+    controller.init(&mut peripherals);
 
-    let mut watchdog = wdt::Wdt::new(dp.WDT, &dp.CPU.mcusr);
     watchdog.start(wdt::Timeout::Ms64).unwrap();
 
-    let mut state = State::default();
     loop {
-        // process data from computer
-        loop {
-            // Get the next byte from the queue
-            let byte = interrupt::free(|cs| {
-                if let Some(ref mut usb_consumer) = USB_READ_CONSUMER.borrow(cs).borrow_mut().deref_mut() {
-                    usb_consumer.dequeue()
-                } else {
-                    None
-                }
-            });
-
-            // Process that byte
-            if let Some(byte) = byte {
-                // Add that byte to the buffer
-                if let Ok(()) = usb_buffer.push(byte) {
-                    // If that byte signals the end of a packet we needed to parse the packet
-                    if common::end_of_frame(&byte) {
-                        match common::read(&mut usb_buffer) {
-                            Ok(message) => {
-                                // Update the robot's state and send acknowledgement
-                                state.update_pc(message);
-                                write_message(&UpstreamMessage::Ack, &mut usb_writer);
-                            }
-                            Err(e) => {
-                                // data was corrupted during transmission
-                                write_message(&UpstreamMessage::BadP(e), &mut usb_writer);
-                            }
-                        }
-
-                        // Clear the packet buffer so we can receive the next packet
-                        usb_buffer.clear();
-                    }
-                } else {
-                    // data buffer was over run, a seperator byte was mis-received
-                    write_message(&UpstreamMessage::BadO, &mut usb_writer);
-                    usb_buffer.clear();
-                }
-            } else {
-                // No more new data
-                break;
-            }
-        }
-
-        // Update joystick state
-        {
-            if joystick_enable.is_low() {
-                let joystick_velocity = joystick.read(&mut adc);
-                state.update_joystick(joystick_velocity);
-            } else {
-                state.update_joystick(VelocityData::default());
-            }
-        }
-
-        // Read emergency stop button
-        {
-            let emergency_stop = estop_in.is_high();
-            state.update_emergency_stop(emergency_stop);
-        }
-
-        // Tell the motor controllers to go into an emergency stop if necessary
-        {
-            if state.emergency_stop() {
-                // The sabertooth's emergency stop pin is active low
-                estop_out_a.set_low();
-                estop_out_b.set_low();
-            }
-
-            // Notify the connected pc
-            write_message(&UpstreamMessage::EStop(state.emergency_stop()), &mut usb_writer);
-        }
-        
-        // Respond to pings
-        {
-            if state.do_ping() {
-                write_message(&UpstreamMessage::Pong, &mut usb_writer);
-                state.clear_ping();
-            }
-        }
-
-        // Send updated motor speeds
-        {
-            let total_velocity = state.compute_velocity();
-            let VelocityData { forwards_left, forwards_right, strafing, vertical } = total_velocity;
-            write_callback(|buffer| sabertooth::write_speed(buffer, sabertooth::MOTOR_LEFT,     (forwards_left * 127.0) as i8),  &mut sabertooth);
-            write_callback(|buffer| sabertooth::write_speed(buffer, sabertooth::MOTOR_RIGHT,    (forwards_right * 127.0) as i8), &mut sabertooth);
-            write_callback(|buffer| sabertooth::write_speed(buffer, sabertooth::MOTOR_STRAFING, (strafing * 127.0) as i8),       &mut sabertooth);
-            write_callback(|buffer| sabertooth::write_speed(buffer, sabertooth::MOTOR_VERTICAL, (vertical * 127.0) as i8),       &mut sabertooth);
-
-            // Notify the connected pc
-            write_message(&UpstreamMessage::TotalVelocity(total_velocity), &mut usb_writer);
-        }
-
+        controller.step(&mut peripherals);
         watchdog.feed();
     }
 }
@@ -243,43 +229,48 @@ fn USART0_RX() {
             if let Some(ref mut usb_producer) = USB_READ_PRODUCER.borrow(cs).borrow_mut().deref_mut() {
                 // Read all available data
                 while let Ok(byte) = usb.read() {
-                    let _ = usb_producer.enqueue(byte);
+                    if usb_producer.enqueue(byte).is_err() {
+                        let overruns = USB_READ_OVERRUNS.borrow(cs);
+                        overruns.set(overruns.get().wrapping_add(1));
+                    }
                 }
             }
         }
     });
 }
 
+#[avr_device::interrupt(atmega2560)]
+#[allow(non_snake_case)]
+fn USART0_UDRE() {
+    interrupt::free(|cs| {
+        // This is safe because only this interrupt writes the data register once the queue is set up
+        let usart = unsafe { &*USART0::ptr() };
+        if let Some(ref mut usb_consumer) = USB_WRITE_CONSUMER.borrow(cs).borrow_mut().deref_mut() {
+            if let Some(byte) = usb_consumer.dequeue() {
+                usart.udr0.write(|w| w.bits(byte));
+                return;
+            }
+        }
 
-// -------------------------
-// |     Communication     |
-// -------------------------
-
-static mut OUT_BUFFER: [u8; 200] = [0; 200];
-
-/// This function is unsafe when called from an interrupt handler
-fn write_message(message: &UpstreamMessage, serial: &mut impl Write<u8>) {
-    // Retrieve a temporary buffer and encode the packet into it
-    let buffer = unsafe { &mut OUT_BUFFER };
-    if let Ok(buffer) = common::write(message, buffer) {
-        // Write the buffer
-        write_buffer(buffer, serial);
-    }
+        // Nothing left to send, `Usb` starts the interrupt again on the next write
+        usart.ucsr0b.modify(|_, w| w.udrie0().clear_bit());
+    });
 }
 
-/// This function is unsafe when called from an interrupt handler
-fn write_callback<F: Fn(&mut [u8]) -> Result<&mut [u8], CommunicationError>>(message_producer: F, serial: &mut impl Write<u8>) {
-    // Retrieve a temporary buffer and encode the packet into it
-    let buffer = unsafe { &mut OUT_BUFFER };
-    if let Ok(buffer) = (message_producer)(buffer) {
-        // Write the buffer
-        write_buffer(buffer, serial);
-    }
-}
+#[avr_device::interrupt(atmega2560)]
+#[allow(non_snake_case)]
+fn USART1_UDRE() {
+    interrupt::free(|cs| {
+        // This is safe because only this interrupt writes the data register once the queue is set up
+        let usart = unsafe { &*USART1::ptr() };
+        if let Some(ref mut sabertooth_consumer) = SABERTOOTH_WRITE_CONSUMER.borrow(cs).borrow_mut().deref_mut() {
+            if let Some(byte) = sabertooth_consumer.dequeue() {
+                usart.udr1.write(|w| w.bits(byte));
+                return;
+            }
+        }
 
-fn write_buffer(buffer: &[u8], serial: &mut impl Write<u8>) {
-    // write each byte to serial
-    for &byte in buffer {
-        let _ = block!(serial.write(byte));
-    }
+        // Nothing left to send, `Sabertooth` starts the interrupt again on the next write
+        usart.ucsr1b.modify(|_, w| w.udrie1().clear_bit());
+    });
 }
@@ -14,10 +14,12 @@ use avr_device::interrupt::Mutex;
 // ║      1024 ║          125 ║              8 ms ║
 // ║      1024 ║          250 ║             16 ms ║
 // ╚═══════════╩══════════════╩═══════════════════╝
-const PRESCALER: u32 = 256;
-const TIMER_COUNTS: u32 = 125;
+const PRESCALER: u32 = 64;
+const TIMER_COUNTS: u32 = 250;
 
 const MILLIS_INCREMENT: u32 = PRESCALER * TIMER_COUNTS / 16000;
+// The timer runs at 16MHz / PRESCALER
+const MICROS_PER_COUNT: u32 = PRESCALER / 16;
 
 static MILLIS_COUNTER: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
 
@@ -26,7 +28,8 @@ pub fn millis_init(tc0: TC0) {
     // Configure the timer for the above interval (in CTC mode)
     // and enable its interrupt.
     tc0.tccr0a.write(|w| w.wgm0().ctc());
-    tc0.ocr0a.write(|w| unsafe { w.bits(TIMER_COUNTS as u8) });
+    // The counter resets after reaching ocr0a, so it counts from 0 to TIMER_COUNTS - 1
+    tc0.ocr0a.write(|w| unsafe { w.bits((TIMER_COUNTS - 1) as u8) });
     tc0.tccr0b.write(|w| match PRESCALER {
         8 => w.cs0().prescale_8(),
         64 => w.cs0().prescale_64(),
@@ -55,4 +58,22 @@ fn TIMER0_COMPA() {
 /// Get the current time
 pub fn millis() -> u32 {
     interrupt::free(|cs| MILLIS_COUNTER.borrow(cs).get())
-}
\ No newline at end of file
+}
+
+/// Get the current time in microseconds, with a resolution of `MICROS_PER_COUNT`
+pub fn micros() -> u32 {
+    interrupt::free(|cs| {
+        // This is safe because the timer is only read here, it is configured by `millis_init`
+        let tc0 = unsafe { &*TC0::ptr() };
+
+        let millis = MILLIS_COUNTER.borrow(cs).get();
+        let mut counts = tc0.tcnt0.read().bits() as u32;
+
+        // The counter may have wrapped since interrupts were disabled, in which case the millisecond counter is behind
+        if tc0.tifr0.read().ocf0a().bit_is_set() {
+            counts = tc0.tcnt0.read().bits() as u32 + TIMER_COUNTS;
+        }
+
+        millis.wrapping_mul(1000).wrapping_add(counts * MICROS_PER_COUNT)
+    })
+}
@@ -0,0 +1,42 @@
+use std::env;
+use anyhow::{anyhow, bail, Context};
+use serial::port::PortConfig;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio_serial::SerialPortBuilderExt;
+
+/// Runs on the onboard computer, forwarding between the pc and the controller's serial port
+///
+/// Usage: `bridge (--tcp <addr> | --udp <addr>) [--port-config <file>] [--controller-port <spec>]`
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<!> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let ports = PortConfig::from_args(args.iter().cloned())?;
+
+    let Some(port) = ports.controller.find()? else {
+        bail!("No port matches {}", ports.controller);
+    };
+    println!("Selected port {}", port.port_name);
+    let serial = tokio_serial::new(&port.port_name, common::BAUD_RATE_CTRL)
+        .open_native_async()
+        .with_context(|| format!("could not open {}", port.port_name))?;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg != "--tcp" && arg != "--udp" {
+            continue;
+        }
+        let address = args.next().ok_or_else(|| anyhow!("{} needs an address", arg))?;
+
+        if arg == "--tcp" {
+            let listener = TcpListener::bind(address).await.with_context(|| format!("could not listen on {}", address))?;
+            println!("Listening on tcp {}", address);
+            return serial::net::bridge_tcp(listener, serial).await;
+        } else {
+            let socket = UdpSocket::bind(address).await.with_context(|| format!("could not bind {}", address))?;
+            println!("Listening on udp {}", address);
+            return serial::net::bridge_udp(socket, serial).await;
+        }
+    }
+
+    bail!("Pass --tcp <addr> or --udp <addr>")
+}
@@ -1,9 +1,19 @@
-#![feature(never_type)]
-
+use std::env;
+use serialport::SerialPortType;
 use sensor_fusion::state;
 use sensor_fusion::state::RobotState;
+use serial::imu::ImuEvent;
+use serial::port::PortConfig;
+
+/// Usage: `calibrate [list-ports] [--port-config <file>] [--controller-port <spec>] [--imu-port <spec>]`
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let ports = PortConfig::from_args(args.iter().cloned())?;
+
+    if args.first().map(String::as_str) == Some("list-ports") {
+        return list_ports(&ports);
+    }
 
-fn main() -> anyhow::Result<!> {
     //let mut gyro_data = (0.0, 0.0, 0.0);
     //let mut local_accel = (0.0, 0.0, 0.0);
     //let mut world_accel = (0.0, 0.0, 0.0);
@@ -13,13 +23,50 @@ fn main() -> anyhow::Result<!> {
 
     //let mut counter = 0;
 
-    serial::imu::listen(move |frame| {
+    serial::imu::listen(move |event| {
+        let ImuEvent::Frame(frame, makeup) = event else {
+            return Ok(());
+        };
+
         //counter += 1;
-        state::update_state(&frame, &mut state);
+        state::update_state(&frame, &mut state, makeup);
 
         //calibrate_local_accel(&frame, &mut local_accel, counter);
         Ok(())
-    })
+    }, ports.imu)?
+}
+
+/// Print every serial port and which device it would be picked for, to help write a port config
+fn list_ports(ports: &PortConfig) -> anyhow::Result<()> {
+    for port in serialport::available_ports()? {
+        let description = match &port.port_type {
+            SerialPortType::UsbPort(info) => format!(
+                "usb vid={:04x} pid={:04x} serial={} product={}",
+                info.vid,
+                info.pid,
+                info.serial_number.as_deref().unwrap_or("-"),
+                info.product.as_deref().unwrap_or("-"),
+            ),
+            SerialPortType::PciPort => "pci".to_owned(),
+            SerialPortType::BluetoothPort => "bluetooth".to_owned(),
+            SerialPortType::Unknown => "unknown".to_owned(),
+        };
+
+        let mut devices = Vec::new();
+        if ports.controller.matches(&port) {
+            devices.push("controller");
+        }
+        if ports.imu.matches(&port) {
+            devices.push("imu");
+        }
+
+        println!("{} {} [{}]", port.port_name, description, devices.join(", "));
+    }
+
+    println!("controller: {}", ports.controller);
+    println!("imu: {}", ports.imu);
+
+    Ok(())
 }
 
 /*fn calibrate_gyro(frame: &IMUFrame, data: &mut (f32, f32, f32), counter: usize) {
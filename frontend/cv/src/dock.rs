@@ -7,10 +7,8 @@ pub struct AutoDock;
 impl OpenCvHandler for AutoDock {
     fn handle_frame(&mut self, _frame: &Mat) -> anyhow::Result<(VelocityData, String)> {
         Ok((VelocityData {
-            forwards_left: 1.0,
-            forwards_right: 1.0,
-            strafing: 0.0,
-            vertical: 0.0
+            surge: 1.0,
+            ..Default::default()
         }, "Docking".to_owned()))
     }
 }
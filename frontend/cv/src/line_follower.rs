@@ -77,10 +77,9 @@ fn center_line(x: f64, y: f64, next_direction: Option<Direction>) -> (VelocityDa
     let correction_multiplier = 1.0;
 
     let update = VelocityData {
-        forwards_left: (error_x * correction_multiplier) as f32,
-        forwards_right: (error_x * correction_multiplier) as f32,
-        strafing: 0.0,
-        vertical: (-error_y * correction_multiplier) as f32
+        surge: (error_x * correction_multiplier) as f32,
+        heave: (-error_y * correction_multiplier) as f32,
+        ..Default::default()
     };
 
     let goal = if error_x.abs() < 0.2 && error_y.abs() < 0.2 {
@@ -109,10 +108,9 @@ fn follow_line(mask: &Mat, line: &Contour, x: f64, y: f64, last_direction: Direc
     };
 
     let update = VelocityData {
-        forwards_left: (error_x * correction_multiplier + horizontal_bias * bias_multiplier) as f32,
-        forwards_right: (error_x * correction_multiplier + horizontal_bias * bias_multiplier) as f32,
-        strafing: 0.0,
-        vertical: (-error_y * correction_multiplier + vertical_bias * bias_multiplier) as f32
+        surge: (error_x * correction_multiplier + horizontal_bias * bias_multiplier) as f32,
+        heave: (-error_y * correction_multiplier + vertical_bias * bias_multiplier) as f32,
+        ..Default::default()
     };
 
     let x_start = mask.cols() * 1 / 5;
@@ -1,6 +1,6 @@
 use bevy::prelude::*;
-use common::controller::{DownstreamMessage, VelocityData};
-use crate::Serial;
+use common::actuator::{Actuator, ActuatorData, SAFE_ACTUATORS};
+use common::controller::VelocityData;
 
 pub struct GamepadPlugin;
 
@@ -15,6 +15,10 @@ impl Plugin for GamepadPlugin {
 
 struct CurrentGamepad(Gamepad);
 pub struct JoyVelo(pub VelocityData);
+pub struct JoyActuators(pub ActuatorData);
+
+/// How far the camera tilts per press of the dpad
+const CAMERA_TILT_STEP: f32 = 0.1;
 
 fn gamepad_connections(
     mut commands: Commands,
@@ -29,6 +33,7 @@ fn gamepad_connections(
                 if current_gamepad.is_none() {
                     commands.insert_resource(CurrentGamepad(*id));
                     commands.insert_resource(JoyVelo(VelocityData::default()));
+                    commands.insert_resource(JoyActuators(SAFE_ACTUATORS));
                 }
             }
             GamepadEventType::Disconnected => {
@@ -38,6 +43,7 @@ fn gamepad_connections(
                     if old_id == id {
                         commands.remove_resource::<CurrentGamepad>();
                         commands.remove_resource::<JoyVelo>();
+                        commands.remove_resource::<JoyActuators>();
                     }
                 }
             }
@@ -50,10 +56,11 @@ fn gamepad_input(
     axes: Res<Axis<GamepadAxis>>,
     buttons: Res<Input<GamepadButton>>,
     current_gamepad: Option<Res<CurrentGamepad>>,
-    velo: Option<ResMut<JoyVelo>>
+    velo: Option<ResMut<JoyVelo>>,
+    actuators: Option<ResMut<JoyActuators>>
 ) {
-    let (gamepad, mut velo) = if let Some((gp, velo)) = current_gamepad.zip(velo) {
-        (gp.0, velo)
+    let (gamepad, mut velo, mut actuators) = if let (Some(gp), Some(velo), Some(actuators)) = (current_gamepad, velo, actuators) {
+        (gp.0, velo, actuators)
     } else {
         return;
     };
@@ -77,5 +84,33 @@ fn gamepad_input(
         velo.0 = velocity;
     }
 
-    // TODO maybe handle buttons
+    // Claw opens while the right trigger is held and closes while the left trigger is held
+    let open_button = GamepadButton(gamepad, GamepadButtonType::RightTrigger2);
+    let close_button = GamepadButton(gamepad, GamepadButtonType::LeftTrigger2);
+    let claw = {
+        let open = if buttons.pressed(open_button) { 1.0 } else { 0.0 };
+        let close = if buttons.pressed(close_button) { -1.0 } else { 0.0 };
+        open + close
+    };
+    actuators.0.set(Actuator::Claw, claw);
+
+    // Dpad up and down tilt the camera
+    let tilt_up_button = GamepadButton(gamepad, GamepadButtonType::DPadUp);
+    let tilt_down_button = GamepadButton(gamepad, GamepadButtonType::DPadDown);
+    let mut tilt = actuators.0.get(Actuator::CameraTilt);
+    if buttons.just_pressed(tilt_up_button) {
+        tilt += CAMERA_TILT_STEP;
+    }
+    if buttons.just_pressed(tilt_down_button) {
+        tilt -= CAMERA_TILT_STEP;
+    }
+    actuators.0.set(Actuator::CameraTilt, tilt);
+
+    // North toggles the lights and west toggles the aux output
+    for (button, actuator) in [(GamepadButtonType::North, Actuator::Lights), (GamepadButtonType::West, Actuator::Aux)] {
+        if buttons.just_pressed(GamepadButton(gamepad, button)) {
+            let on = actuators.0.get(actuator) > 0.5;
+            actuators.0.set(actuator, if on { 0.0 } else { 1.0 });
+        }
+    }
 }
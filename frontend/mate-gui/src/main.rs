@@ -1,5 +1,3 @@
-#![feature(never_type)]
-
 mod render3d;
 mod ui;
 mod video;
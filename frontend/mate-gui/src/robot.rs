@@ -1,11 +1,21 @@
-use std::thread;
-use std::time::Duration;
+use std::{env, thread};
+use std::time::{Duration, Instant};
 use bevy::prelude::*;
 use crossbeam::channel::{bounded, Receiver, Sender};
-use common::controller::{DownstreamMessage, VelocityData};
-use sensor_fusion::state;
+use common::actuator::{Actuator, ActuatorData};
+use common::sensors::{Alarms, DEFAULT_THRESHOLDS, SensorThresholds};
+use common::calibration::{MOTOR_COUNT, MotorCalibration};
+use common::controller::{ArbitrationMode, ControlSource, DownstreamMessage, EStopReason, EStopState, PROTOCOL_VERSION, VelocityData};
+use common::mixing::{AXES, DEFAULT_MIXING, MixingMatrix};
 use sensor_fusion::state::{MotorState, RobotState};
-use crate::{AutoVelo, JoyVelo, ui, utils};
+use serial::command::CommandQueue;
+use serial::connection::ConnectionState;
+use serial::port::PortConfig;
+#[cfg(unix)]
+use serial::sim;
+#[cfg(unix)]
+use serial::sim::SimulatedDevice;
+use crate::{AutoVelo, JoyActuators, JoyVelo, ui, utils};
 
 pub struct RobotPlugin;
 
@@ -13,22 +23,51 @@ impl Plugin for RobotPlugin {
     fn build(&self, app: &mut App) {
         app
             .add_startup_system(serial_monitor)
+            .init_resource::<CalibrationEditor>()
             .add_event::<DataEvent>()
             .add_event::<StateEvent>()
+            .add_event::<ConnectionEvent>()
             .add_system(handler_data)
             .add_system(handler_state)
+            .add_system(handler_connection)
             .add_system(update_displays_imu)
             .add_system(update_displays_controller)
             .add_system(send_velocity)
+            .add_system(send_actuators)
             .add_system(reset_handler)
             .add_system(estop_handler)
+            .add_system(arm_handler)
+            .add_system(arbitration_handler)
             .add_system(estop_display)
+            .add_system(alarm_display)
+            .add_system(link_display)
+            .add_system(calibration_state)
+            .add_system(calibration_handler)
+            .add_system(calibration_display)
         ;
     }
 }
 pub struct DataEvent(pub RobotState);
 pub struct StateEvent(pub MotorState);
-pub struct Serial(Receiver<RobotState>, Receiver<MotorState>, pub Sender<SerialNotification>, pub Sender<DownstreamMessage>);
+pub struct Serial(Receiver<RobotState>, Receiver<MotorState>, pub Sender<SerialNotification>, pub CommandQueue, Receiver<ConnectionEvent>);
+
+/// One of the serial links to the robot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialLink {
+    Controller,
+    Imu,
+}
+/// Keeps the simulated devices of `--demo` running
+#[cfg(unix)]
+struct Demo {
+    _controller: SimulatedDevice,
+    _imu: SimulatedDevice,
+}
+/// A serial link changed state
+pub struct ConnectionEvent(pub SerialLink, pub ConnectionState);
+/// Shows the state of a serial link
+#[derive(Component)]
+pub struct LinkDisplay(pub SerialLink);
 
 #[derive(Component)]
 pub struct ResetButton;
@@ -37,6 +76,46 @@ pub struct ResetButton;
 pub struct EStopButton;
 #[derive(Component)]
 pub struct EStopText;
+#[derive(Component)]
+pub struct ArmButton;
+/// Shows the alarms raised by the firmware over the camera
+#[derive(Component)]
+pub struct AlarmBanner;
+/// Selects how the pc and onboard joystick setpoints are combined
+#[derive(Component)]
+pub struct ArbitrationButton(pub ArbitrationMode);
+
+/// Selects the next motor in the calibration panel
+#[derive(Component)]
+pub struct CalibrationMotorButton;
+/// Asks the firmware to report its calibration again
+#[derive(Component)]
+pub struct CalibrationReloadButton;
+/// Changes the calibration of the selected motor
+#[derive(Component, Clone, Copy)]
+pub enum CalibrationButton {
+    Invert,
+    Trim(f32),
+    MaxOutput(f32),
+}
+
+#[derive(Component)]
+pub enum CalibrationData {
+    Motor,
+    Inverted,
+    Trim,
+    MaxOutput,
+}
+
+/// Names of the motors, in the order of `common::calibration::Calibration`
+pub const MOTOR_NAMES: [&str; MOTOR_COUNT] = ["Left", "Right", "Strafing", "Vertical"];
+
+/// The motor being edited and the last calibration we know of for each motor
+#[derive(Default)]
+pub struct CalibrationEditor {
+    selected: usize,
+    calibration: [Option<MotorCalibration>; MOTOR_COUNT],
+}
 
 #[derive(Component)]
 pub enum RobotData {
@@ -69,49 +148,81 @@ pub enum RobotData {
 
 #[derive(Component)]
 pub enum ControllerData {
-    SpeedSpForwardsLeft,
-    SpeedSpForwardsRight,
-    SpeedSpStrafing,
-    SpeedSpVertical,
+    /// Output of the thruster with the given index in the mixing matrix
+    Thruster(usize),
 
     AveragePing,
     LastPing,
+    EStop,
+    Failsafe,
+    /// Arbitration mode and who is in control
+    Control,
+    Actuators,
+    Battery,
+    Tether,
+    Leak,
+    Firmware,
+    ResetCause,
+    LastPanic,
+    PacketLoss,
+    AckLatency,
+    LoopTime,
+    /// Packets the firmware could not decode
+    Errors,
 }
 
 fn serial_monitor(mut commands: Commands) {
     let (tx_data, rx_data) = bounded::<RobotState>(15);
     let (tx_state, rx_state) = bounded::<MotorState>(15);
     let (tx_notification, rx_notification) = bounded::<SerialNotification>(15);
-    let (tx_command, rx_command) = bounded::<DownstreamMessage>(15);
+    let command_queue = CommandQueue::new();
+    let (tx_connection, rx_connection) = bounded::<ConnectionEvent>(15);
+    let ports = PortConfig::from_args(env::args().skip(1)).expect("invalid serial port configuration");
+
+    // Talk to simulated devices instead of the robot
+    #[cfg(unix)]
+    let ports = if env::args().any(|arg| arg == "--demo") {
+        let controller = sim::controller().expect("could not simulate the controller");
+        let imu = sim::imu().expect("could not simulate the imu");
+        let ports = PortConfig { controller: controller.selector(), imu: imu.selector() };
+        commands.insert_resource(Demo { _controller: controller, _imu: imu });
+        ports
+    } else {
+        ports
+    };
 
     {
+        let tx_connection = tx_connection.clone();
+
         thread::Builder::new()
             .name("IMU Serial Monitor".to_owned())
-            .spawn(move || utils::error_boundary(|| communication::listen_to_imu(tx_data.clone(), rx_notification.clone())))
+            .spawn(move || utils::error_boundary(|| communication::listen_to_imu(tx_data.clone(), rx_notification.clone(), tx_connection.clone(), ports.imu.clone())))
             .unwrap();
     }
 
     {
+        let command_queue = command_queue.clone();
+
         thread::Builder::new()
             .name("Controller Serial Monitor".to_owned())
-            .spawn(move || utils::error_boundary(|| communication::listen_to_controller(tx_state.clone(), rx_command.clone())))
+            .spawn(move || utils::error_boundary(|| communication::listen_to_controller(tx_state.clone(), command_queue.clone(), tx_connection.clone(), ports.controller.clone())))
             .unwrap();
     }
 
     {
-        let tx_command = tx_command.clone();
+        let command_queue = command_queue.clone();
 
         thread::Builder::new()
             .name("Controller Pinger".to_owned())
             .spawn(move || utils::error_boundary(|| loop {
-                tx_command.send(DownstreamMessage::Ping)?;
-                state::increment_ping();
+                // The ping is timed once it is written, a full queue drops it
+                let _ = command_queue.send(DownstreamMessage::Ping);
                 thread::sleep(Duration::from_millis(100));
             }))
             .unwrap();
     }
 
-    commands.insert_resource(Serial(rx_data, rx_state, tx_notification, tx_command));
+    commands.insert_resource(Serial(rx_data, rx_state, tx_notification, command_queue, rx_connection));
 }
 
 fn handler_data(mut ev_data: EventWriter<DataEvent>, serial: Res<Serial>) {
@@ -126,6 +237,13 @@ fn handler_state(mut ev_state: EventWriter<StateEvent>, serial: Res<Serial>) {
     }
 }
 
+fn handler_connection(mut ev_connection: EventWriter<ConnectionEvent>, serial: Res<Serial>) {
+    // Every change is forwarded, skipping one could leave a link shown in the wrong state
+    for event in serial.4.try_iter() {
+        ev_connection.send(event);
+    }
+}
+
 fn reset_handler(query: Query<&Interaction, (With<ResetButton>, Changed<Interaction>)>, serial: Res<Serial>) {
     for interaction in query.iter() {
         if let Interaction::Clicked = interaction {
@@ -137,7 +255,23 @@ fn reset_handler(query: Query<&Interaction, (With<ResetButton>, Changed<Interact
 fn estop_handler(query: Query<&Interaction, (With<EStopButton>, Changed<Interaction>)>, serial: Res<Serial>) {
     for interaction in query.iter() {
         if let Interaction::Clicked = interaction {
-            let _ = serial.3.try_send(DownstreamMessage::EmergencyStop);
+            let _ = serial.3.send(DownstreamMessage::EmergencyStop);
+        }
+    }
+}
+
+fn arm_handler(query: Query<&Interaction, (With<ArmButton>, Changed<Interaction>)>, serial: Res<Serial>) {
+    for interaction in query.iter() {
+        if let Interaction::Clicked = interaction {
+            let _ = serial.3.send(DownstreamMessage::Arm);
+        }
+    }
+}
+
+fn arbitration_handler(query: Query<(&Interaction, &ArbitrationButton), Changed<Interaction>>, serial: Res<Serial>) {
+    for (interaction, ArbitrationButton(mode)) in query.iter() {
+        if let Interaction::Clicked = interaction {
+            let _ = serial.3.send(DownstreamMessage::SetArbitration(*mode));
         }
     }
 }
@@ -146,7 +280,7 @@ fn estop_display(mut query: Query<&mut Text, With<EStopText>>, mut ev_state: Eve
     for StateEvent(state) in ev_state.iter() {
         for mut text in query.iter_mut() {
             for section in text.sections.iter_mut() {
-                if state.emergency_stop {
+                if state.emergency_stop.is_latched() {
                     section.style.color = ui::EMERGENCY_STOP_ACTIVE;
                 } else {
                     section.style.color = Color::WHITE;
@@ -156,6 +290,118 @@ fn estop_display(mut query: Query<&mut Text, With<EStopText>>, mut ev_state: Eve
     }
 }
 
+fn alarm_display(mut query: Query<&mut Text, With<AlarmBanner>>, mut ev_state: EventReader<StateEvent>) {
+    for StateEvent(state) in ev_state.iter() {
+        for mut text in query.iter_mut() {
+            let section = &mut text.sections[0];
+            section.value = if state.alarms.is_empty() {
+                String::new()
+            } else {
+                format!("ALARM: {}", state.alarms)
+            };
+        }
+    }
+}
+
+fn link_display(mut query: Query<(&mut Text, &LinkDisplay)>, mut ev_connection: EventReader<ConnectionEvent>) {
+    for ConnectionEvent(link, state) in ev_connection.iter() {
+        for (mut text, LinkDisplay(display)) in query.iter_mut() {
+            if display != link {
+                continue;
+            }
+            if text.sections.len() == 1 {
+                let mut new_section = text.sections[0].clone();
+                new_section.value = String::new();
+                text.sections.push(new_section);
+            }
+
+            let section = &mut text.sections[1];
+            section.value = state.to_string();
+            section.style.color = if *state == ConnectionState::Live {
+                Color::WHITE
+            } else {
+                ui::WARNING_TEXT
+            };
+        }
+    }
+}
+
+fn calibration_state(mut editor: ResMut<CalibrationEditor>, mut ev_state: EventReader<StateEvent>) {
+    for StateEvent(state) in ev_state.iter() {
+        editor.calibration = state.calibration;
+    }
+}
+
+fn calibration_handler(
+    motor_query: Query<&Interaction, (With<CalibrationMotorButton>, Changed<Interaction>)>,
+    reload_query: Query<&Interaction, (With<CalibrationReloadButton>, Changed<Interaction>)>,
+    edit_query: Query<(&Interaction, &CalibrationButton), Changed<Interaction>>,
+    mut editor: ResMut<CalibrationEditor>,
+    serial: Res<Serial>
+) {
+    for interaction in motor_query.iter() {
+        if let Interaction::Clicked = interaction {
+            editor.selected = (editor.selected + 1) % MOTOR_COUNT;
+        }
+    }
+
+    for interaction in reload_query.iter() {
+        if let Interaction::Clicked = interaction {
+            let _ = serial.3.send(DownstreamMessage::GetCalibration);
+        }
+    }
+
+    for (interaction, button) in edit_query.iter() {
+        if let Interaction::Clicked = interaction {
+            let selected = editor.selected;
+
+            // Can't edit what the firmware hasn't reported yet
+            if let Some(mut calibration) = editor.calibration[selected] {
+                match *button {
+                    CalibrationButton::Invert => {
+                        calibration.inverted = !calibration.inverted;
+                    }
+                    CalibrationButton::Trim(step) => {
+                        calibration.trim = (calibration.trim + step).clamp(-1.0, 1.0);
+                    }
+                    CalibrationButton::MaxOutput(step) => {
+                        calibration.max_output = (calibration.max_output + step).clamp(0.0, 1.0);
+                    }
+                }
+
+                // Assume the change was applied so quick clicks build on each other, the firmware's report will correct us if not
+                editor.calibration[selected] = Some(calibration);
+                let _ = serial.3.send(DownstreamMessage::SetCalibration(selected as u8, calibration));
+            }
+        }
+    }
+}
+
+fn calibration_display(mut query: Query<(&mut Text, &CalibrationData)>, editor: Res<CalibrationEditor>) {
+    if !editor.is_changed() {
+        return;
+    }
+
+    let calibration = editor.calibration[editor.selected];
+    for (mut text, data) in query.iter_mut() {
+        if text.sections.len() == 1 {
+            let mut new_section = text.sections[0].clone();
+            new_section.value = String::new();
+            text.sections.push(new_section);
+        }
+        if text.sections.len() == 2 {
+            let section = &mut text.sections[1];
+            section.value = match (data, calibration) {
+                (CalibrationData::Motor, _) => MOTOR_NAMES[editor.selected].to_owned(),
+                (CalibrationData::Inverted, Some(calibration)) => if calibration.inverted { "Yes" } else { "No" }.to_owned(),
+                (CalibrationData::Trim, Some(calibration)) => format!("{:+.2}", calibration.trim),
+                (CalibrationData::MaxOutput, Some(calibration)) => format!("{:.0}%", calibration.max_output * 100.0),
+                (_, None) => "Unknown".to_owned(),
+            };
+        }
+    }
+}
+
 fn update_displays_imu(mut query: Query<(&mut Text, &RobotData)>, mut ev_data: EventReader<DataEvent>) {
     for DataEvent(state) in ev_data.iter() {
         for (mut text, data) in query.iter_mut() {
@@ -264,29 +510,127 @@ fn update_displays_controller(mut query: Query<(&mut Text, &ControllerData)>, mu
             }
             if text.sections.len() == 2 {
                 match data {
-                    ControllerData::SpeedSpForwardsLeft => {
+                    ControllerData::Thruster(thruster) => {
+                        let section = &mut text.sections[1];
+                        section.value = format!("{:.2}", state.total_velocity.0[*thruster]);
+                    }
+                    ControllerData::AveragePing => {
+                        let section = &mut text.sections[1];
+                        section.value = format!("{:.2} ms", state.average_ping / 1000000.0);
+                    }
+                    ControllerData::LastPing => {
                         let section = &mut text.sections[1];
-                        section.value = format!("{:.2}", state.total_velocity.forwards_left);
+                        section.value = format!("{:.2} ms", state.last_ping / 1000000.0);
                     }
-                    ControllerData::SpeedSpForwardsRight => {
+                    ControllerData::PacketLoss => {
                         let section = &mut text.sections[1];
-                        section.value = format!("{:.2}", state.total_velocity.forwards_right);
+                        section.value = format!("{:.1}% up, {:.1}% down", state.upstream_loss * 100.0, state.downstream_loss * 100.0);
                     }
-                    ControllerData::SpeedSpStrafing => {
+                    ControllerData::AckLatency => {
                         let section = &mut text.sections[1];
-                        section.value = format!("{:.2}", state.total_velocity.strafing);
+                        section.value = format!("{:.2} ms", state.ack_latency / 1000000.0);
                     }
-                    ControllerData::SpeedSpVertical => {
+                    ControllerData::Control => {
                         let section = &mut text.sections[1];
-                        section.value = format!("{:.2}", state.total_velocity.vertical);
+                        let mode = match state.arbitration {
+                            ArbitrationMode::Sum => "Sum",
+                            ArbitrationMode::PcOnly => "PC only",
+                            ArbitrationMode::JoystickOnly => "Joystick only",
+                            ArbitrationMode::JoystickOverride => "Joystick override",
+                        };
+                        let source = match state.control_source {
+                            ControlSource::Pc => "PC",
+                            ControlSource::Joystick => "Joystick",
+                            ControlSource::Both => "PC and Joystick",
+                        };
+                        section.value = format!("{} ({})", mode, source);
+                        section.style.color = if state.control_source == ControlSource::Pc { Color::WHITE } else { ui::WARNING_TEXT };
                     }
-                    ControllerData::AveragePing => {
+                    ControllerData::Actuators => {
                         let section = &mut text.sections[1];
-                        section.value = format!("{:.2} ms", state.average_ping / 1000000.0);
+                        let actuators = &state.actuators;
+                        section.value = format!(
+                            "Claw {:.1}, Tilt {:.1}, Lights {:.0}%, Aux {}",
+                            actuators.get(Actuator::Claw),
+                            actuators.get(Actuator::CameraTilt),
+                            actuators.get(Actuator::Lights) * 100.0,
+                            if actuators.get(Actuator::Aux) > 0.5 { "On" } else { "Off" }
+                        );
                     }
-                    ControllerData::LastPing => {
+                    ControllerData::Battery => {
                         let section = &mut text.sections[1];
-                        section.value = format!("{:.2} ms", state.last_ping / 1000000.0);
+                        section.value = format!("{:.1} V", state.sensors.battery_voltage);
+                        section.style.color = if state.alarms.contains(Alarms::LOW_BATTERY) { ui::EMERGENCY_STOP_ACTIVE } else { Color::WHITE };
+                    }
+                    ControllerData::Tether => {
+                        let section = &mut text.sections[1];
+                        section.value = format!("{:.1} V", state.sensors.tether_voltage);
+                        section.style.color = if state.alarms.contains(Alarms::LOW_TETHER) { ui::EMERGENCY_STOP_ACTIVE } else { Color::WHITE };
+                    }
+                    ControllerData::Leak => {
+                        let section = &mut text.sections[1];
+                        section.value = if state.sensors.leak { "Wet" } else { "Dry" }.to_owned();
+                        section.style.color = if state.alarms.contains(Alarms::LEAK) { ui::EMERGENCY_STOP_ACTIVE } else { Color::WHITE };
+                    }
+                    ControllerData::ResetCause => {
+                        let section = &mut text.sections[1];
+                        section.value = match &state.firmware {
+                            Some(firmware) => firmware.reset_cause.to_string(),
+                            None => "Unknown".to_owned()
+                        };
+                    }
+                    ControllerData::LastPanic => {
+                        let section = &mut text.sections[1];
+                        match &state.last_panic {
+                            Some(location) => {
+                                section.value = location.clone();
+                                section.style.color = ui::WARNING_TEXT;
+                            }
+                            None => {
+                                section.value = "None".to_owned();
+                                section.style.color = Color::WHITE;
+                            }
+                        }
+                    }
+                    ControllerData::LoopTime => {
+                        let section = &mut text.sections[1];
+                        section.value = format!("{} us ({} - {})", state.loop_time.avg, state.loop_time.min, state.loop_time.max);
+                    }
+                    ControllerData::Errors => {
+                        let section = &mut text.sections[1];
+                        section.value = format!("{} bad, {} overrun of {}, {} bytes dropped, {} sends dropped", state.counters.bad_packets, state.counters.overruns, state.counters.packets, state.counters.rx_overruns, state.counters.tx_overflows);
+                    }
+                    ControllerData::EStop => {
+                        let section = &mut text.sections[1];
+                        section.value = match state.emergency_stop {
+                            EStopState::Armed => "Armed".to_owned(),
+                            EStopState::Latched(EStopReason::Button) => "Latched by button".to_owned(),
+                            EStopState::Latched(EStopReason::Pc) => "Latched by pc".to_owned(),
+                            EStopState::Latched(EStopReason::Failsafe) => "Latched by failsafe".to_owned(),
+                            EStopState::Latched(EStopReason::Leak) => "Latched by leak".to_owned(),
+                            EStopState::Latched(EStopReason::LowVoltage) => "Latched by low voltage".to_owned(),
+                        };
+                    }
+                    ControllerData::Failsafe => {
+                        let section = &mut text.sections[1];
+                        section.value = if state.failsafe { "Active" } else { "Inactive" }.to_owned();
+                    }
+                    ControllerData::Firmware => {
+                        let section = &mut text.sections[1];
+                        match &state.firmware {
+                            Some(firmware) if firmware.compatible() => {
+                                section.value = format!("{} (protocol {})", firmware.build_id, firmware.protocol_version);
+                                section.style.color = Color::WHITE;
+                            }
+                            Some(firmware) => {
+                                section.value = format!("{} (protocol {}, expected {})", firmware.build_id, firmware.protocol_version, PROTOCOL_VERSION);
+                                section.style.color = ui::WARNING_TEXT;
+                            }
+                            None => {
+                                section.value = "Unknown".to_owned();
+                                section.style.color = Color::WHITE;
+                            }
+                        }
                     }
                 }
             }
@@ -295,74 +639,159 @@ fn update_displays_controller(mut query: Query<(&mut Text, &ControllerData)>, mu
 }
 
 pub fn send_velocity(serial: Res<Serial>, joystick: Option<Res<JoyVelo>>, auto: Option<Res<AutoVelo>>) {
-    let mut forwards_left = 0.0;
-    let mut forwards_right = 0.0;
-    let mut strafing = 0.0;
-    let mut vertical = 0.0;
+    let mut velocity = VelocityData::default();
 
     if let Some(joy_velo) = joystick {
-        forwards_left += joy_velo.0.forwards_left;
-        forwards_right += joy_velo.0.forwards_right;
-        strafing += joy_velo.0.strafing;
-        vertical += joy_velo.0.vertical;
+        velocity = velocity + joy_velo.0.clone();
     }
 
     if let Some(auto) = auto {
-        forwards_left += auto.0.forwards_left;
-        forwards_right += auto.0.forwards_right;
-        strafing += auto.0.strafing;
-        vertical += auto.0.vertical;
+        velocity = velocity + auto.0.clone();
     }
 
-    let update = VelocityData {
-        forwards_left,
-        forwards_right,
-        strafing,
-        vertical
-    }.clamp();
+    let update = velocity.clamp();
 
-    let _ = serial.3.try_send(DownstreamMessage::VelocityUpdate(update));
+    let _ = serial.3.send(DownstreamMessage::VelocityUpdate(update));
 }
 
+/// How often every actuator is sent again, the firmware resets them after it loses contact with us
+const ACTUATOR_RESEND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Send the actuators that changed since the last call, and every actuator once in a while
+pub fn send_actuators(serial: Res<Serial>, actuators: Option<Res<JoyActuators>>, mut last: Local<Option<(ActuatorData, Instant)>>) {
+    let actuators = if let Some(actuators) = actuators {
+        actuators.0
+    } else {
+        *last = None;
+        return;
+    };
+
+    let resend = match *last {
+        Some((_, sent)) => sent.elapsed() >= ACTUATOR_RESEND_INTERVAL,
+        None => true
+    };
+
+    for actuator in Actuator::ALL {
+        let changed = match *last {
+            Some((last, _)) => last.get(actuator) != actuators.get(actuator),
+            None => true
+        };
+
+        if changed || resend {
+            let _ = serial.3.send(DownstreamMessage::SetActuator(actuator, actuators.get(actuator)));
+        }
+    }
+
+    let sent = match *last {
+        Some((_, sent)) if !resend => sent,
+        _ => Instant::now()
+    };
+    *last = Some((actuators, sent));
+}
+
+/// How the robot's thrusters are laid out
+pub const MIXING: MixingMatrix = DEFAULT_MIXING;
+/// How quickly each axis can change in full scale per second
+pub const SLEW_RATES: [f32; AXES] = [2.0, 2.0, 2.0, 2.0, 2.0, 2.0];
+/// How often the controller reports its state (ms)
+pub const TELEMETRY_INTERVAL: u16 = 50;
+/// When the controller raises alarms on the leak and power sensors
+pub const SENSOR_THRESHOLDS: SensorThresholds = DEFAULT_THRESHOLDS;
+
 pub enum SerialNotification {
     ResetState
 }
 
 mod communication {
+    use common::controller::UpstreamMessage;
+    use serial::controller::ControllerEvent;
+    use serial::command::QueueFull;
+    use serial::imu::ImuEvent;
+    use serial::port::PortSelector;
     use sensor_fusion::state;
     use sensor_fusion::state::MotorState;
     use super::*;
 
-    pub(super) fn listen_to_imu(tx_data: Sender<RobotState>, rx_notification: Receiver<SerialNotification>) -> anyhow::Result<!> {
+    pub(super) fn listen_to_imu(tx_data: Sender<RobotState>, rx_notification: Receiver<SerialNotification>, tx_connection: Sender<ConnectionEvent>, port: PortSelector) -> anyhow::Result<!> {
         let mut state = RobotState::default();
         state.reset();
 
-        serial::imu::listen(move |frame, makeup| {
-            for command in rx_notification.try_iter() {
-                match command {
-                    SerialNotification::ResetState => {
-                        state.reset();
+        serial::imu::listen(move |event| {
+            match event {
+                ImuEvent::Frame(frame, makeup) => {
+                    for command in rx_notification.try_iter() {
+                        match command {
+                            SerialNotification::ResetState => {
+                                state.reset();
+                            }
+                        }
                     }
-                }
-            }
 
-            state::update_state(&frame, &mut state, makeup);
+                    state::update_state(&frame, &mut state, makeup);
 
-            tx_data.send(state.clone()).unwrap();
+                    tx_data.send(state.clone()).unwrap();
+                }
+                ImuEvent::Connection(connection) => {
+                    tx_connection.send(ConnectionEvent(SerialLink::Imu, connection)).unwrap();
+                }
+            }
 
             Ok(())
-        })
+        }, port)
     }
 
-    pub(super) fn listen_to_controller(tx_state: Sender<MotorState>, rx_command: Receiver<DownstreamMessage>) -> anyhow::Result<!> {
+    pub(super) fn listen_to_controller(tx_state: Sender<MotorState>, commands: CommandQueue, tx_connection: Sender<ConnectionEvent>, port: PortSelector) -> anyhow::Result<!> {
         let mut state = MotorState::default();
 
-        serial::controller::listen(move |message| {
-            state::handle_message(&message, &mut state);
+        serial::controller::listen(move |event| {
+            match event {
+                ControllerEvent::Message(message) => {
+                    // The firmware (re)started, make sure it is configured the way we expect
+                    if let UpstreamMessage::Hello(_) = message {
+                        if let Err(error) = send_configuration(&commands) {
+                            eprintln!("Could not configure the controller: {}", error);
+                        }
+                    }
+
+                    state::handle_message(&message, &mut state);
+                }
+                ControllerEvent::LinkStats(stats) => {
+                    state.upstream_loss = stats.upstream_loss;
+                    state.downstream_loss = stats.downstream_loss;
+                    state.duplicates = stats.duplicates;
+                    state.reordered = stats.reordered;
+                    state.ack_latency = stats.average_latency.map(|latency| latency.as_nanos() as f64).unwrap_or_default();
+                }
+                ControllerEvent::Connection(connection) => {
+                    // Whatever answers next may be running different firmware
+                    if connection == ConnectionState::Lost {
+                        state.firmware = None;
+                    }
+
+                    tx_connection.send(ConnectionEvent(SerialLink::Controller, connection)).unwrap();
+                }
+                ControllerEvent::PingSent => {
+                    state::increment_ping();
+                }
+                ControllerEvent::Dropped(dropped) => {
+                    eprintln!("Dropped {} commands, the controller speaks another protocol: {:?}", dropped.len(), dropped);
+                }
+            }
 
             tx_state.send(state.clone()).unwrap();
 
             Ok(())
-        }, Some(rx_command))
+        }, Some(commands.clone()), port)
+    }
+
+    /// Sends our mixing matrix, slew rates and telemetry interval to the controller and asks for its calibration
+    fn send_configuration(commands: &CommandQueue) -> Result<(), QueueFull> {
+        for (thruster, weights) in MIXING.weights.iter().enumerate() {
+            commands.send(DownstreamMessage::SetMixing(thruster as u8, *weights))?;
+        }
+        commands.send(DownstreamMessage::SetSlewRate(SLEW_RATES))?;
+        commands.send(DownstreamMessage::SetTelemetryInterval(TELEMETRY_INTERVAL))?;
+        commands.send(DownstreamMessage::SetSensorThresholds(SENSOR_THRESHOLDS))?;
+        commands.send(DownstreamMessage::GetCalibration)
     }
 }
\ No newline at end of file
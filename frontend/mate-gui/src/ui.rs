@@ -3,9 +3,10 @@ use bevy::input::mouse::{MouseScrollUnit, MouseWheel};
 use bevy::prelude::*;
 use cv::{line_follower, take_image, dock, mosaic};
 use cv::line_follower::LineGoal;
-use crate::{CameraDisplay, ControllerData, EStopButton, EStopText, GoalDisplay, OpenCvTaskButton, ResetButton};
+use crate::{AlarmBanner, ArbitrationButton, ArmButton, CalibrationButton, CalibrationData, CalibrationMotorButton, CalibrationReloadButton, CameraDisplay, ControllerData, EStopButton, EStopText, GoalDisplay, LinkDisplay, OpenCvTaskButton, ResetButton, SerialLink};
 use crate::robot::RobotData;
 use cv::line_follower::Direction;
+use common::controller::ArbitrationMode;
 
 pub struct UiPlugin;
 
@@ -26,6 +27,7 @@ pub const CAMERA_BACKGROUND: Color = Color::rgb(0.3, 0.3, 0.3);
 pub const LEFT_PANEL_BACKGROUND: Color = Color::rgb(0.15, 0.15, 0.15);
 pub const LEFT_PANEL_BUTTON_BACKGROUND: Color = Color::rgb(0.4, 0.4, 0.4);
 pub const EMERGENCY_STOP_ACTIVE: Color = Color::rgb(1.0, 0.0, 0.0);
+pub const WARNING_TEXT: Color = Color::rgb(1.0, 0.6, 0.0);
 
 #[derive(Component)]
 pub struct InfoPanel;
@@ -137,18 +139,72 @@ fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
                     create_rect()
                 ).with_children(|parent| {
                     parent.spawn_bundle(create_text("Motor Speeds: ", 20.0, &asset_server));
-                    parent.spawn_bundle(create_text("Left: ", 15.0, &asset_server)).insert(ControllerData::SpeedSpForwardsLeft);
-                    parent.spawn_bundle(create_text("Right: ", 15.0, &asset_server)).insert(ControllerData::SpeedSpForwardsRight);
-                    parent.spawn_bundle(create_text("Strafing: ", 15.0, &asset_server)).insert(ControllerData::SpeedSpStrafing);
-                    parent.spawn_bundle(create_text("Vertical: ", 15.0, &asset_server)).insert(ControllerData::SpeedSpVertical);
+                    parent.spawn_bundle(create_text("Left: ", 15.0, &asset_server)).insert(ControllerData::Thruster(0));
+                    parent.spawn_bundle(create_text("Right: ", 15.0, &asset_server)).insert(ControllerData::Thruster(1));
+                    parent.spawn_bundle(create_text("Strafing: ", 15.0, &asset_server)).insert(ControllerData::Thruster(2));
+                    parent.spawn_bundle(create_text("Vertical: ", 15.0, &asset_server)).insert(ControllerData::Thruster(3));
+                });
+
+                parent.spawn_bundle(
+                    create_rect()
+                ).with_children(|parent| {
+                    parent.spawn_bundle(create_text("Calibration: ", 20.0, &asset_server));
+                    parent.spawn_bundle(create_text("Motor: ", 15.0, &asset_server)).insert(CalibrationData::Motor);
+                    parent.spawn_bundle(create_text("Inverted: ", 15.0, &asset_server)).insert(CalibrationData::Inverted);
+                    parent.spawn_bundle(create_text("Trim: ", 15.0, &asset_server)).insert(CalibrationData::Trim);
+                    parent.spawn_bundle(create_text("Max Output: ", 15.0, &asset_server)).insert(CalibrationData::MaxOutput);
+
+                    let buttons = [
+                        ("Invert", CalibrationButton::Invert),
+                        ("Trim +", CalibrationButton::Trim(0.01)),
+                        ("Trim -", CalibrationButton::Trim(-0.01)),
+                        ("Max Output +", CalibrationButton::MaxOutput(0.05)),
+                        ("Max Output -", CalibrationButton::MaxOutput(-0.05)),
+                    ];
+
+                    parent.spawn_bundle(
+                        create_button()
+                    ).with_children(|parent| {
+                        parent.spawn_bundle(create_text("Next Motor", 20.0, &asset_server));
+                    }).insert(CalibrationMotorButton);
+
+                    for (label, button) in buttons {
+                        parent.spawn_bundle(
+                            create_button()
+                        ).with_children(|parent| {
+                            parent.spawn_bundle(create_text(label, 20.0, &asset_server));
+                        }).insert(button);
+                    }
+
+                    parent.spawn_bundle(
+                        create_button()
+                    ).with_children(|parent| {
+                        parent.spawn_bundle(create_text("Reload", 20.0, &asset_server));
+                    }).insert(CalibrationReloadButton);
                 });
 
                 parent.spawn_bundle(
                     create_rect()
                 ).with_children(|parent| {
                     parent.spawn_bundle(create_text("Communication: ", 20.0, &asset_server));
+                    parent.spawn_bundle(create_text("Controller Link: ", 15.0, &asset_server)).insert(LinkDisplay(SerialLink::Controller));
+                    parent.spawn_bundle(create_text("IMU Link: ", 15.0, &asset_server)).insert(LinkDisplay(SerialLink::Imu));
                     parent.spawn_bundle(create_text("Avg Ping: ", 15.0, &asset_server)).insert(ControllerData::AveragePing);
                     parent.spawn_bundle(create_text("Last Ping: ", 15.0, &asset_server)).insert(ControllerData::LastPing);
+                    parent.spawn_bundle(create_text("Ack Latency: ", 15.0, &asset_server)).insert(ControllerData::AckLatency);
+                    parent.spawn_bundle(create_text("Packet Loss: ", 15.0, &asset_server)).insert(ControllerData::PacketLoss);
+                    parent.spawn_bundle(create_text("Bad Packets: ", 15.0, &asset_server)).insert(ControllerData::Errors);
+                    parent.spawn_bundle(create_text("Loop Time: ", 15.0, &asset_server)).insert(ControllerData::LoopTime);
+                    parent.spawn_bundle(create_text("E-Stop: ", 15.0, &asset_server)).insert(ControllerData::EStop);
+                    parent.spawn_bundle(create_text("Control: ", 15.0, &asset_server)).insert(ControllerData::Control);
+                    parent.spawn_bundle(create_text("Actuators: ", 15.0, &asset_server)).insert(ControllerData::Actuators);
+                    parent.spawn_bundle(create_text("Battery: ", 15.0, &asset_server)).insert(ControllerData::Battery);
+                    parent.spawn_bundle(create_text("Tether: ", 15.0, &asset_server)).insert(ControllerData::Tether);
+                    parent.spawn_bundle(create_text("Leak: ", 15.0, &asset_server)).insert(ControllerData::Leak);
+                    parent.spawn_bundle(create_text("Failsafe: ", 15.0, &asset_server)).insert(ControllerData::Failsafe);
+                    parent.spawn_bundle(create_text("Firmware: ", 15.0, &asset_server)).insert(ControllerData::Firmware);
+                    parent.spawn_bundle(create_text("Reset By: ", 15.0, &asset_server)).insert(ControllerData::ResetCause);
+                    parent.spawn_bundle(create_text("Last Panic: ", 15.0, &asset_server)).insert(ControllerData::LastPanic);
                 });
 
                 /*parent.spawn_bundle(
@@ -158,6 +214,27 @@ fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
                     parent.spawn_bundle(create_text("Psi: ", 15.0, &asset_server)).insert(RobotData::Pressure);
                 });*/
 
+                parent.spawn_bundle(
+                    create_rect()
+                ).with_children(|parent| {
+                    parent.spawn_bundle(create_text("Arbitration: ", 20.0, &asset_server));
+
+                    let modes = [
+                        ("PC Only", ArbitrationMode::PcOnly),
+                        ("Joystick Only", ArbitrationMode::JoystickOnly),
+                        ("Joystick Override", ArbitrationMode::JoystickOverride),
+                        ("Sum", ArbitrationMode::Sum),
+                    ];
+
+                    for (label, mode) in modes {
+                        parent.spawn_bundle(
+                            create_button()
+                        ).with_children(|parent| {
+                            parent.spawn_bundle(create_text(label, 20.0, &asset_server));
+                        }).insert(ArbitrationButton(mode));
+                    }
+                });
+
                 parent.spawn_bundle(
                     create_rect()
                 ).with_children(|parent| {
@@ -218,6 +295,12 @@ fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
                 ).with_children(|parent| {
                     parent.spawn_bundle(create_text("Emergency Stop", 20.0, &asset_server)).insert(EStopText);
                 }).insert(EStopButton);
+
+                parent.spawn_bundle(
+                    create_button()
+                ).with_children(|parent| {
+                    parent.spawn_bundle(create_text("Arm", 20.0, &asset_server));
+                }).insert(ArmButton);
             });
         });
 
@@ -262,6 +345,29 @@ fn setup_ui(mut commands: Commands, asset_server: Res<AssetServer>) {
                     },
                     ..default()
                 }).insert(CameraDisplay);
+
+                // alarms, empty while there are none
+                parent.spawn_bundle(TextBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        position: Rect {
+                            top: Val::Px(10.0),
+                            left: Val::Px(10.0),
+                            ..default()
+                        },
+                        ..default()
+                    },
+                    text: Text::with_section(
+                        "",
+                        TextStyle {
+                            font: asset_server.load("fonts/FiraMono-Medium.ttf"),
+                            font_size: 40.0,
+                            color: EMERGENCY_STOP_ACTIVE,
+                        },
+                        Default::default(),
+                    ),
+                    ..default()
+                }).insert(AlarmBanner);
             });
         });
     });
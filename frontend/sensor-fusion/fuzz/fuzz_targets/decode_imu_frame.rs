@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sensor_fusion::frame::decode_imu_frame;
+
+// Frames come from the imu over a noisy serial line
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_imu_frame(data);
+});
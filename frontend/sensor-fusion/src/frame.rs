@@ -109,3 +109,40 @@ fn raw_to_frame(accel_x: i16, accel_y: i16, accel_z: i16, gyro_x: i16, gyro_y: i
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use super::*;
+
+    /// Lay out readings the way the imu sends them, checksum and terminator included
+    fn encode(readings: &[i16], total_ms: u8) -> Vec<u8> {
+        let mut frame: Vec<u8> = readings.iter().flat_map(|reading| reading.to_le_bytes()).collect();
+        frame.push(total_ms);
+        frame.push(frame.iter().fold(0, |acc, &it| acc ^ it));
+        frame.push(0);
+        frame
+    }
+
+    proptest! {
+        #[test]
+        fn decodes_valid_frames(readings in prop::collection::vec(any::<i16>(), 10), with_mag in any::<bool>(), total_ms in any::<u8>()) {
+            let readings = if with_mag { &readings[..] } else { &readings[..7] };
+            let frame = decode_imu_frame(&encode(readings, total_ms)).unwrap();
+            prop_assert_eq!(frame.mag.is_some(), with_mag);
+            prop_assert_eq!(frame.total_duration, Duration::from_millis(total_ms as u64));
+        }
+
+        #[test]
+        fn rejects_corrupted_frames(readings in prop::collection::vec(any::<i16>(), 7), total_ms in any::<u8>(), bit in 0..8 * 15usize) {
+            let mut frame = encode(&readings, total_ms);
+            frame[bit / 8] ^= 1 << (bit % 8);
+            prop_assert!(decode_imu_frame(&frame).is_none());
+        }
+
+        #[test]
+        fn never_panics(data in prop::collection::vec(any::<u8>(), 0..64)) {
+            decode_imu_frame(&data);
+        }
+    }
+}
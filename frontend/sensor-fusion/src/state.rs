@@ -2,7 +2,11 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use std::time;
 use std::time::{Duration, SystemTime};
 use glam::*;
-use common::controller::{UpstreamMessage, VelocityData};
+use common::actuator::ActuatorData;
+use common::sensors::{Alarms, SensorData};
+use common::calibration::{MOTOR_COUNT, MotorCalibration};
+use common::controller::{ArbitrationMode, ControlSource, Counters, EStopState, LoopStats, PROTOCOL_VERSION, ResetCause, UpstreamMessage, VelocityData};
+use common::mixing::ThrusterData;
 use crate::frame::IMUFrame;
 use crate::fusion::*;
 
@@ -27,10 +31,48 @@ pub struct RobotState {
 
 #[derive(Clone, Debug, Default)]
 pub struct MotorState {
-    pub total_velocity: VelocityData,
-    pub emergency_stop: bool,
+    pub firmware: Option<FirmwareInfo>,
+    /// Where the firmware panicked before its last reset
+    pub last_panic: Option<String>,
+    pub total_velocity: ThrusterData,
+    pub emergency_stop: EStopState,
+    pub failsafe: bool,
+    /// Average duration of a firmware loop iteration (us)
+    pub loop_time: LoopStats,
+    pub pc_setpoint: VelocityData,
+    pub joystick_setpoint: VelocityData,
+    pub arbitration: ArbitrationMode,
+    pub control_source: ControlSource,
+    pub actuators: ActuatorData,
+    pub sensors: SensorData,
+    pub alarms: Alarms,
+    pub counters: Counters,
+    /// Last calibration the firmware reported for each motor
+    pub calibration: [Option<MotorCalibration>; MOTOR_COUNT],
     pub average_ping: f64,
     pub last_ping: f64,
+
+    pub upstream_loss: f64,
+    pub downstream_loss: f64,
+    pub duplicates: u64,
+    pub reordered: u64,
+    pub ack_latency: f64,
+}
+
+/// What the firmware told us about itself in its hello
+#[derive(Clone, Debug)]
+pub struct FirmwareInfo {
+    pub build_id: String,
+    pub protocol_version: u16,
+    pub capabilities: u32,
+    pub reset_cause: ResetCause,
+}
+
+impl FirmwareInfo {
+    /// Does the firmware speak the same protocol as us
+    pub fn compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
 }
 
 impl RobotState {
@@ -49,10 +91,19 @@ pub fn handle_message(message: &UpstreamMessage, state: &mut MotorState) {
         UpstreamMessage::Log(msg) => {
             println!("Arduino logged: {}", msg)
         }
-        UpstreamMessage::Init => {
-            println!("Arduino init")
+        UpstreamMessage::Hello(hello) => {
+            println!("Arduino init, firmware {} protocol {}, reset by {}", hello.build_id, hello.protocol_version, hello.reset_cause);
+
+            state.firmware = Some(FirmwareInfo {
+                build_id: hello.build_id.to_owned(),
+                protocol_version: hello.protocol_version,
+                capabilities: hello.capabilities,
+                reset_cause: hello.reset_cause
+            });
+            // A panic is reported after every hello, so forget the last one until we hear otherwise
+            state.last_panic = None;
         }
-        UpstreamMessage::Ack => {
+        UpstreamMessage::Ack(_seq) => {
             //println!("ack");
         }
         UpstreamMessage::BadP(com_error) => {
@@ -61,11 +112,29 @@ pub fn handle_message(message: &UpstreamMessage, state: &mut MotorState) {
         UpstreamMessage::BadO => {
             println!("bado");
         }
-        UpstreamMessage::EStop(emergency_stop) => {
-            state.emergency_stop = *emergency_stop;
+        UpstreamMessage::Panic(report) => {
+            println!("Arduino panicked before its last reset at {}:{}:{}", report.file, report.line, report.column);
+
+            state.last_panic = Some(format!("{}:{}:{}", report.file, report.line, report.column));
+        }
+        UpstreamMessage::Calibration(motor, calibration) => {
+            if let Some(slot) = state.calibration.get_mut(*motor as usize) {
+                *slot = Some(*calibration);
+            }
         }
-        UpstreamMessage::TotalVelocity(velocity) => {
-            state.total_velocity = velocity.clone();
+        UpstreamMessage::Telemetry(telemetry) => {
+            state.total_velocity = telemetry.thrusters;
+            state.emergency_stop = telemetry.emergency_stop;
+            state.failsafe = telemetry.failsafe;
+            state.loop_time = telemetry.loop_time;
+            state.pc_setpoint = telemetry.pc_setpoint.clone();
+            state.joystick_setpoint = telemetry.joystick_setpoint.clone();
+            state.arbitration = telemetry.arbitration;
+            state.control_source = telemetry.control_source;
+            state.actuators = telemetry.actuators;
+            state.sensors = telemetry.sensors;
+            state.alarms = telemetry.alarms;
+            state.counters = telemetry.counters;
         }
         UpstreamMessage::Pong => {
             let tx_time = OUT_TIME.load(Ordering::Acquire);
@@ -0,0 +1,204 @@
+//! Commands waiting to be sent to the controller, sorted into lanes by how urgent they are
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use common::controller::DownstreamMessage;
+use mio::Waker;
+
+/// How many housekeeping commands may wait before new ones are refused
+pub const HOUSEKEEPING_CAPACITY: usize = 64;
+
+/// How urgent a command is, lanes are drained in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Sent as soon as the port accepts it, skipping the write rate limit, never refused
+    Safety,
+    /// Setpoints, never refused
+    Control,
+    /// Configuration and diagnostics
+    Housekeeping,
+}
+
+impl Priority {
+    pub fn of(command: &DownstreamMessage) -> Self {
+        match command {
+            DownstreamMessage::EmergencyStop | DownstreamMessage::Arm => Priority::Safety,
+            DownstreamMessage::VelocityUpdate(_) | DownstreamMessage::SetActuator(..) | DownstreamMessage::SetArbitration(_) => Priority::Control,
+            _ => Priority::Housekeeping,
+        }
+    }
+}
+
+/// Does `new` make a waiting `old` pointless to send, only the latest setpoint matters
+pub(crate) fn supersedes(new: &DownstreamMessage, old: &DownstreamMessage) -> bool {
+    match (new, old) {
+        (DownstreamMessage::VelocityUpdate(_), DownstreamMessage::VelocityUpdate(_)) => true,
+        (DownstreamMessage::SetActuator(new, _), DownstreamMessage::SetActuator(old, _)) => new == old,
+        (DownstreamMessage::SetArbitration(_), DownstreamMessage::SetArbitration(_)) => true,
+        // Only one ping needs to be in flight, it is timed once written
+        (DownstreamMessage::Ping, DownstreamMessage::Ping) => true,
+        _ => false,
+    }
+}
+
+/// The housekeeping lane was full, the command was not queued
+#[derive(Debug)]
+pub struct QueueFull(pub DownstreamMessage);
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "command queue full, dropped {:?}", self.0)
+    }
+}
+
+impl std::error::Error for QueueFull {}
+
+#[derive(Default)]
+struct Lanes {
+    safety: VecDeque<DownstreamMessage>,
+    control: VecDeque<DownstreamMessage>,
+    housekeeping: VecDeque<DownstreamMessage>,
+    // Wakes the listener when a safety command arrives
+    waker: Option<Waker>,
+}
+
+impl Lanes {
+    fn lane(&mut self, priority: Priority) -> &mut VecDeque<DownstreamMessage> {
+        match priority {
+            Priority::Safety => &mut self.safety,
+            Priority::Control => &mut self.control,
+            Priority::Housekeeping => &mut self.housekeeping,
+        }
+    }
+}
+
+/// Commands for `controller::listen`, shared between every thread that sends them
+///
+/// Sending never blocks. Commands of one priority go out in the order they were sent,
+/// except that a setpoint replaces a waiting one of the same kind.
+#[derive(Clone, Default)]
+pub struct CommandQueue {
+    lanes: Arc<Mutex<Lanes>>,
+}
+
+impl CommandQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a command, only housekeeping commands can be refused
+    pub fn send(&self, command: DownstreamMessage) -> Result<(), QueueFull> {
+        let priority = Priority::of(&command);
+        let mut lanes = self.lanes.lock().unwrap();
+        let lane = lanes.lane(priority);
+
+        if let Some(waiting) = lane.iter_mut().find(|waiting| supersedes(&command, waiting)) {
+            *waiting = command;
+        } else if priority == Priority::Housekeeping && lane.len() >= HOUSEKEEPING_CAPACITY {
+            return Err(QueueFull(command));
+        } else {
+            lane.push_back(command);
+        }
+
+        if priority == Priority::Safety {
+            if let Some(waker) = &lanes.waker {
+                let _ = waker.wake();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take the most urgent waiting command, ignoring anything less urgent than `lowest`
+    pub(crate) fn pop(&self, lowest: Priority) -> Option<DownstreamMessage> {
+        let mut lanes = self.lanes.lock().unwrap();
+        [Priority::Safety, Priority::Control, Priority::Housekeeping].into_iter()
+            .filter(|&priority| priority <= lowest)
+            .find_map(|priority| lanes.lane(priority).pop_front())
+    }
+
+    /// Drop every waiting command less urgent than `keep`, returns what was dropped
+    pub(crate) fn clear(&self, keep: Priority) -> Vec<DownstreamMessage> {
+        let mut lanes = self.lanes.lock().unwrap();
+        [Priority::Safety, Priority::Control, Priority::Housekeeping].into_iter()
+            .filter(|&priority| priority > keep)
+            .flat_map(|priority| lanes.lane(priority).drain(..).collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Wake this poll whenever a safety command is sent
+    pub(crate) fn set_waker(&self, waker: Waker) {
+        self.lanes.lock().unwrap().waker = Some(waker);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::actuator::Actuator;
+    use common::controller::VelocityData;
+    use super::*;
+
+    fn velocity(surge: f32) -> DownstreamMessage {
+        DownstreamMessage::VelocityUpdate(VelocityData { surge, ..Default::default() })
+    }
+
+    fn drain(queue: &CommandQueue) -> Vec<DownstreamMessage> {
+        std::iter::from_fn(|| queue.pop(Priority::Housekeeping)).collect()
+    }
+
+    #[test]
+    fn emergency_stop_jumps_the_queue() {
+        let queue = CommandQueue::new();
+        queue.send(DownstreamMessage::GetCalibration).unwrap();
+        queue.send(velocity(1.0)).unwrap();
+        queue.send(DownstreamMessage::EmergencyStop).unwrap();
+
+        assert_eq!(queue.pop(Priority::Safety), Some(DownstreamMessage::EmergencyStop));
+        assert_eq!(queue.pop(Priority::Safety), None);
+        assert_eq!(drain(&queue), [velocity(1.0), DownstreamMessage::GetCalibration]);
+    }
+
+    #[test]
+    fn setpoints_are_coalesced() {
+        let queue = CommandQueue::new();
+        queue.send(velocity(1.0)).unwrap();
+        queue.send(DownstreamMessage::SetActuator(Actuator::Claw, 0.0)).unwrap();
+        queue.send(DownstreamMessage::SetActuator(Actuator::Lights, 1.0)).unwrap();
+        queue.send(velocity(2.0)).unwrap();
+        queue.send(DownstreamMessage::SetActuator(Actuator::Claw, 0.5)).unwrap();
+
+        assert_eq!(drain(&queue), [
+            velocity(2.0),
+            DownstreamMessage::SetActuator(Actuator::Claw, 0.5),
+            DownstreamMessage::SetActuator(Actuator::Lights, 1.0),
+        ]);
+    }
+
+    #[test]
+    fn safety_commands_are_never_refused() {
+        let queue = CommandQueue::new();
+        for _ in 0..HOUSEKEEPING_CAPACITY {
+            queue.send(DownstreamMessage::GetCalibration).unwrap();
+        }
+
+        assert!(queue.send(DownstreamMessage::GetCalibration).is_err());
+        queue.send(DownstreamMessage::EmergencyStop).unwrap();
+        queue.send(DownstreamMessage::Arm).unwrap();
+        queue.send(DownstreamMessage::EmergencyStop).unwrap();
+
+        let safety: Vec<_> = std::iter::from_fn(|| queue.pop(Priority::Safety)).collect();
+        assert_eq!(safety, [DownstreamMessage::EmergencyStop, DownstreamMessage::Arm, DownstreamMessage::EmergencyStop]);
+    }
+
+    #[test]
+    fn clearing_keeps_urgent_commands() {
+        let queue = CommandQueue::new();
+        queue.send(DownstreamMessage::GetCalibration).unwrap();
+        queue.send(velocity(1.0)).unwrap();
+        queue.send(DownstreamMessage::EmergencyStop).unwrap();
+
+        assert_eq!(queue.clear(Priority::Safety), [velocity(1.0), DownstreamMessage::GetCalibration]);
+        assert_eq!(drain(&queue), [DownstreamMessage::EmergencyStop]);
+    }
+}
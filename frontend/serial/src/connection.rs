@@ -0,0 +1,190 @@
+//! Keeps a serial link up, finding and reopening its port whenever it drops
+
+use std::fmt;
+use std::thread;
+use std::time::Duration;
+
+/// Where a link is in its lifecycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Looking for the device's port
+    Searching,
+    /// Opening the port
+    Connecting,
+    /// Waiting for the device to identify itself
+    Handshaking,
+    /// Exchanging data
+    Live,
+    /// The link dropped, it is searched for again after a backoff
+    Lost,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ConnectionState::Searching => "Searching",
+            ConnectionState::Connecting => "Connecting",
+            ConnectionState::Handshaking => "Handshaking",
+            ConnectionState::Live => "Live",
+            ConnectionState::Lost => "Lost",
+        })
+    }
+}
+
+/// Delay between attempts to bring a link up, doubles after every attempt that doesn't go live
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    next: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Backoff { initial, max, next: initial }
+    }
+
+    /// How long to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(self.max);
+        delay
+    }
+
+    /// The link worked, retry quickly the next time it drops
+    pub fn reset(&mut self) {
+        self.next = self.initial;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(Duration::from_millis(250), Duration::from_secs(5))
+    }
+}
+
+/// The state of a link run by `maintain`
+#[derive(Debug)]
+pub struct Connection {
+    state: ConnectionState,
+}
+
+impl Default for Connection {
+    /// A port that is about to be opened, for running a session without `maintain`
+    fn default() -> Self {
+        Connection { state: ConnectionState::Connecting }
+    }
+}
+
+impl Connection {
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    /// Move to `state`, returns it if it changed so it can be reported
+    pub fn enter(&mut self, state: ConnectionState) -> Option<ConnectionState> {
+        if self.state != state {
+            self.state = state;
+            Some(state)
+        } else {
+            None
+        }
+    }
+}
+
+/// Keep a link up forever
+///
+/// `session` talks over the port `find_port` picks until the link fails, moving the connection to `Handshaking` and `Live` as it comes up.
+/// Every state change is passed to `report` along with `context`, only errors from `report` end this.
+pub fn maintain<C, R, P, S>(context: &mut C, mut report: R, mut find_port: P, mut session: S) -> anyhow::Result<!>
+    where
+        R: FnMut(&mut C, ConnectionState) -> anyhow::Result<()>,
+        P: FnMut() -> anyhow::Result<Option<String>>,
+        S: FnMut(&mut C, &str, &mut Connection) -> anyhow::Result<!>,
+{
+    let mut backoff = Backoff::default();
+    let mut connection = Connection { state: ConnectionState::Lost };
+
+    loop {
+        if let Some(state) = connection.enter(ConnectionState::Searching) {
+            report(context, state)?;
+        }
+
+        match find_port() {
+            Ok(Some(port)) => {
+                println!("Selected port {}", port);
+                if let Some(state) = connection.enter(ConnectionState::Connecting) {
+                    report(context, state)?;
+                }
+
+                let Err(error) = session(context, &port, &mut connection);
+                eprintln!("Lost {}: {:?}", port, error);
+
+                if connection.state() == ConnectionState::Live {
+                    backoff.reset();
+                }
+                if let Some(state) = connection.enter(ConnectionState::Lost) {
+                    report(context, state)?;
+                }
+            }
+            Ok(None) => {}
+            Err(error) => {
+                eprintln!("Could not list ports: {:?}", error);
+            }
+        }
+
+        thread::sleep(backoff.next_delay());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(300));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(200));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(300));
+        assert_eq!(backoff.next_delay(), Duration::from_millis(300));
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn reconnects_after_losing_the_link() {
+        let mut states = Vec::new();
+        let mut searches = 0;
+
+        let result = maintain(
+            &mut states,
+            |states, state| {
+                states.push(state);
+                // Stop once the link dropped a second time
+                if states.iter().filter(|&&state| state == ConnectionState::Lost).count() == 2 {
+                    bail!("done");
+                }
+                Ok(())
+            },
+            || {
+                searches += 1;
+                // The device is unplugged for the second search
+                Ok((searches != 2).then(|| "port".to_owned()))
+            },
+            |states, port, connection| {
+                assert_eq!(port, "port");
+                for state in [ConnectionState::Handshaking, ConnectionState::Live] {
+                    states.extend(connection.enter(state));
+                }
+                bail!("unplugged")
+            },
+        );
+
+        assert!(result.is_err());
+        use ConnectionState::*;
+        assert_eq!(states, [Searching, Connecting, Handshaking, Live, Lost, Searching, Connecting, Handshaking, Live, Lost]);
+    }
+}
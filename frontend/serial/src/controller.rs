@@ -1,38 +1,54 @@
-use common::controller::{DownstreamMessage, UpstreamMessage};
+use common::codec::{FrameDecoder, FrameEncoder};
+use common::controller::{DownstreamMessage, Hello, PROTOCOL_VERSION, UpstreamMessage};
 use std::time::{Duration, Instant};
-use mio_serial::{ClearBuffer, SerialPort, SerialPortInfo, SerialPortType};
-use std::{io, thread};
+use mio_serial::{ClearBuffer, SerialPort};
+use std::{io, iter, mem};
 use std::io::{Read, Write};
-use std::sync::atomic::Ordering;
 use anyhow::{bail, Context};
-use crossbeam::channel::Receiver;
-use mio::{Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Token, Waker};
 use mio_serial::{SerialPortBuilderExt, SerialStream};
+use crate::command::{CommandQueue, Priority};
+use crate::connection;
+use crate::connection::{Connection, ConnectionState};
+use crate::port::PortSelector;
+use crate::sequence::{Arrival, Link, LinkStats};
 
-fn get_port() -> anyhow::Result<Option<SerialPortInfo>> {
-    Ok(mio_serial::available_ports()?
-        .into_iter()
-        .find(|port| {
-            match &port.port_type {
-                SerialPortType::UsbPort(info) => { info.vid == 0x2341 && info.pid == 0x42 }
-                _ => { false }
-            }
-        }))
+/// Everything the controller listener reports back
+#[derive(Debug)]
+pub enum ControllerEvent<'a> {
+    Message(UpstreamMessage<'a>),
+    LinkStats(LinkStats),
+    Connection(ConnectionState),
+    /// Commands thrown away because the firmware speaks another protocol, safety commands stay queued
+    Dropped(Vec<DownstreamMessage>),
+    /// A ping was written to the port, pings waiting in the queue are merged so time the round trip from here
+    PingSent,
 }
 
-pub fn listen<F: FnMut(UpstreamMessage) -> anyhow::Result<()> + Send + 'static>(data_callback: F, commands: Option<Receiver<DownstreamMessage>>) -> anyhow::Result<!> {
-    if let Some(port) = get_port()? {
-        println!("Selected port {}", port.port_name);
-        listen_to_port(&port.port_name, data_callback, commands)
-    } else {
-        bail!("No suitable serial port found")
-    }
-
+/// Talk to the controller, reconnecting whenever the link drops
+/// Commands queued while the link is down are sent once it is live again
+pub fn listen<F: FnMut(ControllerEvent) -> anyhow::Result<()> + Send + 'static>(mut data_callback: F, commands: Option<CommandQueue>, port: PortSelector) -> anyhow::Result<!> {
+    connection::maintain(
+        &mut data_callback,
+        |data_callback, state| (data_callback)(ControllerEvent::Connection(state)),
+        || Ok(port.find()?.map(|port| port.port_name)),
+        |data_callback, port, connection| listen_to_port(port, data_callback, commands.as_ref(), connection),
+    )
 }
 
 const SERIAL_TOKEN: Token = Token(0);
+const WAKER_TOKEN: Token = Token(1);
+
+/// Progress of the protocol version handshake with the firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Handshake {
+    Pending,
+    Agreed,
+    Mismatch(u16),
+}
 
-pub fn listen_to_port<F: FnMut(UpstreamMessage) -> anyhow::Result<()> + Send + 'static>(port: &str, mut data_callback: F, commands: Option<Receiver<DownstreamMessage>>) -> anyhow::Result<!> {
+/// Talk to the controller on `port` until the link fails
+pub fn listen_to_port<F: FnMut(ControllerEvent) -> anyhow::Result<()>>(port: &str, data_callback: &mut F, commands: Option<&CommandQueue>, connection: &mut Connection) -> anyhow::Result<!> {
     let mut poll = Poll::new().context("could not create poll")?;
     let mut events = Events::with_capacity(10);
 
@@ -44,83 +60,123 @@ pub fn listen_to_port<F: FnMut(UpstreamMessage) -> anyhow::Result<()> + Send + '
         .register(&mut port, SERIAL_TOKEN, Interest::READABLE | Interest::WRITABLE)
         .context("could not register port")?;
 
-    let mut buf_read = [0; 4098];
-    let mut last_end = 0;
-    let mut buf_write = [0; 4098];
-    let mut buf_partial = [0; 4098];
-    let mut partial_written = 0;
+    if let Some(commands) = commands {
+        commands.set_waker(Waker::new(poll.registry(), WAKER_TOKEN).context("could not create waker")?);
+    }
+
+    if let Some(state) = connection.enter(ConnectionState::Handshaking) {
+        (data_callback)(ControllerEvent::Connection(state))?;
+    }
+
+    let mut decoder = FrameDecoder::default();
+    let mut writer = Writer::default();
     let mut writeable = false;
-    let mut last_write = Instant::now();
-    let mut earlist_write = None;
+    let opened = Instant::now();
+    // When the firmware last sent a valid frame, we only write once it is running
+    let mut last_heard = None;
+    let mut handshake = Handshake::Pending;
+    let mut link = Link::default();
+    let mut last_stats = Instant::now();
 
     loop {
-        // Fixme better way to wake up this thread?
+        // Safety commands wake the poll, everything else waits for the timeout
         poll.poll(&mut events, Some(MIN_WRITE_DELAY)).context("could not poll")?;
 
         for event in &events {
-            match event.token() {
-                SERIAL_TOKEN => {
-                    if event.is_readable() {
-                        do_read(&mut buf_read, &mut last_end, &mut data_callback, &mut port, &mut earlist_write).context("Read error")?;
-                    }
-                    if let Some(ref commands) = commands {
-                        if event.is_writable() {
-                            if earlist_write.map(|time| time < Instant::now()).unwrap_or(false)  {
-                                writeable = do_write(&mut buf_write, &mut buf_partial, &mut partial_written, commands, &mut port, &mut last_write).context("Write error")?;
-                            } else {
-                                writeable = true;
-                            }
+            if event.token() == SERIAL_TOKEN {
+                if event.is_readable() {
+                    do_read(&mut decoder, data_callback, &mut port, &mut last_heard, &mut handshake, &mut link, connection).context("Read error")?;
+                }
+                if let Some(commands) = commands {
+                    if event.is_writable() {
+                        if last_heard.is_some() {
+                            writeable = do_write(&mut writer, commands, &mut port, handshake, &mut link).context("Write error")?;
+                        } else {
+                            writeable = true;
                         }
                     }
-                },
-                _ => {}
+                }
             }
         }
 
-        if let Some(ref commands) = commands {
-            if writeable && earlist_write.map(|time| time < Instant::now()).unwrap_or(false)  {
-                writeable = do_write(&mut buf_write, &mut buf_partial, &mut partial_written, commands, &mut port, &mut last_write).context("Write error")?;
+        if let Some(commands) = commands {
+            // Firmware speaking another protocol would misinterpret our commands, so drop them
+            // Until we know, keep them queued for when the link goes live
+            if let Handshake::Mismatch(_) = handshake {
+                let dropped = commands.clear(Priority::Safety);
+                if !dropped.is_empty() {
+                    (data_callback)(ControllerEvent::Dropped(dropped))?;
+                }
+            }
+
+            if writeable && last_heard.is_some() {
+                writeable = do_write(&mut writer, commands, &mut port, handshake, &mut link).context("Write error")?;
             }
         }
+
+        if mem::take(&mut writer.pinged) {
+            (data_callback)(ControllerEvent::PingSent)?;
+        }
+
+        // A bumped cable can leave the port open but silent
+        let timeout = if connection.state() == ConnectionState::Live { SILENCE_TIMEOUT } else { HANDSHAKE_TIMEOUT };
+        if last_heard.unwrap_or(opened).elapsed() > timeout {
+            bail!("Controller went silent");
+        }
+
+        if last_stats.elapsed() > STATS_INTERVAL {
+            (data_callback)(ControllerEvent::LinkStats(link.stats()))?;
+            last_stats = Instant::now();
+        }
     }
 }
 
-fn do_read<F: FnMut(UpstreamMessage) -> anyhow::Result<()>>(buffer: &mut [u8], last_end: &mut usize, data_callback: &mut F, port: &mut SerialStream, earlist_write: &mut Option<Instant>) -> anyhow::Result<()> {
+fn do_read<F: FnMut(ControllerEvent) -> anyhow::Result<()>>(decoder: &mut FrameDecoder, data_callback: &mut F, port: &mut SerialStream, last_heard: &mut Option<Instant>, handshake: &mut Handshake, link: &mut Link, connection: &mut Connection) -> anyhow::Result<()> {
+    let mut buffer = [0; 1024];
     loop {
-        assert!(buffer[*last_end..].len() > 0, "Read buffer full");
-
-        match port.read(&mut buffer[*last_end..]) {
+        match port.read(&mut buffer) {
             Ok(0) => {
                 bail!("Remote device was disconnected");
             }
             Ok(read) => {
-                let available = read + *last_end;
-                let frames = buffer[..available]
-                    .split_inclusive_mut(common::end_of_frame);
-
-                let mut remaining = 0;
-                for frame in frames {
-                    if common::end_of_frame(frame.last().unwrap()) {
-                        match common::read(frame) {
-                            Ok(message) => {
-                                (data_callback)(message)?;
-
-                                if earlist_write.is_none() {
-                                    *earlist_write = Some(Instant::now() + Duration::from_secs(7));
+                decoder.push(&buffer[..read]);
+
+                while let Some(result) = decoder.next::<UpstreamMessage>() {
+                    match result {
+                        Ok(envelope) => {
+                            let message = envelope.message;
+
+                            *last_heard = Some(Instant::now());
+
+                            if let UpstreamMessage::Hello(hello) = &message {
+                                *handshake = check_hello(hello, *handshake);
+
+                                // The firmware restarted, so did its sequence numbers
+                                link.incoming.reset();
+
+                                let state = if *handshake == Handshake::Agreed { ConnectionState::Live } else { ConnectionState::Handshaking };
+                                if let Some(state) = connection.enter(state) {
+                                    (data_callback)(ControllerEvent::Connection(state))?;
                                 }
+                            } else if *handshake != Handshake::Agreed {
+                                // We can't trust the decoding of anything else until we know the firmware speaks our protocol
+                                continue;
                             }
-                            Err(com_error) => {
-                                println!("read error: {:?}", com_error);
+
+                            if link.incoming.record(envelope.seq) == Arrival::Duplicate {
+                                continue;
+                            }
+                            if let UpstreamMessage::Ack(seq) = message {
+                                link.outgoing.acked(seq);
                             }
+
+                            (data_callback)(ControllerEvent::Message(message))?;
+                        }
+                        Err(error) => {
+                            println!("read error: {}", error);
                         }
-                    } else {
-                        remaining = frame.len();
-                        break;
                     }
                 }
-
-                buffer.copy_within(available - remaining..available, 0);
-                *last_end = remaining;
             }
             Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
                 continue;
@@ -137,27 +193,81 @@ fn do_read<F: FnMut(UpstreamMessage) -> anyhow::Result<()>>(buffer: &mut [u8], l
     Ok(())
 }
 
+fn check_hello(hello: &Hello, previous: Handshake) -> Handshake {
+    let handshake = if hello.protocol_version == PROTOCOL_VERSION {
+        Handshake::Agreed
+    } else {
+        Handshake::Mismatch(hello.protocol_version)
+    };
+
+    if handshake != previous {
+        match handshake {
+            Handshake::Agreed => {
+                println!("Firmware {} speaks protocol {}", hello.build_id, hello.protocol_version);
+            }
+            Handshake::Mismatch(version) => {
+                println!("Firmware {} speaks protocol {}, expected {}. Commands will not be sent", hello.build_id, version, PROTOCOL_VERSION);
+            }
+            Handshake::Pending => {}
+        }
+    }
+
+    handshake
+}
+
 const MIN_WRITE_DELAY: Duration = Duration::from_millis(2);
+/// How long a live link may go without a valid frame, the firmware sends telemetry far more often
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long the firmware may take to start and identify itself, it resets when the port is opened
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 const MAX_COMMANDS: usize = 2;
+pub(crate) const HELLO_INTERVAL: Duration = Duration::from_millis(500);
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
 
-fn do_write(buffer: &mut [u8], buf_partial: &mut [u8], partial_written: &mut usize, command_stream: &Receiver<DownstreamMessage>, port: &mut SerialStream, last_write: &mut Instant) -> anyhow::Result<bool> {
-    if *partial_written > 0 {
-        let mut buffer = &buf_partial[..*partial_written];
-        while !buffer.is_empty() {
-            match port.write(buffer) {
+/// Buffers and timers used to write commands to the controller
+struct Writer {
+    encoder: FrameEncoder,
+    // Bytes the port didn't accept yet
+    partial: Vec<u8>,
+    last_write: Instant,
+    last_hello: Instant,
+    // A ping was written since this was last checked
+    pinged: bool,
+    // The end of `partial` is a ping
+    partial_ping: bool,
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Writer {
+            encoder: FrameEncoder::default(),
+            partial: Vec::new(),
+            last_write: Instant::now(),
+            last_hello: Instant::now(),
+            pinged: false,
+            partial_ping: false,
+        }
+    }
+}
+
+fn do_write(writer: &mut Writer, commands: &CommandQueue, port: &mut SerialStream, handshake: Handshake, link: &mut Link) -> anyhow::Result<bool> {
+    let Writer { encoder, partial, last_write, last_hello, pinged, partial_ping } = writer;
+
+    if !partial.is_empty() {
+        let mut written = 0;
+        while written < partial.len() {
+            match port.write(&partial[written..]) {
                 Ok(0) => {
                     bail!("Failed to write buffer");
                 }
                 Ok(n) => {
-                    buffer = &buffer[n..];
+                    written += n;
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
                     continue;
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    let remaining = buffer.len();
-                    buf_partial.copy_within(*partial_written-remaining..*partial_written, 0);
-                    *partial_written = remaining;
+                    partial.drain(..written);
                     return Ok(false);
                 }
                 Err(e) => {
@@ -165,39 +275,35 @@ fn do_write(buffer: &mut [u8], buf_partial: &mut [u8], partial_written: &mut usi
                 }
             }
         }
-        *partial_written = 0;
+        partial.clear();
+        *pinged |= mem::take(partial_ping);
+    }
+
+    if handshake != Handshake::Agreed {
+        if last_hello.elapsed() > HELLO_INTERVAL {
+            *last_hello = Instant::now();
+            return write_message(&DownstreamMessage::Hello(PROTOCOL_VERSION), encoder, partial, port, link);
+        }
+
+        return Ok(true);
+    }
+
+    // Safety commands aren't rate limited so they can't wait behind anything
+    while let Some(command) = commands.pop(Priority::Safety) {
+        if !write_message(&command, encoder, partial, port, link)? {
+            return Ok(false);
+        }
     }
 
     if last_write.elapsed() > MIN_WRITE_DELAY {
-        for command in command_stream.try_iter().take(MAX_COMMANDS) {
-            if let Ok(mut buffer) = common::write(&command, buffer) {
-                while !buffer.is_empty() {
-                    match port.write(buffer) {
-                        Ok(0) => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::WriteZero,
-                                "failed to write whole buffer",
-                            )).context("Write zero");
-                        }
-                        Ok(n) => {
-                            buffer = &mut buffer[n..];
-                        }
-                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
-                            continue;
-                        }
-                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                            let additional = buffer.len();
-                            assert!(additional < buf_partial.len() - *partial_written, "partial write buffer is full");
-                            buf_partial[*partial_written..*partial_written+additional].copy_from_slice(buffer);
-                            *partial_written += additional;
-                            return Ok(false);
-                        }
-                        Err(e) => {
-                            return Err(e).context("Io error");
-                        }
-                    }
-                }
+        for command in iter::from_fn(|| commands.pop(Priority::Housekeeping)).take(MAX_COMMANDS) {
+            let ping = command == DownstreamMessage::Ping;
+            if !write_message(&command, encoder, partial, port, link)? {
+                // A partly written ping counts as sent once the rest of it is written
+                *partial_ping = ping;
+                return Ok(false);
             }
+            *pinged |= ping;
         }
 
         *last_write = Instant::now();
@@ -205,3 +311,35 @@ fn do_write(buffer: &mut [u8], buf_partial: &mut [u8], partial_written: &mut usi
 
     Ok(true)
 }
+
+/// Encodes and writes a message, anything that couldn't be written yet is stored in `partial`
+/// Returns false if the port stopped accepting data
+fn write_message(message: &DownstreamMessage, encoder: &mut FrameEncoder, partial: &mut Vec<u8>, port: &mut SerialStream, link: &mut Link) -> anyhow::Result<bool> {
+    if let Ok(mut buffer) = encoder.encode(link.outgoing.next_seq(), message) {
+        while !buffer.is_empty() {
+            match port.write(buffer) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    )).context("Write zero");
+                }
+                Ok(n) => {
+                    buffer = &buffer[n..];
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    partial.extend_from_slice(buffer);
+                    return Ok(false);
+                }
+                Err(e) => {
+                    return Err(e).context("Io error");
+                }
+            }
+        }
+    }
+
+    Ok(true)
+}
@@ -1,39 +1,44 @@
-use serialport::{ClearBuffer, SerialPort, SerialPortInfo, SerialPortType};
+use serialport::{ClearBuffer, SerialPort};
 use std::io;
 use std::io::Read;
-use std::time::Duration;
-use anyhow::bail;
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context};
 use sensor_fusion::frame::IMUFrame;
 use sensor_fusion::frame;
+use crate::connection;
+use crate::connection::{Connection, ConnectionState};
+use crate::port::PortSelector;
 
-fn get_port() -> anyhow::Result<Option<SerialPortInfo>> {
-    Ok(serialport::available_ports()?
-        .into_iter()
-        .find(|port| {
-            match &port.port_type {
-                SerialPortType::UsbPort(info) => { info.vid == 0x2341 && info.pid == 0x43 }
-                _ => { false }
-            }
-        }))
+/// Everything the imu listener reports back
+#[derive(Debug)]
+pub enum ImuEvent {
+    /// A decoded frame and how many invalid frames came before it
+    Frame(IMUFrame, u32),
+    Connection(ConnectionState),
 }
 
-pub fn listen<F: FnMut(IMUFrame, u32) -> anyhow::Result<()>>(imu_notification: F) -> anyhow::Result<!> {
-    if let Some(port) = get_port()? {
-        println!("Selected port {}", port.port_name);
-        listen_to_port(&port.port_name, imu_notification)
-    } else {
-        bail!("No suitable serial port found")
-    }
+/// How long the imu may go without a valid frame before the link is considered lost
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// Listen to the imu, reconnecting whenever the link drops
+pub fn listen<F: FnMut(ImuEvent) -> anyhow::Result<()>>(mut imu_notification: F, port: PortSelector) -> anyhow::Result<!> {
+    connection::maintain(
+        &mut imu_notification,
+        |imu_notification, state| (imu_notification)(ImuEvent::Connection(state)),
+        || Ok(port.find()?.map(|port| port.port_name)),
+        |imu_notification, port, connection| listen_to_port(port, imu_notification, connection),
+    )
 }
 
-pub fn listen_to_port<F: FnMut(IMUFrame, u32) -> anyhow::Result<()>>(port: &str, mut imu_notification: F) -> anyhow::Result<!> {
+/// Listen to the imu on `port` until the link fails
+pub fn listen_to_port<F: FnMut(ImuEvent) -> anyhow::Result<()>>(port: &str, imu_notification: &mut F, connection: &mut Connection) -> anyhow::Result<!> {
     let mut port = serialport::new(port, common::BAUD_RATE_FORWARD)
         .timeout(Duration::from_millis(1))
         .open_native()
-        .expect("Failed to open port");
+        .context("could not open port")?;
 
     port.clear(ClearBuffer::All)?;
+    let mut last_heard = Instant::now();
 
     let mut buffer = [0; 4098];
     let mut last_end = 0;
@@ -49,7 +54,13 @@ pub fn listen_to_port<F: FnMut(IMUFrame, u32) -> anyhow::Result<()>>(port: &str,
                 for frame in frames {
                     if *frame.last().unwrap() == 0x6E {
                         if let Some(frame) = frame::decode_imu_frame(frame) {
-                            (imu_notification)(frame, makeup)?;
+                            // The imu has no handshake, a valid frame means it is up
+                            if let Some(state) = connection.enter(ConnectionState::Live) {
+                                (imu_notification)(ImuEvent::Connection(state))?;
+                            }
+                            last_heard = Instant::now();
+
+                            (imu_notification)(ImuEvent::Frame(frame, makeup))?;
                             makeup = 0;
                         } else {
                             println!("invalid frame");
@@ -66,9 +77,13 @@ pub fn listen_to_port<F: FnMut(IMUFrame, u32) -> anyhow::Result<()>>(port: &str,
             }
             Err(e) => {
                 if e.kind() != io::ErrorKind::TimedOut {
-                    Err::<(), _>(e).unwrap();
+                    return Err(e).context("Io error");
                 }
             }
         }
+
+        if last_heard.elapsed() > SILENCE_TIMEOUT {
+            bail!("Imu went silent");
+        }
     }
 }
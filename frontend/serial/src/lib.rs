@@ -1,4 +1,10 @@
-#![feature(never_type)]
-
+pub mod command;
+pub mod connection;
 pub mod controller;
 pub mod imu;
+pub mod net;
+pub mod port;
+pub mod sequence;
+#[cfg(unix)]
+pub mod sim;
+pub mod transport;
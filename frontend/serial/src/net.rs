@@ -0,0 +1,267 @@
+//! Carries the controller protocol over a network, for a controller behind an onboard computer
+//!
+//! The onboard computer runs a bridge between a socket and the controller's serial port,
+//! the pc connects to it with `ControllerTransport::connect_tcp` or `ControllerTransport::connect_udp`.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use anyhow::{bail, Context as _};
+use common::controller::DownstreamMessage;
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, ToSocketAddrs, UdpSocket};
+use crate::sequence::WINDOW;
+
+/// Largest datagram sent, fits in an ethernet frame
+pub const MAX_DATAGRAM: usize = 1472;
+
+/// A connected udp socket read and written like a byte stream
+///
+/// Every write is sent as one datagram and every read returns the next one, lost datagrams are skipped.
+pub struct UdpStream {
+    socket: UdpSocket,
+    // The rest of a datagram that didn't fit in the last read
+    pending: Vec<u8>,
+}
+
+impl UdpStream {
+    /// Bind to `local` and exchange datagrams with `remote`
+    pub async fn connect<L: ToSocketAddrs, R: ToSocketAddrs>(local: L, remote: R) -> io::Result<Self> {
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(remote).await?;
+        Ok(UdpStream { socket, pending: Vec::new() })
+    }
+
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
+
+impl AsyncRead for UdpStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.pending.is_empty() {
+            let mut datagram = [0; MAX_DATAGRAM];
+            let mut datagram = ReadBuf::new(&mut datagram);
+            match ready!(self.socket.poll_recv(cx, &mut datagram)) {
+                // An empty read would look like the end of the stream, a refused datagram means nobody is listening yet
+                Ok(()) if datagram.filled().is_empty() => {}
+                Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {}
+                Ok(()) => self.pending.extend_from_slice(datagram.filled()),
+                Err(error) => return Poll::Ready(Err(error)),
+            }
+        }
+
+        let len = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for UdpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let len = buf.len().min(MAX_DATAGRAM);
+        match ready!(self.socket.poll_send(cx, &buf[..len])) {
+            // Nobody is listening, the datagram is lost like any other
+            Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => Poll::Ready(Ok(len)),
+            result => Poll::Ready(result),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Forward between one tcp client at a time and the controller's serial port
+pub async fn bridge_tcp<S: AsyncRead + AsyncWrite + Unpin>(listener: TcpListener, mut serial: S) -> anyhow::Result<!> {
+    let mut downstream = [0; 1024];
+    let mut upstream = [0; 1024];
+
+    loop {
+        let (mut client, address) = listener.accept().await.context("could not accept client")?;
+        client.set_nodelay(true)?;
+        println!("Bridging {}", address);
+
+        loop {
+            tokio::select! {
+                read = client.read(&mut downstream) => match read {
+                    Ok(0) => break,
+                    Ok(read) => serial.write_all(&downstream[..read]).await.context("could not write to serial port")?,
+                    Err(error) => {
+                        eprintln!("Lost {}: {:?}", address, error);
+                        break;
+                    }
+                },
+                read = serial.read(&mut upstream) => match read.context("could not read serial port")? {
+                    0 => bail!("Serial port closed"),
+                    // Whatever the client misses while disconnecting is lost
+                    read => if client.write_all(&upstream[..read]).await.is_err() {
+                        break;
+                    },
+                },
+            }
+        }
+
+        println!("{} disconnected", address);
+    }
+}
+
+/// Forward between udp and the controller's serial port
+///
+/// Frames from the serial port go to whoever sent the last datagram, a pc registers itself with an empty one.
+/// Whole frames are sent together, so a lost datagram doesn't corrupt its neighbours.
+/// Velocity updates that arrive after a newer one are dropped, everything else is forwarded as it arrives.
+pub async fn bridge_udp<S: AsyncRead + AsyncWrite + Unpin>(socket: UdpSocket, mut serial: S) -> anyhow::Result<!> {
+    let mut peer = None;
+    let mut velocity = None;
+    let mut datagram = [0; MAX_DATAGRAM];
+    let mut buffer = [0; 1024];
+    // Serial bytes waiting for the end of their frame
+    let mut upstream = Vec::new();
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut datagram) => {
+                let (len, from) = match received {
+                    Ok(received) => received,
+                    // A pc that went away, it can register again
+                    Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => continue,
+                    Err(error) => return Err(error).context("could not receive datagram"),
+                };
+                if peer != Some(from) {
+                    println!("Bridging {}", from);
+                    peer = Some(from);
+                }
+
+                for frame in datagram[..len].split_inclusive(common::end_of_frame) {
+                    if !is_stale(frame, &mut velocity) {
+                        serial.write_all(frame).await.context("could not write to serial port")?;
+                    }
+                }
+            }
+            read = serial.read(&mut buffer) => {
+                let read = read.context("could not read serial port")?;
+                if read == 0 {
+                    bail!("Serial port closed");
+                }
+                upstream.extend_from_slice(&buffer[..read]);
+
+                let end = match upstream.iter().rposition(common::end_of_frame) {
+                    Some(end) => end + 1,
+                    None if upstream.len() >= MAX_DATAGRAM => upstream.len(),
+                    None => continue,
+                };
+                if let Some(peer) = peer {
+                    for chunk in upstream[..end].chunks(MAX_DATAGRAM) {
+                        let _ = socket.send_to(chunk, peer).await;
+                    }
+                }
+                upstream.drain(..end);
+            }
+        }
+    }
+}
+
+/// Is `frame` a velocity update older than the last one forwarded, which it would overwrite
+fn is_stale(frame: &[u8], last_velocity: &mut Option<u16>) -> bool {
+    let Ok(envelope) = common::read::<DownstreamMessage>(&mut frame.to_vec()) else {
+        return false;
+    };
+    if !matches!(envelope.message, DownstreamMessage::VelocityUpdate(_)) {
+        return false;
+    }
+
+    // Anything further back is a pc that restarted its sequence
+    if let Some(last) = *last_velocity {
+        if last.wrapping_sub(envelope.seq) < WINDOW {
+            return true;
+        }
+    }
+    *last_velocity = Some(envelope.seq);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use common::controller::{EStopReason, EStopState, UpstreamMessage, VelocityData};
+    use futures::{SinkExt, StreamExt};
+    use tokio_serial::SerialPortBuilderExt;
+    #[cfg(unix)]
+    use crate::sim;
+    use crate::transport::ControllerTransport;
+    use super::*;
+
+    fn frame(seq: u16, message: &DownstreamMessage) -> Vec<u8> {
+        let mut buffer = [0; 256];
+        common::write(seq, message, &mut buffer).unwrap().to_vec()
+    }
+
+    #[test]
+    fn drops_late_velocity_updates() {
+        let velocity = DownstreamMessage::VelocityUpdate(VelocityData::default());
+        let mut last = None;
+
+        assert!(!is_stale(&frame(10, &velocity), &mut last));
+        assert!(is_stale(&frame(9, &velocity), &mut last));
+        assert!(is_stale(&frame(10, &velocity), &mut last));
+        assert!(!is_stale(&frame(8, &DownstreamMessage::EmergencyStop), &mut last));
+        assert!(!is_stale(&frame(1000, &velocity), &mut last));
+        // The pc restarted
+        assert!(!is_stale(&frame(0, &velocity), &mut last));
+        assert!(!is_stale(&frame(1, &velocity), &mut last));
+        assert!(!is_stale(&[1, 2, 3, 0], &mut last));
+    }
+
+    #[cfg(unix)]
+    /// Stop the simulated controller through `transport`, returns once its telemetry reports it
+    async fn emergency_stop<T: AsyncRead + AsyncWrite + Unpin>(mut transport: ControllerTransport<T>) {
+        assert_eq!(transport.hello().build_id, "simulated");
+        transport.send(DownstreamMessage::EmergencyStop).await.unwrap();
+
+        while let Some(frame) = transport.next().await {
+            if let UpstreamMessage::Telemetry(telemetry) = frame.unwrap().message() {
+                if telemetry.emergency_stop == EStopState::Latched(EStopReason::Pc) {
+                    return;
+                }
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn simulated_serial() -> (sim::SimulatedDevice, tokio_serial::SerialStream) {
+        let device = sim::controller().unwrap();
+        let serial = tokio_serial::new(device.path(), common::BAUD_RATE_CTRL).open_native_async().unwrap();
+        (device, serial)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bridges_tcp() {
+        let (_device, serial) = simulated_serial();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(bridge_tcp(listener, serial));
+
+        let transport = ControllerTransport::connect_tcp(address).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), emergency_stop(transport)).await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn bridges_udp() {
+        let (_device, serial) = simulated_serial();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        tokio::spawn(bridge_udp(socket, serial));
+
+        let transport = ControllerTransport::connect_udp("127.0.0.1:0", address).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), emergency_stop(transport)).await.unwrap();
+    }
+}
@@ -0,0 +1,280 @@
+//! Picks which serial port each device is on
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Context};
+use serde::Deserialize;
+use serialport::{SerialPortInfo, SerialPortType};
+
+/// Arduino's USB vendor id
+const ARDUINO_VID: u16 = 0x2341;
+
+/// Which port a device is on, every field that is set has to match
+///
+/// Anything but `path` only matches usb ports.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortSelector {
+    /// Device path, `/dev/ttyACM0` or `COM3`
+    pub path: Option<String>,
+    pub serial_number: Option<String>,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    /// Product string the device reports, compared ignoring case
+    pub product: Option<String>,
+}
+
+impl PortSelector {
+    /// Any usb port with this vendor and product id
+    pub fn usb(vid: u16, pid: u16) -> Self {
+        PortSelector {
+            vid: Some(vid),
+            pid: Some(pid),
+            ..Default::default()
+        }
+    }
+
+    /// Does `port` match every field that is set
+    pub fn matches(&self, port: &SerialPortInfo) -> bool {
+        if let Some(path) = &self.path {
+            if *path != port.port_name {
+                return false;
+            }
+        }
+        if self.only_path() {
+            return true;
+        }
+
+        match &port.port_type {
+            SerialPortType::UsbPort(info) => {
+                self.vid.is_none_or(|vid| vid == info.vid)
+                    && self.pid.is_none_or(|pid| pid == info.pid)
+                    && self.serial_number.as_ref().is_none_or(|serial| info.serial_number.as_ref() == Some(serial))
+                    && self.product.as_ref().is_none_or(|product| info.product.as_ref().is_some_and(|info| info.eq_ignore_ascii_case(product)))
+            }
+            _ => false,
+        }
+    }
+
+    /// Find the first connected port that matches
+    pub fn find(&self) -> anyhow::Result<Option<SerialPortInfo>> {
+        let mut ports = serialport::available_ports()?.into_iter().filter(|port| self.matches(port));
+        let port = ports.next();
+
+        if let Some(other) = ports.next() {
+            eprintln!("Both {} and {} match {}, using the first", port.as_ref().unwrap().port_name, other.port_name, self);
+        }
+
+        // Pseudo-terminals and links like /dev/serial/by-id aren't listed, use them as given
+        if port.is_none() && self.only_path() {
+            if let Some(path) = self.path.as_ref().filter(|path| Path::new(path).exists()) {
+                return Ok(Some(SerialPortInfo { port_name: path.clone(), port_type: SerialPortType::Unknown }));
+            }
+        }
+
+        Ok(port)
+    }
+
+    /// Is nothing but the path set
+    fn only_path(&self) -> bool {
+        self.serial_number.is_none() && self.vid.is_none() && self.pid.is_none() && self.product.is_none()
+    }
+}
+
+/// Parses the `key=value,...` form used on the command line, keys are `path`, `serial`, `vid`, `pid` and `product`
+///
+/// Ids are hex, a spec without any `=` is a path.
+impl FromStr for PortSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        if !spec.contains('=') {
+            return Ok(PortSelector {
+                path: Some(spec.to_owned()),
+                ..Default::default()
+            });
+        }
+
+        let mut selector = PortSelector::default();
+        for field in spec.split(',') {
+            let (key, value) = field.split_once('=').ok_or_else(|| anyhow!("expected key=value, got {:?}", field))?;
+            let value = value.trim();
+            match key.trim() {
+                "path" => selector.path = Some(value.to_owned()),
+                "serial" => selector.serial_number = Some(value.to_owned()),
+                "vid" => selector.vid = Some(parse_id(value)?),
+                "pid" => selector.pid = Some(parse_id(value)?),
+                "product" => selector.product = Some(value.to_owned()),
+                key => bail!("unknown port selector key {:?}", key),
+            }
+        }
+
+        Ok(selector)
+    }
+}
+
+fn parse_id(id: &str) -> anyhow::Result<u16> {
+    u16::from_str_radix(id.trim_start_matches("0x"), 16).with_context(|| format!("invalid usb id {:?}", id))
+}
+
+impl std::fmt::Display for PortSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut fields = Vec::new();
+        if let Some(path) = &self.path {
+            fields.push(format!("path={}", path));
+        }
+        if let Some(serial) = &self.serial_number {
+            fields.push(format!("serial={}", serial));
+        }
+        if let Some(vid) = self.vid {
+            fields.push(format!("vid={:04x}", vid));
+        }
+        if let Some(pid) = self.pid {
+            fields.push(format!("pid={:04x}", pid));
+        }
+        if let Some(product) = &self.product {
+            fields.push(format!("product={}", product));
+        }
+
+        if fields.is_empty() {
+            f.write_str("any port")
+        } else {
+            f.write_str(&fields.join(","))
+        }
+    }
+}
+
+/// The ports of every device we talk to
+///
+/// Loaded from a toml file with a `[controller]` and an `[imu]` table, a table that is present replaces the default selector.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortConfig {
+    pub controller: PortSelector,
+    pub imu: PortSelector,
+}
+
+impl Default for PortConfig {
+    fn default() -> Self {
+        PortConfig {
+            controller: PortSelector::usb(ARDUINO_VID, 0x42),
+            imu: PortSelector::usb(ARDUINO_VID, 0x43),
+        }
+    }
+}
+
+impl PortConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let config = fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+        toml::from_str(&config).with_context(|| format!("could not parse {}", path.display()))
+    }
+
+    /// Build the config from command line arguments, the program name already skipped
+    ///
+    /// `--port-config <file>` loads a file, `--controller-port <spec>` and `--imu-port <spec>` then override it.
+    /// Other arguments are ignored.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> anyhow::Result<Self> {
+        let mut config = None;
+        let mut controller = None;
+        let mut imu = None;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let target = match arg.as_str() {
+                "--port-config" => &mut config,
+                "--controller-port" => &mut controller,
+                "--imu-port" => &mut imu,
+                _ => continue,
+            };
+            *target = Some(args.next().ok_or_else(|| anyhow!("{} needs a value", arg))?);
+        }
+
+        let mut ports = match config {
+            Some(path) => PortConfig::load(Path::new(&path))?,
+            None => PortConfig::default(),
+        };
+        if let Some(spec) = controller {
+            ports.controller = spec.parse().context("invalid --controller-port")?;
+        }
+        if let Some(spec) = imu {
+            ports.imu = spec.parse().context("invalid --imu-port")?;
+        }
+
+        Ok(ports)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serialport::UsbPortInfo;
+    use super::*;
+
+    fn usb_port(name: &str, vid: u16, pid: u16, serial_number: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_owned(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: Some(serial_number.to_owned()),
+                manufacturer: Some("Arduino (www.arduino.cc)".to_owned()),
+                product: Some("Arduino Mega 2560".to_owned()),
+            }),
+        }
+    }
+
+    #[test]
+    fn every_set_field_has_to_match() {
+        let first = usb_port("/dev/ttyACM0", 0x2341, 0x42, "7573530323035150E0A1");
+        let second = usb_port("/dev/ttyACM1", 0x2341, 0x42, "95635333930351F0B122");
+        let uart = SerialPortInfo { port_name: "/dev/ttyS0".to_owned(), port_type: SerialPortType::PciPort };
+
+        let mega = PortSelector::usb(0x2341, 0x42);
+        assert!(mega.matches(&first) && mega.matches(&second));
+        assert!(!PortSelector::usb(0x2341, 0x43).matches(&first));
+
+        let serial = PortSelector { serial_number: Some("95635333930351F0B122".to_owned()), ..mega };
+        assert!(!serial.matches(&first) && serial.matches(&second));
+
+        let product = PortSelector { product: Some("arduino mega 2560".to_owned()), ..Default::default() };
+        assert!(product.matches(&first) && !product.matches(&uart));
+
+        let path = PortSelector { path: Some("/dev/ttyS0".to_owned()), ..Default::default() };
+        assert!(path.matches(&uart) && !path.matches(&first));
+    }
+
+    #[test]
+    fn parses_specs() {
+        assert_eq!("/dev/ttyUSB0".parse::<PortSelector>().unwrap(), PortSelector { path: Some("/dev/ttyUSB0".to_owned()), ..Default::default() });
+        assert_eq!("vid=2341, pid=0x42".parse::<PortSelector>().unwrap(), PortSelector::usb(0x2341, 0x42));
+        assert_eq!(
+            "serial=ABC123,product=Mega".parse::<PortSelector>().unwrap(),
+            PortSelector { serial_number: Some("ABC123".to_owned()), product: Some("Mega".to_owned()), ..Default::default() },
+        );
+
+        assert!("vid=nope".parse::<PortSelector>().is_err());
+        assert!("colour=blue".parse::<PortSelector>().is_err());
+    }
+
+    #[test]
+    fn loads_config() {
+        let config: PortConfig = toml::from_str(r#"
+            [controller]
+            serial_number = "7573530323035150E0A1"
+        "#).unwrap();
+
+        assert_eq!(config.controller, PortSelector { serial_number: Some("7573530323035150E0A1".to_owned()), ..Default::default() });
+        assert_eq!(config.imu, PortConfig::default().imu);
+    }
+
+    #[test]
+    fn flags_override_defaults() {
+        let args = ["--imu-port", "/dev/ttyUSB1", "--fullscreen"].map(str::to_owned);
+        let config = PortConfig::from_args(args).unwrap();
+
+        assert_eq!(config.controller, PortConfig::default().controller);
+        assert_eq!(config.imu.path.as_deref(), Some("/dev/ttyUSB1"));
+
+        assert!(PortConfig::from_args(["--controller-port".to_owned()]).is_err());
+    }
+}
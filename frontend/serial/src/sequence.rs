@@ -0,0 +1,252 @@
+use std::time::{Duration, Instant};
+
+/// How many sequence numbers back we remember
+pub(crate) const WINDOW: u16 = 64;
+
+/// Tracks the sequence numbers of received packets to detect loss, duplication and reordering
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    highest: Option<u16>,
+    // Bit n is set if `highest - n` has been received
+    window: u64,
+
+    received: u64,
+    lost: u64,
+    duplicates: u64,
+    reordered: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arrival {
+    /// A packet newer than any seen so far, `skipped` packets before it are missing
+    InOrder { skipped: u16 },
+    /// A packet that was already received
+    Duplicate,
+    /// A packet older than the newest one seen so far
+    Reordered,
+}
+
+impl SequenceTracker {
+    /// Record the arrival of a packet
+    pub fn record(&mut self, seq: u16) -> Arrival {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(seq);
+                self.window = 1;
+                self.received += 1;
+                return Arrival::InOrder { skipped: 0 };
+            }
+        };
+
+        let ahead = seq.wrapping_sub(highest) as i16;
+        if ahead > 0 {
+            let ahead = ahead as u16;
+            let skipped = ahead - 1;
+
+            self.window = if ahead < WINDOW { self.window << ahead | 1 } else { 1 };
+            self.highest = Some(seq);
+            self.received += 1;
+            self.lost += skipped as u64;
+
+            Arrival::InOrder { skipped }
+        } else {
+            let behind = ahead.unsigned_abs();
+
+            if behind < WINDOW && self.window & (1 << behind) != 0 {
+                self.duplicates += 1;
+                return Arrival::Duplicate;
+            }
+
+            if behind < WINDOW {
+                // This packet was counted as lost when a newer one arrived
+                self.window |= 1 << behind;
+                self.lost = self.lost.saturating_sub(1);
+            }
+            self.received += 1;
+            self.reordered += 1;
+
+            Arrival::Reordered
+        }
+    }
+
+    /// Forget everything, used when the other side restarts its sequence
+    pub fn reset(&mut self) {
+        *self = Default::default();
+    }
+
+    /// Fraction of packets that never arrived
+    pub fn loss_rate(&self) -> f64 {
+        let total = self.received + self.lost;
+        if total == 0 {
+            0.0
+        } else {
+            self.lost as f64 / total as f64
+        }
+    }
+}
+
+/// Hands out sequence numbers for outgoing packets and matches them with their acknowledgements
+#[derive(Debug)]
+pub struct AckTracker {
+    next_seq: u16,
+    // Send time of packets that have not been acknowledged yet, indexed by `seq % WINDOW`
+    in_flight: [Option<(u16, Instant)>; WINDOW as usize],
+
+    acked: u64,
+    lost: u64,
+    average_latency: Option<Duration>,
+    last_latency: Option<Duration>,
+}
+
+impl Default for AckTracker {
+    fn default() -> Self {
+        AckTracker {
+            next_seq: 0,
+            in_flight: [None; WINDOW as usize],
+            acked: 0,
+            lost: 0,
+            average_latency: None,
+            last_latency: None
+        }
+    }
+}
+
+impl AckTracker {
+    /// Sequence number for the next outgoing packet
+    pub fn next_seq(&mut self) -> u16 {
+        let seq = self.next_seq;
+        self.next_seq = seq.wrapping_add(1);
+
+        // A packet still waiting after `WINDOW` newer ones were sent is not going to be acknowledged
+        let slot = &mut self.in_flight[(seq % WINDOW) as usize];
+        if slot.is_some() {
+            self.lost += 1;
+        }
+        *slot = Some((seq, Instant::now()));
+
+        seq
+    }
+
+    /// Record an acknowledgement, returns the round trip time of the acknowledged packet
+    pub fn acked(&mut self, seq: u16) -> Option<Duration> {
+        let slot = &mut self.in_flight[(seq % WINDOW) as usize];
+        match *slot {
+            Some((sent_seq, sent_at)) if sent_seq == seq => {
+                *slot = None;
+
+                let latency = sent_at.elapsed();
+                self.acked += 1;
+                self.last_latency = Some(latency);
+                self.average_latency = Some(match self.average_latency {
+                    Some(average) => average.mul_f64(0.9) + latency.mul_f64(0.1),
+                    None => latency
+                });
+
+                Some(latency)
+            }
+            _ => None
+        }
+    }
+
+    /// Fraction of packets that were never acknowledged
+    pub fn loss_rate(&self) -> f64 {
+        let total = self.acked + self.lost;
+        if total == 0 {
+            0.0
+        } else {
+            self.lost as f64 / total as f64
+        }
+    }
+}
+
+/// Summary of the health of a link
+#[derive(Debug, Clone, Default)]
+pub struct LinkStats {
+    /// Fraction of packets from the remote that never arrived
+    pub upstream_loss: f64,
+    pub duplicates: u64,
+    pub reordered: u64,
+
+    /// Fraction of our packets the remote never acknowledged
+    pub downstream_loss: f64,
+    pub average_latency: Option<Duration>,
+    pub last_latency: Option<Duration>,
+}
+
+/// Sequence tracking for both directions of a link
+#[derive(Debug, Default)]
+pub struct Link {
+    pub incoming: SequenceTracker,
+    pub outgoing: AckTracker,
+}
+
+impl Link {
+    pub fn stats(&self) -> LinkStats {
+        LinkStats {
+            upstream_loss: self.incoming.loss_rate(),
+            duplicates: self.incoming.duplicates,
+            reordered: self.incoming.reordered,
+            downstream_loss: self.outgoing.loss_rate(),
+            average_latency: self.outgoing.average_latency,
+            last_latency: self.outgoing.last_latency
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut tracker = SequenceTracker::default();
+        for seq in 0..10 {
+            assert_eq!(tracker.record(seq), Arrival::InOrder { skipped: 0 });
+        }
+        assert_eq!(tracker.loss_rate(), 0.0);
+    }
+
+    #[test]
+    fn loss_and_late_arrival() {
+        let mut tracker = SequenceTracker::default();
+        tracker.record(0);
+        assert_eq!(tracker.record(3), Arrival::InOrder { skipped: 2 });
+        assert_eq!(tracker.lost, 2);
+
+        assert_eq!(tracker.record(1), Arrival::Reordered);
+        assert_eq!(tracker.lost, 1);
+        assert_eq!(tracker.record(1), Arrival::Duplicate);
+        assert_eq!(tracker.record(3), Arrival::Duplicate);
+        assert_eq!(tracker.reordered, 1);
+        assert_eq!(tracker.duplicates, 2);
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut tracker = SequenceTracker::default();
+        tracker.record(u16::MAX - 1);
+        assert_eq!(tracker.record(u16::MAX), Arrival::InOrder { skipped: 0 });
+        assert_eq!(tracker.record(1), Arrival::InOrder { skipped: 1 });
+        assert_eq!(tracker.record(0), Arrival::Reordered);
+        assert_eq!(tracker.record(u16::MAX), Arrival::Duplicate);
+        assert_eq!(tracker.lost, 0);
+    }
+
+    #[test]
+    fn acks() {
+        let mut tracker = AckTracker::default();
+        let first = tracker.next_seq();
+        let second = tracker.next_seq();
+
+        assert!(tracker.acked(second).is_some());
+        assert!(tracker.acked(second).is_none());
+
+        // Push the first packet out of the window without acknowledging it
+        for _ in 0..WINDOW {
+            tracker.next_seq();
+        }
+        assert!(tracker.acked(first).is_none());
+        assert_eq!(tracker.lost, 1);
+    }
+}
@@ -0,0 +1,288 @@
+//! A simulated controller and imu behind pseudo-terminals, to run the serial stack without hardware
+//!
+//! Each device gets a pty whose path the listeners open like the real port.
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Context};
+use common::actuator::{ActuatorData, SAFE_ACTUATORS};
+use common::codec::{FrameDecoder, FrameEncoder, FrameError};
+use common::controller::{ArbitrationMode, DownstreamMessage, EStopReason, EStopState, Hello, PROTOCOL_VERSION, ResetCause, Telemetry, UpstreamMessage, VelocityData};
+use serialport::{SerialPort, TTYPort};
+use crate::port::PortSelector;
+
+/// How often the simulated imu sends a frame
+const IMU_INTERVAL: Duration = Duration::from_millis(10);
+/// How often the simulated controller sends telemetry, the firmware's default
+const TELEMETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A device running on its own thread until dropped
+pub struct SimulatedDevice {
+    path: String,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SimulatedDevice {
+    fn spawn<F: FnOnce(TTYPort, &AtomicBool) + Send + 'static>(name: &str, run: F) -> anyhow::Result<Self> {
+        let (mut device, port) = TTYPort::pair().context("could not create pseudo-terminal")?;
+        let path = port.name().ok_or_else(|| anyhow!("pseudo-terminal has no name"))?;
+        // Only the listener keeps the port open, so reading fails with nobody on the other end
+        drop(port);
+        device.set_timeout(Duration::from_millis(1))?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name(name.to_owned())
+                .spawn(move || run(device, &stop))?
+        };
+
+        Ok(SimulatedDevice { path, stop, thread: Some(thread) })
+    }
+
+    /// The port to open to talk to the device
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn selector(&self) -> PortSelector {
+        PortSelector {
+            path: Some(self.path.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for SimulatedDevice {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A controller that acks, pongs, latches emergency stops and reports its state in telemetry
+pub fn controller() -> anyhow::Result<SimulatedDevice> {
+    SimulatedDevice::spawn("Simulated Controller", |device, stop| SimulatedController::new(device).run(stop))
+}
+
+/// An imu sitting level and still
+pub fn imu() -> anyhow::Result<SimulatedDevice> {
+    SimulatedDevice::spawn("Simulated IMU", run_imu)
+}
+
+struct SimulatedController {
+    device: TTYPort,
+    decoder: FrameDecoder,
+    encoder: FrameEncoder,
+    seq: u16,
+    started: Instant,
+
+    emergency_stop: EStopState,
+    setpoint: VelocityData,
+    arbitration: ArbitrationMode,
+    actuators: ActuatorData,
+    telemetry: Telemetry,
+}
+
+impl SimulatedController {
+    fn new(device: TTYPort) -> Self {
+        SimulatedController {
+            device,
+            decoder: FrameDecoder::default(),
+            encoder: FrameEncoder::default(),
+            seq: 0,
+            started: Instant::now(),
+            emergency_stop: EStopState::Armed,
+            setpoint: VelocityData::default(),
+            arbitration: ArbitrationMode::default(),
+            actuators: SAFE_ACTUATORS,
+            telemetry: Telemetry::default(),
+        }
+    }
+
+    fn run(mut self, stop: &AtomicBool) {
+        let mut buffer = [0; 256];
+        let mut last_telemetry = Instant::now();
+
+        while !stop.load(Ordering::Relaxed) {
+            match self.device.read(&mut buffer) {
+                Ok(read) => self.receive(&buffer[..read]),
+                // Nobody has the port open
+                Err(_) => thread::sleep(Duration::from_millis(1)),
+            }
+
+            if last_telemetry.elapsed() > TELEMETRY_INTERVAL {
+                let telemetry = Telemetry {
+                    timestamp: self.started.elapsed().as_micros() as u32,
+                    pc_setpoint: self.setpoint.clone(),
+                    arbitration: self.arbitration,
+                    emergency_stop: self.emergency_stop,
+                    actuators: self.actuators,
+                    ..self.telemetry.clone()
+                };
+                self.send(&UpstreamMessage::Telemetry(telemetry));
+                last_telemetry = Instant::now();
+            }
+        }
+    }
+
+    fn receive(&mut self, bytes: &[u8]) {
+        self.decoder.push(bytes);
+        while let Some(result) = self.decoder.next::<DownstreamMessage>() {
+            match result {
+                Ok(envelope) => {
+                    self.handle(envelope.message);
+                    self.send(&UpstreamMessage::Ack(envelope.seq));
+                    self.telemetry.counters.packets += 1;
+                }
+                Err(FrameError::Decode(error)) => {
+                    self.send(&UpstreamMessage::BadP(error));
+                    self.telemetry.counters.bad_packets += 1;
+                }
+                Err(FrameError::Overflow(_)) => {
+                    self.send(&UpstreamMessage::BadO);
+                    self.telemetry.counters.overruns += 1;
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, message: DownstreamMessage) {
+        match message {
+            DownstreamMessage::Hello(_) => {
+                self.send(&UpstreamMessage::Hello(Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    build_id: "simulated",
+                    capabilities: 0,
+                    reset_cause: ResetCause(ResetCause::POWER_ON),
+                }));
+            }
+            DownstreamMessage::Ping => self.send(&UpstreamMessage::Pong),
+            DownstreamMessage::EmergencyStop => {
+                self.emergency_stop = EStopState::Latched(EStopReason::Pc);
+                self.setpoint = VelocityData::default();
+                self.actuators = SAFE_ACTUATORS;
+            }
+            DownstreamMessage::Arm => self.emergency_stop = EStopState::Armed,
+            DownstreamMessage::VelocityUpdate(velocity) if !self.emergency_stop.is_latched() => self.setpoint = velocity.clamp(),
+            DownstreamMessage::SetArbitration(mode) => self.arbitration = mode,
+            DownstreamMessage::SetActuator(actuator, value) if !self.emergency_stop.is_latched() => self.actuators.set(actuator, value),
+            _ => {}
+        }
+    }
+
+    /// Write a message, dropped if nobody is reading like on a real uart
+    fn send(&mut self, message: &UpstreamMessage) {
+        self.seq = self.seq.wrapping_add(1);
+        if let Ok(frame) = self.encoder.encode(self.seq, message) {
+            let _ = self.device.write_all(frame);
+        }
+    }
+}
+
+fn run_imu(mut device: TTYPort, stop: &AtomicBool) {
+    // Raw readings that decode to 1 g up, no rotation once the gyro offsets are removed, and the surface pressure
+    let readings = [102, 0, 0, 8197, 20, -63, -15, 1000, 0, -2000];
+    let frame = imu_frame(readings, IMU_INTERVAL.as_millis() as u8);
+
+    while !stop.load(Ordering::Relaxed) {
+        let _ = device.write_all(&frame);
+        thread::sleep(IMU_INTERVAL);
+    }
+}
+
+/// Lay out readings the way the imu sends them, checksum and terminator included
+///
+/// Readings are nudged until no byte but the terminator is 0x6E, it isn't escaped.
+fn imu_frame(mut readings: [i16; 10], total_ms: u8) -> Vec<u8> {
+    loop {
+        let mut frame: Vec<u8> = readings.iter().flat_map(|reading| reading.to_le_bytes()).collect();
+        frame.push(total_ms);
+        frame.push(frame.iter().fold(0, |acc, &it| acc ^ it));
+
+        if !frame.contains(&0x6E) {
+            frame.push(0x6E);
+            return frame;
+        }
+        readings[0] += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::bail;
+    use common::controller::EStopReason;
+    use crate::command::CommandQueue;
+    use crate::connection::{Connection, ConnectionState};
+    use crate::controller::ControllerEvent;
+    use crate::imu::ImuEvent;
+    use crate::{controller, imu};
+    use super::*;
+
+    #[test]
+    fn imu_frames_decode() {
+        let frame = imu_frame([0x6E, 0, 0, 8197, 0, 0, 0, 0, 0, 0], 10);
+        assert!(!frame[..frame.len() - 1].contains(&0x6E));
+
+        let frame = sensor_fusion::frame::decode_imu_frame(&frame).unwrap();
+        assert!((frame.acceleration.z - 9.8).abs() < 0.1);
+    }
+
+    #[test]
+    fn talks_to_the_controller() {
+        let device = controller().unwrap();
+        let commands = CommandQueue::new();
+        commands.send(DownstreamMessage::Ping).unwrap();
+        commands.send(DownstreamMessage::EmergencyStop).unwrap();
+
+        let mut states = Vec::new();
+        let mut ponged = false;
+        let Err(error) = controller::listen_to_port(device.path(), &mut |event| {
+            match event {
+                ControllerEvent::Connection(state) => states.push(state),
+                ControllerEvent::Message(UpstreamMessage::Pong) => ponged = true,
+                ControllerEvent::Message(UpstreamMessage::Telemetry(telemetry)) if telemetry.emergency_stop == EStopState::Latched(EStopReason::Pc) => {
+                    bail!("stopped");
+                }
+                _ => {}
+            }
+            Ok(())
+        }, Some(&commands), &mut Connection::default());
+
+        assert_eq!(error.root_cause().to_string(), "stopped");
+        assert!(ponged);
+        assert_eq!(states, [ConnectionState::Handshaking, ConnectionState::Live]);
+    }
+
+    #[test]
+    fn listens_to_the_imu() {
+        let device = imu().unwrap();
+
+        let mut states = Vec::new();
+        let mut frames = 0;
+        let Err(error) = imu::listen_to_port(device.path(), &mut |event| {
+            match event {
+                ImuEvent::Connection(state) => states.push(state),
+                ImuEvent::Frame(frame, _) => {
+                    assert_eq!(frame.total_duration, IMU_INTERVAL);
+                    frames += 1;
+                    if frames == 5 {
+                        bail!("done");
+                    }
+                }
+            }
+            Ok(())
+        }, &mut Connection::default());
+
+        assert_eq!(error.root_cause().to_string(), "done");
+        assert_eq!(states, [ConnectionState::Live]);
+    }
+}
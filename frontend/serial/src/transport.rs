@@ -0,0 +1,639 @@
+//! Async connection to the controller, for running it alongside other tokio services
+//!
+//! It runs over anything that reads and writes bytes, a serial port, tcp, or udp through `net::UdpStream`.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+use anyhow::{bail, Context as _};
+use bytes::BytesMut;
+use common::CommunicationError;
+use common::codec::{FrameDecoder, FrameEncoder, FrameError, ReceivedFrame};
+use common::controller::{DownstreamMessage, Hello, PROTOCOL_VERSION, UpstreamMessage};
+use futures::{ready, Sink, SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time::{Interval, MissedTickBehavior};
+use tokio_serial::{ClearBuffer, SerialPort, SerialPortBuilderExt, SerialStream};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+use crate::command::supersedes;
+use crate::controller::HELLO_INTERVAL;
+use crate::net::UdpStream;
+use crate::sequence::{Arrival, Link, LinkStats};
+
+/// How long `connect` waits for the firmware to identify itself, covers the reset when the port opens
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a command on a lossy link waits for its ack before it is sent again
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(100);
+/// How many times a command on a lossy link is sent before giving up on it
+const MAX_SENDS: u8 = 5;
+
+#[derive(Debug)]
+pub enum TransportError {
+    Io(io::Error),
+    /// A received frame was dropped, the stream carries on with the next one
+    Frame(FrameError),
+    /// A command could not be encoded
+    Encode(CommunicationError),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Io(error) => write!(f, "io error: {}", error),
+            TransportError::Frame(error) => write!(f, "{}", error),
+            TransportError::Encode(error) => write!(f, "could not encode command: {:?}", error),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl From<io::Error> for TransportError {
+    fn from(error: io::Error) -> Self {
+        TransportError::Io(error)
+    }
+}
+
+/// A message from the firmware along with its sequence number
+///
+/// Messages can borrow text from their frame, so the frame is kept and decoded again on access.
+/// Only frames that decoded are received, so that can't fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedMessage(ReceivedFrame);
+
+impl ReceivedMessage {
+    pub fn seq(&self) -> u16 {
+        self.0.seq()
+    }
+
+    pub fn message(&self) -> UpstreamMessage<'_> {
+        self.0.message().expect("received frames were decoded")
+    }
+}
+
+/// Frames commands and messages, numbering the commands and dropping duplicated messages
+#[derive(Default)]
+pub struct ControllerCodec {
+    decoder: FrameDecoder,
+    encoder: FrameEncoder,
+    link: Link,
+    // Sequence number of the last encoded command
+    last_seq: u16,
+}
+
+impl Decoder for ControllerCodec {
+    // Bad frames are items rather than errors, an error would end the stream
+    type Item = Result<ReceivedMessage, FrameError>;
+    type Error = TransportError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        self.decoder.push(&src.split());
+
+        while let Some(frame) = self.decoder.next_frame() {
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(error) => return Ok(Some(Err(error))),
+            };
+
+            match frame.message::<UpstreamMessage>() {
+                Ok(UpstreamMessage::Hello(_)) => {
+                    // The firmware restarted, so did its sequence numbers
+                    self.link.incoming.reset();
+                }
+                Ok(_) => {}
+                Err(error) => return Ok(Some(Err(FrameError::Decode(error)))),
+            }
+            let message = ReceivedMessage(frame);
+
+            if self.link.incoming.record(message.seq()) == Arrival::Duplicate {
+                continue;
+            }
+            if let UpstreamMessage::Ack(seq) = message.message() {
+                self.link.outgoing.acked(seq);
+            }
+
+            return Ok(Some(Ok(message)));
+        }
+
+        Ok(None)
+    }
+}
+
+impl Encoder<DownstreamMessage> for ControllerCodec {
+    type Error = TransportError;
+
+    fn encode(&mut self, message: DownstreamMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        self.last_seq = self.link.outgoing.next_seq();
+        let frame = self.encoder.encode(self.last_seq, &message).map_err(TransportError::Encode)?;
+        dst.extend_from_slice(frame);
+        Ok(())
+    }
+}
+
+/// Can losing this command go unnoticed, a newer one follows shortly
+fn loss_tolerant(command: &DownstreamMessage) -> bool {
+    matches!(command, DownstreamMessage::VelocityUpdate(_) | DownstreamMessage::Ping)
+}
+
+/// Sending this again could undo what happened since, a late arm would release an emergency stop the firmware latched
+fn repeatable(command: &DownstreamMessage) -> bool {
+    *command != DownstreamMessage::Arm
+}
+
+/// A command sent over a lossy link that wasn't acknowledged yet
+struct Unacked {
+    // Sequence number of every send, an ack for any of them will do
+    seqs: Vec<u16>,
+    command: DownstreamMessage,
+    sent: Instant,
+    sends: u8,
+}
+
+/// Commands a lossy link sends again until they are acknowledged
+struct Retransmit {
+    unacked: Vec<Unacked>,
+    timer: Interval,
+}
+
+impl Retransmit {
+    fn new() -> Self {
+        let mut timer = tokio::time::interval(RETRANSMIT_TIMEOUT / 2);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Retransmit { unacked: Vec::new(), timer }
+    }
+
+    /// Stop sending commands again that `command` makes outdated, so they can't arrive after it
+    ///
+    /// An emergency stop cancels everything, so nothing it stopped arrives after it.
+    /// Repeated emergency stops are only ever cancelled by newer ones.
+    fn sending(&mut self, command: &DownstreamMessage) {
+        if *command == DownstreamMessage::EmergencyStop {
+            self.unacked.clear();
+        } else {
+            self.unacked.retain(|unacked| !supersedes(command, &unacked.command));
+        }
+    }
+}
+
+/// A connection to firmware that speaks our protocol
+///
+/// Received messages are a `Stream` of `ReceivedMessage`s, frames that don't decode are reported as errors.
+/// Commands are sent through its `Sink`. The stream ends once the shutdown token is cancelled.
+/// Over a lossy link commands other than velocity updates, pings and arms are sent again until acknowledged
+/// or outdated by a newer command, while the stream is polled. An emergency stop outdates every other command.
+pub struct ControllerTransport<T = SerialStream> {
+    framed: Framed<T, ControllerCodec>,
+    hello: ReceivedMessage,
+    shutdown: CancellationToken,
+    cancelled: Pin<Box<WaitForCancellationFutureOwned>>,
+    retransmit: Option<Retransmit>,
+}
+
+impl ControllerTransport {
+    /// Open a serial port and handshake with the firmware on it
+    pub async fn open(port: &str) -> anyhow::Result<Self> {
+        let port = tokio_serial::new(port, common::BAUD_RATE_CTRL).open_native_async().context("could not open serial stream")?;
+        port.clear(ClearBuffer::All).context("could not clear port")?;
+
+        ControllerTransport::connect(port).await
+    }
+}
+
+impl ControllerTransport<TcpStream> {
+    /// Connect to a bridge over tcp and handshake with the firmware behind it
+    pub async fn connect_tcp<A: ToSocketAddrs>(bridge: A) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(bridge).await.context("could not connect to bridge")?;
+        stream.set_nodelay(true)?;
+
+        ControllerTransport::connect(stream).await
+    }
+}
+
+impl ControllerTransport<UdpStream> {
+    /// Exchange datagrams with a bridge from `local` and handshake with the firmware behind it
+    pub async fn connect_udp<L: ToSocketAddrs, R: ToSocketAddrs>(local: L, bridge: R) -> anyhow::Result<Self> {
+        let stream = UdpStream::connect(local, bridge).await.context("could not connect to bridge")?;
+        // The bridge only forwards to us once it has heard from us
+        stream.socket().send(&[]).await.context("could not register with bridge")?;
+
+        let mut transport = ControllerTransport::connect(stream).await?;
+        transport.retransmit = Some(Retransmit::new());
+        Ok(transport)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> ControllerTransport<T> {
+    /// Wait for the firmware to identify itself, asking it to if it was already running
+    /// Fails if it speaks another protocol
+    pub async fn connect(io: T) -> anyhow::Result<Self> {
+        let mut framed = Framed::new(io, ControllerCodec::default());
+
+        let handshake = async {
+            let mut hello_interval = tokio::time::interval(HELLO_INTERVAL);
+            // Firmware that just reset announces itself, only ask once it has said something else
+            let mut running = false;
+
+            loop {
+                tokio::select! {
+                    frame = framed.next() => match frame {
+                        Some(Ok(Ok(received))) => {
+                            if let UpstreamMessage::Hello(_) = received.message() {
+                                return Ok(received);
+                            }
+                            running = true;
+                        }
+                        Some(Ok(Err(_))) => {}
+                        Some(Err(error)) => return Err(anyhow::Error::from(error)),
+                        None => bail!("Remote device was disconnected"),
+                    },
+                    _ = hello_interval.tick(), if running => {
+                        framed.send(DownstreamMessage::Hello(PROTOCOL_VERSION)).await?;
+                    }
+                }
+            }
+        };
+
+        let hello = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await
+            .context("firmware did not identify itself")??;
+
+        let transport = ControllerTransport::with_hello(framed, hello);
+        let hello = transport.hello();
+        if hello.protocol_version != PROTOCOL_VERSION {
+            bail!("Firmware {} speaks protocol {}, expected {}", hello.build_id, hello.protocol_version, PROTOCOL_VERSION);
+        }
+
+        Ok(transport)
+    }
+
+    fn with_hello(framed: Framed<T, ControllerCodec>, hello: ReceivedMessage) -> Self {
+        let shutdown = CancellationToken::new();
+        ControllerTransport {
+            framed,
+            hello,
+            cancelled: Box::pin(shutdown.clone().cancelled_owned()),
+            shutdown,
+            retransmit: None,
+        }
+    }
+
+    /// How the firmware identified itself when we connected
+    pub fn hello(&self) -> Hello<'_> {
+        match self.hello.message() {
+            UpstreamMessage::Hello(hello) => hello,
+            _ => unreachable!("the handshake only accepts hellos"),
+        }
+    }
+
+    pub fn link_stats(&self) -> LinkStats {
+        self.framed.codec().link.stats()
+    }
+
+    /// Cancel this token to end the stream, for example from a ctrl-c handler
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// End the stream and finish writing any queued commands
+    pub async fn shutdown(mut self) -> Result<(), TransportError> {
+        self.shutdown.cancel();
+        self.framed.close().await
+    }
+
+    /// Send commands that weren't acknowledged in time again
+    fn poll_retransmit(&mut self, cx: &mut Context<'_>) -> Result<(), TransportError> {
+        let ControllerTransport { framed, retransmit, .. } = self;
+        let Some(retransmit) = retransmit else {
+            return Ok(());
+        };
+        // Poll the timer until it is pending, so it wakes us for the next tick
+        let mut due = false;
+        while retransmit.timer.poll_tick(cx).is_ready() {
+            due = true;
+        }
+        if !due {
+            return Ok(());
+        }
+
+        let mut sent = false;
+        for unacked in retransmit.unacked.iter_mut() {
+            if unacked.sent.elapsed() < RETRANSMIT_TIMEOUT || unacked.sends >= MAX_SENDS {
+                continue;
+            }
+            if framed.poll_ready_unpin(cx).is_pending() {
+                break;
+            }
+
+            framed.start_send_unpin(unacked.command.clone())?;
+            unacked.seqs.push(framed.codec().last_seq);
+            unacked.sent = Instant::now();
+            unacked.sends += 1;
+            sent = true;
+        }
+        retransmit.unacked.retain(|unacked| unacked.sends < MAX_SENDS || unacked.sent.elapsed() < RETRANSMIT_TIMEOUT);
+
+        if sent {
+            if let Poll::Ready(Err(error)) = framed.poll_flush_unpin(cx) {
+                return Err(error);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream for ControllerTransport<T> {
+    type Item = Result<ReceivedMessage, TransportError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.cancelled.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
+        if let Err(error) = self.poll_retransmit(cx) {
+            return Poll::Ready(Some(Err(error)));
+        }
+
+        Poll::Ready(match ready!(self.framed.poll_next_unpin(cx)) {
+            Some(Ok(Ok(received))) => {
+                if let (Some(retransmit), UpstreamMessage::Ack(seq)) = (&mut self.retransmit, received.message()) {
+                    retransmit.unacked.retain(|unacked| !unacked.seqs.contains(&seq));
+                }
+                Some(Ok(received))
+            }
+            Some(Ok(Err(error))) => Some(Err(TransportError::Frame(error))),
+            Some(Err(error)) => Some(Err(error)),
+            None => None,
+        })
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Sink<DownstreamMessage> for ControllerTransport<T> {
+    type Error = TransportError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.framed.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: DownstreamMessage) -> Result<(), Self::Error> {
+        let ControllerTransport { framed, retransmit, .. } = &mut *self;
+        match retransmit {
+            Some(retransmit) if !loss_tolerant(&item) => {
+                retransmit.sending(&item);
+                let repeat = repeatable(&item);
+                framed.start_send_unpin(item.clone())?;
+                if repeat {
+                    retransmit.unacked.push(Unacked { seqs: vec![framed.codec().last_seq], command: item, sent: Instant::now(), sends: 1 });
+                }
+                Ok(())
+            }
+            _ => framed.start_send_unpin(item),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.framed.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.framed.poll_close_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::actuator::Actuator;
+    use common::controller::{ArbitrationMode, EStopReason, EStopState, ResetCause, Telemetry};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::net::UdpSocket;
+    use crate::net::MAX_DATAGRAM;
+    use super::*;
+
+    /// Long enough for a command to be sent as many times as it will be
+    const EVERY_SEND: Duration = Duration::from_millis(RETRANSMIT_TIMEOUT.as_millis() as u64 * 2 * MAX_SENDS as u64);
+
+    fn hello(protocol_version: u16) -> UpstreamMessage<'static> {
+        UpstreamMessage::Hello(Hello { protocol_version, build_id: "test", capabilities: 0, reset_cause: ResetCause::default() })
+    }
+
+    /// The firmware's end of the link
+    struct Firmware {
+        io: DuplexStream,
+        decoder: FrameDecoder,
+    }
+
+    impl Firmware {
+        fn new(io: DuplexStream) -> Self {
+            Firmware { io, decoder: FrameDecoder::default() }
+        }
+
+        async fn send(&mut self, seq: u16, message: &UpstreamMessage<'_>) {
+            let mut buffer = [0; 256];
+            self.io.write_all(common::write(seq, message, &mut buffer).unwrap()).await.unwrap();
+        }
+
+        async fn receive(&mut self) -> (u16, String) {
+            loop {
+                if let Some(envelope) = self.decoder.next::<DownstreamMessage>() {
+                    let envelope = envelope.unwrap();
+                    return (envelope.seq, format!("{:?}", envelope.message));
+                }
+
+                let mut buffer = [0; 256];
+                let read = self.io.read(&mut buffer).await.unwrap();
+                self.decoder.push(&buffer[..read]);
+            }
+        }
+    }
+
+    fn pair() -> (DuplexStream, Firmware) {
+        let (pc, firmware) = tokio::io::duplex(1024);
+        (pc, Firmware::new(firmware))
+    }
+
+    #[tokio::test]
+    async fn messages_and_commands() {
+        let (pc, mut firmware) = pair();
+        firmware.send(0, &hello(PROTOCOL_VERSION)).await;
+        let mut transport = ControllerTransport::connect(pc).await.unwrap();
+        assert_eq!(transport.hello().build_id, "test");
+
+        // Duplicates are dropped, garbage is reported without ending the stream
+        firmware.send(1, &UpstreamMessage::Pong).await;
+        firmware.send(1, &UpstreamMessage::Pong).await;
+        firmware.io.write_all(&[1, 2, 3, 0]).await.unwrap();
+        firmware.send(2, &UpstreamMessage::Ack(0)).await;
+
+        let frame = transport.next().await.unwrap().unwrap();
+        assert_eq!((frame.seq(), frame.message()), (1, UpstreamMessage::Pong));
+        assert!(matches!(transport.next().await, Some(Err(TransportError::Frame(_)))));
+
+        transport.send(DownstreamMessage::Ping).await.unwrap();
+        transport.send(DownstreamMessage::Arm).await.unwrap();
+        assert_eq!(firmware.receive().await, (0, "Ping".to_string()));
+        assert_eq!(firmware.receive().await, (1, "Arm".to_string()));
+
+        let frame = transport.next().await.unwrap().unwrap();
+        assert_eq!(frame.message(), UpstreamMessage::Ack(0));
+        assert_eq!(transport.link_stats().duplicates, 1);
+        assert!(transport.link_stats().last_latency.is_some());
+    }
+
+    #[tokio::test]
+    async fn asks_running_firmware_to_identify_itself() {
+        let (pc, mut firmware) = pair();
+        firmware.send(7, &UpstreamMessage::Pong).await;
+
+        let firmware = async {
+            assert_eq!(firmware.receive().await.1, format!("Hello({})", PROTOCOL_VERSION));
+            firmware.send(8, &hello(PROTOCOL_VERSION)).await;
+            firmware
+        };
+        let (transport, _firmware) = tokio::join!(ControllerTransport::connect(pc), firmware);
+        assert!(transport.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_other_protocols() {
+        let (pc, mut firmware) = pair();
+        firmware.send(0, &hello(PROTOCOL_VERSION + 1)).await;
+        assert!(ControllerTransport::connect(pc).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_the_stream() {
+        let (pc, mut firmware) = pair();
+        firmware.send(0, &hello(PROTOCOL_VERSION)).await;
+        let mut transport = ControllerTransport::connect(pc).await.unwrap();
+
+        let shutdown = transport.shutdown_token();
+        let (next, _) = tokio::join!(transport.next(), async { shutdown.cancel() });
+        assert!(next.is_none());
+
+        // Queued commands still go out
+        transport.feed(DownstreamMessage::EmergencyStop).await.unwrap();
+        transport.shutdown().await.unwrap();
+        assert_eq!(firmware.receive().await, (0, "EmergencyStop".to_string()));
+    }
+
+    /// Connect over udp straight to a socket standing in for the bridge and firmware, it acks nothing
+    async fn lossy_pair() -> (ControllerTransport<UdpStream>, UdpSocket) {
+        let firmware = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = firmware.local_addr().unwrap();
+
+        let firmware = async {
+            // Answer the registration
+            let (_, pc) = firmware.recv_from(&mut [0; 16]).await.unwrap();
+            firmware.connect(pc).await.unwrap();
+            send_datagram(&firmware, 0, &hello(PROTOCOL_VERSION)).await;
+            firmware
+        };
+        let (transport, firmware) = tokio::join!(ControllerTransport::connect_udp("127.0.0.1:0", address), firmware);
+        (transport.unwrap(), firmware)
+    }
+
+    async fn send_datagram(firmware: &UdpSocket, seq: u16, message: &UpstreamMessage<'_>) {
+        let mut buffer = [0; 256];
+        firmware.send(common::write(seq, message, &mut buffer).unwrap()).await.unwrap();
+    }
+
+    /// Every command and its sequence number that arrives over `duration`, while the transport is polled so it retransmits
+    async fn receive_for(transport: &mut ControllerTransport<UdpStream>, firmware: &UdpSocket, duration: Duration) -> Vec<(u16, String)> {
+        let mut decoder = FrameDecoder::default();
+        let mut received = Vec::new();
+        let deadline = tokio::time::sleep(duration);
+        tokio::pin!(deadline);
+
+        loop {
+            let mut buffer = [0; MAX_DATAGRAM];
+            tokio::select! {
+                _ = &mut deadline => return received,
+                _ = transport.next() => {}
+                read = firmware.recv(&mut buffer) => {
+                    decoder.push(&buffer[..read.unwrap()]);
+                    while let Some(envelope) = decoder.next::<DownstreamMessage>() {
+                        let envelope = envelope.unwrap();
+                        received.push((envelope.seq, format!("{:?}", envelope.message)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn count(received: &[(u16, String)], command: &DownstreamMessage) -> usize {
+        received.iter().filter(|(_, received)| *received == format!("{:?}", command)).count()
+    }
+
+    #[tokio::test]
+    async fn emergency_stop_cancels_retransmits() {
+        let (mut transport, firmware) = lossy_pair().await;
+        let arbitration = DownstreamMessage::SetArbitration(ArbitrationMode::PcOnly);
+        let claw = DownstreamMessage::SetActuator(Actuator::Claw, 1.0);
+
+        // The arbitration and claw are lost, so they are sent again until the emergency stop
+        transport.send(arbitration.clone()).await.unwrap();
+        transport.send(claw.clone()).await.unwrap();
+        let mut received = receive_for(&mut transport, &firmware, RETRANSMIT_TIMEOUT * 2).await;
+        assert!(count(&received, &arbitration) >= 2);
+        assert!(count(&received, &claw) >= 2);
+
+        transport.send(DownstreamMessage::EmergencyStop).await.unwrap();
+        received.extend(receive_for(&mut transport, &firmware, EVERY_SEND).await);
+        let stop = received.iter().position(|(_, received)| received == "EmergencyStop").unwrap();
+        assert_eq!(count(&received[stop..], &arbitration), 0);
+        assert_eq!(count(&received[stop..], &claw), 0);
+        assert_eq!(count(&received, &DownstreamMessage::EmergencyStop), MAX_SENDS as usize);
+    }
+
+    #[tokio::test]
+    async fn newer_commands_cancel_retransmits() {
+        let (mut transport, firmware) = lossy_pair().await;
+        let old = DownstreamMessage::SetArbitration(ArbitrationMode::JoystickOnly);
+        let new = DownstreamMessage::SetArbitration(ArbitrationMode::PcOnly);
+
+        transport.send(old.clone()).await.unwrap();
+        transport.send(DownstreamMessage::SetActuator(Actuator::Lights, 0.0)).await.unwrap();
+        transport.send(new.clone()).await.unwrap();
+        transport.send(DownstreamMessage::SetActuator(Actuator::Lights, 1.0)).await.unwrap();
+
+        let received = receive_for(&mut transport, &firmware, EVERY_SEND).await;
+        assert_eq!(count(&received, &old), 1);
+        assert_eq!(count(&received, &DownstreamMessage::SetActuator(Actuator::Lights, 0.0)), 1);
+        assert_eq!(count(&received, &new), MAX_SENDS as usize);
+        assert_eq!(count(&received, &DownstreamMessage::SetActuator(Actuator::Lights, 1.0)), MAX_SENDS as usize);
+    }
+
+    #[tokio::test]
+    async fn arm_is_not_repeated() {
+        let (mut transport, firmware) = lossy_pair().await;
+
+        // The arm is lost, then a leak latches an emergency stop before it would have been sent again
+        transport.send(DownstreamMessage::Arm).await.unwrap();
+        let mut received = receive_for(&mut transport, &firmware, RETRANSMIT_TIMEOUT / 2).await;
+        let leak = Telemetry { emergency_stop: EStopState::Latched(EStopReason::Leak), ..Default::default() };
+        send_datagram(&firmware, 1, &UpstreamMessage::Telemetry(leak)).await;
+
+        // Sending the arm again would release the stop
+        received.extend(receive_for(&mut transport, &firmware, EVERY_SEND).await);
+        assert_eq!(count(&received, &DownstreamMessage::Arm), 1);
+    }
+
+    #[tokio::test]
+    async fn late_acks_retire_commands() {
+        let (mut transport, firmware) = lossy_pair().await;
+        let claw = DownstreamMessage::SetActuator(Actuator::Claw, 1.0);
+
+        transport.send(claw.clone()).await.unwrap();
+        let received = receive_for(&mut transport, &firmware, RETRANSMIT_TIMEOUT * 2).await;
+        assert!(count(&received, &claw) >= 2);
+
+        // The ack for the first send arrives after it was sent again
+        send_datagram(&firmware, 1, &UpstreamMessage::Ack(received[0].0)).await;
+        // A send may already have been on its way
+        receive_for(&mut transport, &firmware, RETRANSMIT_TIMEOUT / 2).await;
+        let received = receive_for(&mut transport, &firmware, EVERY_SEND).await;
+        assert_eq!(count(&received, &claw), 0);
+    }
+}